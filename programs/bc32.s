@@ -0,0 +1,15 @@
+; switches to bc32 mode and uses memory beyond the first 64KiB
+;! map ram 0x0 0x20000
+;! expect %xb = 0x12345678
+;! expect %flags = 0x100
+;! expect mem 0x18000 = 78 56 34 12
+
+.org 0x100
+start:
+    mov word 0x100, %flags  ; code is fetched from the flat pc from here on
+.bc32
+    mov 0x12345678, %xa
+    st %xa, 0x18000
+    ld %xb, 0x18000
+    hlt
+    nop                     ; ends hlt's operands
@@ -0,0 +1,80 @@
+; boots from a disk, writing what it read to another sector and reading it back
+;! map console 0xff00
+;! map disk 0xfc00
+;! disk "boot"
+;! expect output "boot"
+;! expect %c = 3
+;! expect mem BUFFER = 62 6f 6f 74
+
+CONSOLE = 0xff00
+DISK = 0xfc00
+SECTOR = DISK
+COMMAND = DISK + 4
+STATUS = DISK + 5
+CONTROL = DISK + 6
+BUFFER = DISK + 0x200
+READ = 1
+WRITE = 2
+DONE = 2
+IRQ = 1
+
+.org 0x100
+start:
+    mov word 0x400, %sp
+    mov idt, %idtp
+    mov word 4, %idtl
+    mov word 0x200, %flags  ; takes irqs
+    mov word 0, %c
+    mov byte IRQ, %al
+    st %al, CONTROL
+    mov word 0, %a
+    st %a, SECTOR
+    mov byte READ, %al
+    st %al, COMMAND
+wait:                       ; the handler halts once the last command is done
+    wfi
+    jmp wait
+
+; each command finishing starts the next, counting them in %c
+irq_handler:
+    mov byte DONE, %al
+    st %al, STATUS
+    cmp %c, word 0
+    jz booted
+    cmp %c, word 1
+    jz written
+    cmp %c, word 2
+    jz cleared
+    hlt
+booted:
+    ld %al, BUFFER
+    st %al, CONSOLE
+    ld %al, BUFFER + 1
+    st %al, CONSOLE
+    ld %al, BUFFER + 2
+    st %al, CONSOLE
+    ld %al, BUFFER + 3
+    st %al, CONSOLE
+    mov word 5, %a
+    st %a, SECTOR
+    mov byte WRITE, %al
+    jmp next
+written:                    ; reading an empty sector clears the buffer
+    mov word 1, %a
+    st %a, SECTOR
+    mov byte READ, %al
+    jmp next
+cleared:
+    mov word 5, %a
+    st %a, SECTOR
+    mov byte READ, %al
+next:
+    st %al, COMMAND
+    swr %a, %c              ; only %a can be added to
+    add word 1
+    swr %a, %c
+    iret
+    nop                     ; ends iret's operands
+
+idt:
+    .word 0, 0, 0, 0, 0, 0, irq_handler, 0
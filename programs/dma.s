@@ -0,0 +1,50 @@
+; prints a message by having the dma controller copy it to the console
+;! map console 0xff00
+;! map dma 0xff40
+;! expect output "hello, dma\n"
+;! expect mem DMA + 8 = 00 00 00 00
+
+CONSOLE = 0xff00
+DMA = 0xff40
+SOURCE = DMA
+DEST = DMA + 4
+COUNT = DMA + 8
+CONTROL = DMA + 12
+STATUS = DMA + 13
+BURST = DMA + 14
+START = 1
+IRQ = 2
+FIXED_DEST = 0x10
+DONE = 2
+
+.org 0x100
+start:
+    mov word 0x400, %sp
+    mov idt, %idtp
+    mov word 4, %idtl
+    mov word 0x200, %flags  ; takes irqs
+    mov message, %a
+    st %a, SOURCE
+    mov word CONSOLE, %a
+    st %a, DEST
+    mov word message_end - message, %a
+    st %a, COUNT
+    mov byte 4, %al
+    st %al, BURST           ; a few bytes between each instruction
+    mov byte START + IRQ + FIXED_DEST, %al
+    st %al, CONTROL
+wait:                       ; the handler halts once it's done
+    wfi
+    jmp wait
+
+irq_handler:
+    mov byte DONE, %al
+    st %al, STATUS
+    hlt
+    nop                     ; ends hlt's operands
+
+idt:
+    .word 0, 0, 0, 0, 0, 0, irq_handler, 0
+message:
+    .ascii "hello, dma\n"
+message_end:
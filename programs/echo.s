@@ -0,0 +1,39 @@
+; echoes what the uart receives from its irq handler, until a newline
+;! map uart 0xff10
+;! input "echo me\n"
+;! expect output "echo me\n"
+;! expect %dl = '\n'
+
+UART = 0xff10
+UART_STATUS = UART + 1
+UART_CONTROL = UART + 2
+RX_READY = 1
+RX_IRQ = 1
+
+.org 0x100
+start:
+    mov word 0x400, %sp
+    mov idt, %idtp
+    mov word 4, %idtl
+    mov word 0x200, %flags  ; takes irqs
+    mov byte RX_IRQ, %al
+    st %al, UART_CONTROL
+wait:                       ; the handler halts, since the input can all come in before wfi
+    wfi
+    jmp wait
+
+irq_handler:                ; takes everything that's waiting, not just one byte
+    ld %al, UART_STATUS
+    and %al, byte RX_READY
+    jz irq_done
+    ld %dl, UART
+    st %dl, UART
+    cmp %dl, byte '\n'
+    jnz irq_handler
+    hlt
+irq_done:
+    iret
+    nop                     ; ends iret's operands
+
+idt:
+    .word 0, 0, 0, 0, 0, 0, irq_handler, 0
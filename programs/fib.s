@@ -0,0 +1,18 @@
+; works out the 20th fibonacci number
+.org 0x100
+start:
+    mov word 0, %a          ; fib(n)
+    mov word 1, %b          ; fib(n + 1)
+    mov word 20, %c
+loop:
+    add %b
+    swr %a, %b
+    swr %a, %c
+    sub word 1
+    swr %a, %c
+    jnz loop
+    st %a, result
+    hlt
+    nop                     ; ends hlt's operands
+
+result: .word 0
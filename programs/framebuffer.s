@@ -0,0 +1,53 @@
+; writes some text to the framebuffer, and halts at the second vblank
+;! map framebuffer 0xa000
+;! expect pixel 0 0 = ff ff 55
+;! expect pixel 2 0 = 00 00 aa
+;! expect pixel 8 7 = 00 00 aa
+;! expect pixel 10 2 = ff ff 55
+;! expect pixel 16 0 = 00 00 00
+;! expect mem FRAME = 02 00
+
+FB = 0xa000
+CONTROL = FB + 1
+STATUS = FB + 2
+FRAME = FB + 4
+VRAM = FB + 0x400
+VBLANK_IRQ = 1
+VBLANK = 1
+YELLOW_ON_BLUE = 0x1e
+
+.org 0x100
+start:
+    mov word 0x400, %sp
+    mov idt, %idtp
+    mov word 4, %idtl
+    mov word 0x200, %flags  ; takes irqs
+    mov word 0, %c
+    mov byte 'H', %al
+    st %al, VRAM
+    mov byte 'i', %al
+    st %al, VRAM + 2
+    mov byte YELLOW_ON_BLUE, %al
+    st %al, VRAM + 1
+    st %al, VRAM + 3
+    mov byte VBLANK_IRQ, %al
+    st %al, CONTROL
+wait:                       ; the handler halts at the second one
+    wfi
+    jmp wait
+
+vblank_handler:
+    mov byte VBLANK, %al
+    st %al, STATUS
+    swr %a, %c              ; only %a can be added to
+    add word 1
+    swr %a, %c
+    cmp %c, word 2
+    jnz vblank_done
+    hlt
+vblank_done:
+    iret
+    nop                     ; ends iret's operands
+
+idt:
+    .word 0, 0, 0, 0, 0, 0, vblank_handler, 0
@@ -0,0 +1,24 @@
+; prints a string to the console one byte at a time
+;! map console 0xff00
+;! expect output "hello, world\n"
+;! expect %b = 13
+
+CONSOLE = 0xff00
+
+.org 0x100
+start:
+    mov word 0, %b          ; index into msg
+loop:
+    ld %al, msg, %b
+    cmp %al, byte 0
+    jz done
+    st %al, CONSOLE
+    swr %a, %b              ; only %a can be added to
+    add word 1
+    swr %a, %b
+    jmp loop
+done:
+    hlt
+    nop                     ; ends hlt's operands
+
+msg: .asciz "hello, world\n"
@@ -0,0 +1,35 @@
+; a software interrupt, and a divide by zero that its handler recovers from
+;! map console 0xff00
+;! expect output "id"
+;! expect %a = 100
+;! expect %b = 0
+;! expect %sp = 0x400
+
+CONSOLE = 0xff00
+
+.org 0x100
+start:
+    mov word 0x400, %sp
+    mov idt, %idtp
+    mov word 16, %idtl
+    int byte 15
+    mov word 100, %a
+    mov word 0, %b
+    div %b                  ; faults, and runs again once the handler has fixed %b
+    hlt
+
+int_handler:
+    mov byte 'i', %dl
+    st %dl, CONSOLE
+    iret
+div_handler:
+    mov byte 'd', %dl
+    st %dl, CONSOLE
+    mov word 1, %b
+    iret
+    nop                     ; ends iret's operands
+
+idt:
+    .word 0, 0, 0, 0, div_handler, 0
+    .word 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+    .word int_handler, 0
@@ -0,0 +1,47 @@
+; collects scan codes from a keyboard's irq handler, until enter is released
+;! map keyboard 0xff50
+;! keys 500 type "hI"
+;! keys 3000 press enter
+;! keys 3100 release enter
+;! expect mem codes = 23 a3 2a 17 97 aa 1c 9c
+;! expect %b = 8
+
+KEYBOARD = 0xff50
+STATUS = KEYBOARD + 1
+CONTROL = KEYBOARD + 2
+READY = 1
+IRQ = 1
+ENTER_RELEASED = 0x9c
+
+.org 0x100
+start:
+    mov word 0x400, %sp
+    mov idt, %idtp
+    mov word 4, %idtl
+    mov word 0x200, %flags  ; takes irqs
+    mov word 0, %b
+    mov byte IRQ, %al
+    st %al, CONTROL
+wait:                       ; the handler halts after the last key
+    wfi
+    jmp wait
+
+irq_handler:                ; takes every code that's waiting, not just one
+    ld %al, STATUS
+    and %al, byte READY
+    jz irq_done
+    ld %dl, KEYBOARD
+    st %dl, codes, %b
+    swr %a, %b              ; only %a can be added to
+    add word 1
+    swr %a, %b
+    cmp %dl, byte ENTER_RELEASED
+    jnz irq_handler
+    hlt
+irq_done:
+    iret
+    nop                     ; ends iret's operands
+
+idt:
+    .word 0, 0, 0, 0, 0, 0, irq_handler, 0
+codes:
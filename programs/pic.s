@@ -0,0 +1,77 @@
+; takes interrupts from a uart and a timer on separate lines of an interrupt controller
+;! map pic 0xff30
+;! map uart 0xff10 line 0
+;! map timer 0xff20 line 1
+;! input "ab"
+;! expect output "ab"
+;! expect %c = 3
+;! expect mem PIC + 2 = 00
+
+PIC = 0xff30
+EOI = PIC + 3
+UART = 0xff10
+UART_STATUS = UART + 1
+UART_CONTROL = UART + 2
+RX_READY = 1
+RX_IRQ = 1
+TIMER = 0xff20
+RELOAD = TIMER
+PRESCALER = TIMER + 4
+CONTROL = TIMER + 6
+STATUS = TIMER + 7
+ENABLE = 1
+PERIODIC = 2
+IRQ = 4
+EXPIRED = 1
+
+.org 0x100
+start:
+    mov word 0x400, %sp
+    mov idt, %idtp
+    mov word 0x12, %idtl    ; the pic's lines 0 and 1 are vectors 0x10 and 0x11
+    mov word 0x200, %flags  ; takes irqs
+    mov word 0, %c
+    mov byte RX_IRQ, %al
+    st %al, UART_CONTROL
+    mov word 100, %a
+    st %a, RELOAD
+    mov word 9, %a
+    st %a, PRESCALER
+    mov byte ENABLE + PERIODIC + IRQ, %al
+    st %al, CONTROL
+wait:                       ; the timer handler halts after the last one
+    wfi
+    jmp wait
+
+uart_handler:
+    ld %al, UART_STATUS
+    and %al, byte RX_READY
+    jz uart_done
+    ld %al, UART
+    st %al, UART
+    jmp uart_handler
+uart_done:
+    mov byte 0, %al
+    st %al, EOI
+    iret
+    nop                     ; ends iret's operands
+
+timer_handler:
+    mov byte EXPIRED, %al
+    st %al, STATUS
+    mov byte 1, %al
+    st %al, EOI
+    swr %a, %c              ; only %a can be added to
+    add word 1
+    swr %a, %c
+    cmp %c, word 3
+    jnz timer_done
+    hlt
+timer_done:
+    iret
+    nop                     ; ends iret's operands
+
+idt:
+    .word 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+    .word 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0
+    .word uart_handler, 0, timer_handler, 0
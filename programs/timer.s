@@ -0,0 +1,47 @@
+; waits for five interrupts from a periodic timer
+;! map timer 0xff20
+;! expect %c = 5
+;! expect mem TIMER + 6 = 07
+
+TIMER = 0xff20
+RELOAD = TIMER
+PRESCALER = TIMER + 4
+CONTROL = TIMER + 6
+STATUS = TIMER + 7
+ENABLE = 1
+PERIODIC = 2
+IRQ = 4
+EXPIRED = 1
+
+.org 0x100
+start:
+    mov word 0x400, %sp
+    mov idt, %idtp
+    mov word 4, %idtl
+    mov word 0x200, %flags  ; takes irqs
+    mov word 0, %c
+    mov word 100, %a
+    st %a, RELOAD
+    mov word 9, %a
+    st %a, PRESCALER        ; a tick every 10 cycles, so an irq every 1000
+    mov byte ENABLE + PERIODIC + IRQ, %al
+    st %al, CONTROL
+wait:                       ; the handler halts after the last one
+    wfi
+    jmp wait
+
+irq_handler:
+    mov byte EXPIRED, %al
+    st %al, STATUS
+    swr %a, %c              ; only %a can be added to
+    add word 1
+    swr %a, %c
+    cmp %c, word 5
+    jnz irq_done
+    hlt
+irq_done:
+    iret
+    nop                     ; ends iret's operands
+
+idt:
+    .word 0, 0, 0, 0, 0, 0, irq_handler, 0
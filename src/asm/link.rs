@@ -0,0 +1,251 @@
+//! links objects into an image. sections with the same name are joined together in the order the
+//! objects come in, then placed one after another, or where a layout puts them
+//!
+//! ```text
+//! ; a linker script
+//! place text 0x100       puts the text sections at 0x100
+//! place data 0x10000
+//! entry main             starts at a global label, `start` by default
+//! ```
+//!
+//! sections a layout doesn't place follow the one before, with the first at 0
+
+use std::collections::BTreeMap;
+use crate::image::{split_addr, Image};
+use super::object::{Object, RelocKind, Target};
+use crate::utils::parse_addr;
+
+#[derive(Default, Debug, PartialEq)]
+pub struct Layout {
+    /// where to put sections, in the order they go
+    pub placements: Vec<(String, u32)>,
+    pub entry: Option<String>,
+}
+impl Layout {
+    /// reads a linker script
+    pub fn parse(script: &str) -> Result<Layout, String> {
+        let mut layout = Layout::default();
+        for (i, line) in script.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default();
+            match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [] => (),
+                ["place", name, addr] => {
+                    let addr = parse_addr(addr).ok_or(format!("{}: bad address {}", i + 1, addr))?;
+                    layout.place(name, addr);
+                }
+                ["entry", name] => layout.entry = Some(name.to_string()),
+                _ => return Err(format!("{}: can't parse {:?}", i + 1, line.trim())),
+            }
+        }
+        Ok(layout)
+    }
+    /// puts a section at an address, replacing where it was put before
+    pub fn place(&mut self, name: &str, addr: u32) {
+        self.placements.retain(|(n, _)| n != name);
+        self.placements.push((name.to_string(), addr));
+    }
+}
+
+/// a section of the output, made of the same named sections of every object
+struct Output {
+    name: String,
+    addr: u32,
+    data: Vec<u8>,
+}
+
+/// links named objects, so errors can say which one they're in
+pub fn link(objects: &[(String, Object)], layout: &Layout) -> Result<Image, String> {
+    let mut outputs: Vec<Output> = layout.placements.iter()
+        .map(|(name, _)| Output { name: name.clone(), addr: 0, data: Vec::new() })
+        .collect();
+    // where each object's sections are, as an output and an offset into it
+    let mut places = Vec::new();
+    for (_, obj) in objects {
+        let mut object_places = Vec::new();
+        for s in &obj.sections {
+            let i = match outputs.iter().position(|o| o.name == s.name) {
+                Some(i) => i,
+                None => {
+                    outputs.push(Output { name: s.name.clone(), addr: 0, data: Vec::new() });
+                    outputs.len() - 1
+                }
+            };
+            object_places.push((i, outputs[i].data.len() as u32));
+            outputs[i].data.extend(&s.data);
+        }
+        places.push(object_places);
+    }
+
+    let mut next = 0u32;
+    for o in &mut outputs {
+        o.addr = match layout.placements.iter().find(|(name, _)| *name == o.name) {
+            Some((_, addr)) => *addr,
+            None => next,
+        };
+        next = o.addr.wrapping_add(o.data.len() as u32);
+    }
+    let mut placed: Vec<&Output> = outputs.iter().filter(|o| !o.data.is_empty()).collect();
+    placed.sort_by_key(|o| o.addr);
+    for pair in placed.windows(2) {
+        if pair[0].addr as u64 + pair[0].data.len() as u64 > pair[1].addr as u64 {
+            return Err(format!("section {} at {:#x} overlaps section {} at {:#x}",
+                pair[0].name, pair[0].addr, pair[1].name, pair[1].addr))
+        }
+    }
+    let lowest = placed.first().map(|o| o.addr);
+    let addrs: Vec<u32> = outputs.iter().map(|o| o.addr).collect();
+    let section_addr = |obj: usize, section: usize| {
+        let (i, offset) = places[obj][section];
+        addrs[i].wrapping_add(offset)
+    };
+
+    let mut globals = BTreeMap::new();
+    for (obj, (name, object)) in objects.iter().enumerate() {
+        for (sym, s) in object.symbols.iter().filter(|(_, s)| s.global) {
+            let value = match s.section {
+                Some(section) if section < object.sections.len() => section_addr(obj, section).wrapping_add(s.value),
+                Some(_) => return Err(format!("{}: {} is in a section that doesn't exist", name, sym)),
+                None => s.value,
+            };
+            if globals.insert(sym.clone(), value).is_some() {
+                return Err(format!("{}: {} is already defined by another object", name, sym))
+            }
+        }
+    }
+
+    for (obj, (name, object)) in objects.iter().enumerate() {
+        for (section, s) in object.sections.iter().enumerate() {
+            for r in &s.relocs {
+                let target = match &r.target {
+                    Target::Section(i) if *i < object.sections.len() => section_addr(obj, *i),
+                    Target::Section(i) => return Err(format!("{}: relocation against section {} that doesn't exist", name, i)),
+                    Target::Symbol(sym) => *globals.get(sym).ok_or(format!("{}: undefined symbol {}", name, sym))?,
+                };
+                let v = target.wrapping_add(r.addend);
+                let bytes = match r.kind {
+                    RelocKind::Abs8 if v > 0xff => return Err(format!("{}: {:#x} doesn't fit in a byte", name, v)),
+                    RelocKind::Abs8 => vec![v as u8],
+                    RelocKind::Abs16 if v > 0xffff => return Err(format!("{}: {:#x} doesn't fit in a word", name, v)),
+                    RelocKind::Abs16 => (v as u16).to_le_bytes().to_vec(),
+                    RelocKind::Abs32 => v.to_le_bytes().to_vec(),
+                    RelocKind::Seg16 | RelocKind::Off16 => {
+                        let (seg, off) = split_addr(v).ok_or(format!("{}: {:#x} doesn't fit in a segment", name, v))?;
+                        if r.kind == RelocKind::Seg16 { seg } else { off }.to_le_bytes().to_vec()
+                    }
+                };
+                if (r.offset as usize).checked_add(bytes.len()).is_none_or(|end| end > s.data.len()) {
+                    return Err(format!("{}: relocation at {:#x} is past the end of section {}", name, r.offset, s.name))
+                }
+                let (i, offset) = places[obj][section];
+                let at = offset as usize + r.offset as usize;
+                outputs[i].data[at..at + bytes.len()].copy_from_slice(&bytes);
+            }
+        }
+    }
+
+    let entry = match &layout.entry {
+        Some(sym) => *globals.get(sym).ok_or(format!("entry {} isn't a global symbol", sym))?,
+        None => globals.get("start").copied()
+            .or(lowest)
+            .unwrap_or(0),
+    };
+    let (entry_co, entry_pc) = split_addr(entry).ok_or(format!("entry {:#x} doesn't fit in a segment", entry))?;
+    let mut image = Image { entry_co, entry_pc, segments: Vec::new(), symbols: globals };
+    for o in outputs.iter().filter(|o| !o.data.is_empty()) {
+        image.add_segment(o.addr, &o.data).map_err(|e| format!("{}: {}", o.name, e))?;
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble_object;
+    use crate::memory::{MemoryMap, RustMemory};
+    use crate::processor::{Processor, State};
+
+    fn objects(sources: &[&str]) -> Vec<(String, Object)> {
+        sources.iter().enumerate()
+            .map(|(i, s)| (format!("{}.o", i), assemble_object(s).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn links_and_runs() {
+        let objects = objects(&["
+            .global start
+            start:
+                mov word seg(value), %do
+                mov word 0x20, %flags
+                ld %b, off(value)
+                jmp word finish
+            .section data
+            pad: .byte 0
+            ",
+            "
+            .global finish, value
+            finish:
+                mov word len, %c
+                hlt
+                nop
+            len = 3
+            .section data
+            value: .word 0x1234
+            ",
+        ]);
+        let layout = Layout::parse("place text 0x100 ; code\nplace data 0x10040\n").unwrap();
+        let image = link(&objects, &layout).unwrap();
+        assert_eq!(image.symbols["value"], 0x10041);
+        assert_eq!((image.entry_co, image.entry_pc), (0, 0x100));
+
+        let mut mem = MemoryMap::new();
+        mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+        mem.add_device_at(Box::new(RustMemory::new()), 0x1_0000..0x2_0000);
+        let mut p = Processor::default();
+        image.load(&mut mem, &mut p);
+        for _ in 0..100 {
+            if p.state() == State::Halted {
+                break
+            }
+            p.clock(&mut mem);
+        }
+        assert_eq!(p.state(), State::Halted);
+        assert_eq!(p.inspect(0x04), Some(0x1234));
+        assert_eq!(p.inspect(0x08), Some(3));
+    }
+
+    #[test]
+    fn unplaced_sections_follow() {
+        let objects = objects(&[".byte 1, 2\n.section data\n.byte 3", ".byte 4\n.section bss\n.byte 5"]);
+        let image = link(&objects, &Layout::default()).unwrap();
+        let data: Vec<_> = image.segments.iter().map(|s| (s.flat_addr(), s.data.clone())).collect();
+        assert_eq!(data, [(0, vec![1, 2, 4]), (3, vec![3]), (4, vec![5])]);
+    }
+
+    #[test]
+    fn errors() {
+        let missing = objects(&["jmp word nowhere\nnop"]);
+        assert_eq!(link(&missing, &Layout::default()).unwrap_err(), "0.o: undefined symbol nowhere");
+        let twice = objects(&[".global a\na: nop", ".global a\na: nop"]);
+        assert_eq!(link(&twice, &Layout::default()).unwrap_err(), "1.o: a is already defined by another object");
+        let big = objects(&[".section data\n.byte 0\n.section text\n.byte data_start", ".section data\n.global data_start\ndata_start: nop"]);
+        let layout = Layout::parse("place data 0x200").unwrap();
+        assert_eq!(link(&big, &layout).unwrap_err(), "0.o: 0x201 doesn't fit in a byte");
+        let far = objects(&[".word seg(far)\n.section data\nfar: nop"]);
+        let layout = Layout::parse("place data 0x1000000").unwrap();
+        assert_eq!(link(&far, &layout).unwrap_err(), "0.o: 0x1000000 doesn't fit in a segment");
+        let far = objects(&[".global start\nstart: hlt"]);
+        let layout = Layout::parse("place text 0x1000000").unwrap();
+        assert_eq!(link(&far, &layout).unwrap_err(), "entry 0x1000000 doesn't fit in a segment");
+        let far = objects(&["hlt\n.section data\n.byte 1"]);
+        let layout = Layout::parse("place text 0\nplace data 0x1000000").unwrap();
+        assert_eq!(link(&far, &layout).unwrap_err(), "data: 0x1000000 is past the 16MiB segments can reach");
+        let overlap = objects(&[".byte 1, 2\n.section data\n.byte 3"]);
+        let layout = Layout::parse("place text 0\nplace data 1").unwrap();
+        assert_eq!(link(&overlap, &layout).unwrap_err(), "section text at 0x0 overlaps section data at 0x1");
+        assert_eq!(Layout::parse("place text").unwrap_err(), "1: can't parse \"place text\"");
+        let mut past_end = objects(&["here: .word here"]);
+        past_end[0].1.sections[0].relocs[0].offset = u32::MAX;
+        assert_eq!(link(&past_end, &Layout::default()).unwrap_err(), "0.o: relocation at 0xffffffff is past the end of section text");
+    }
+}
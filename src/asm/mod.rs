@@ -0,0 +1,590 @@
+//! a small two pass assembler, using the syntax from the design documents
+//!
+//! ```text
+//! .org 0x100          ; code goes at 0x100
+//! start:
+//!     mov word 10, %c
+//! loop: add %c        ; labels can share a line
+//!     jnz loop        ; bare values are words (dwords after .bc32)
+//!     test sub word 1 ; test prefixes the instruction after it
+//!     hlt
+//! msg: .asciz "hi"
+//! ```
+//!
+//! directives are .org, .bc16, .bc32, .byte, .word, .dword, .ascii, .asciz and `name = value`.
+//! values are numbers (decimal, 0x hex, 0b binary or 'c'), labels and `.` for the current address,
+//! added or subtracted together. `seg(value)` and `off(value)` split an address into the co and pc to reach it.
+//!
+//! objects are assembled without addresses, for the linker to place. they use .section instead of .org,
+//! .global to export labels, and can use labels from other objects
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use crate::image::split_addr;
+use crate::utils::{string, strip_comment};
+pub use link::{link, Layout};
+pub use object::Object;
+use object::{Reloc, RelocKind, Section, Symbol, Target};
+
+mod link;
+mod object;
+
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Assembly {
+    /// the code and data, in the order they appear in the source
+    pub segments: Vec<Segment>,
+    pub symbols: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub msg: String,
+}
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+type Result<T> = std::result::Result<T, String>;
+
+const MNEMONICS: &[(&str, u8)] = &[
+    ("mov", 0x80), ("movsx", 0x81), ("push", 0x84), ("swr", 0x85), ("nop", 0x85), ("pop", 0x86),
+    ("jmp", 0x88), ("jz", 0x89), ("je", 0x89), ("jnz", 0x8a), ("jne", 0x8a),
+    ("jc", 0x8b), ("jae", 0x8b), ("jnc", 0x8c), ("jb", 0x8c),
+    ("jn", 0x8d), ("jnn", 0x8e), ("jo", 0x8f), ("jno", 0x98),
+    ("ja", 0x99), ("jbe", 0x9a), ("jl", 0x9b), ("jge", 0x9c), ("jg", 0x9d), ("jle", 0x9e),
+    ("ld", 0x90), ("ld.e", 0x91), ("st", 0xa0), ("st.e", 0xa1), ("swm", 0xb0), ("swm.e", 0xb1),
+    ("mul", 0xc0), ("imul", 0xc1), ("div", 0xc2), ("idiv", 0xc3),
+    ("add", 0xc4), ("adc", 0xc5), ("sub", 0xc6), ("sbc", 0xc7), ("cmp", 0xc8),
+    ("and", 0xd0), ("or", 0xd1), ("xor", 0xd2), ("not", 0xd3),
+    ("shl", 0xd8), ("shr", 0xd9), ("sar", 0xda), ("rol", 0xdc), ("ror", 0xdd), ("rcl", 0xde), ("rcr", 0xdf),
+    ("movs", 0xe8), ("movs.d", 0xe9), ("stos", 0xea), ("stos.d", 0xeb),
+    ("cmps", 0xec), ("cmps.d", 0xed), ("scas", 0xee), ("scas.d", 0xef),
+    ("test", 0xf0), ("int", 0xf2), ("iret", 0xf3), ("hlt", 0xf4), ("wfi", 0xf5),
+];
+
+const REGISTERS: &[(&str, u8)] = &[
+    ("a", 0x00), ("xa", 0x01), ("al", 0x02), ("ah", 0x03),
+    ("b", 0x04), ("xb", 0x05), ("bl", 0x06), ("bh", 0x07),
+    ("c", 0x08), ("xc", 0x09), ("cl", 0x0a),
+    ("d", 0x0c), ("xd", 0x0d), ("dl", 0x0e),
+    ("sp", 0x10), ("xsp", 0x11), ("bp", 0x12), ("xbp", 0x13),
+    ("si", 0x14), ("xsi", 0x15), ("di", 0x16), ("xdi", 0x17),
+    ("rp", 0x18), ("xrp", 0x19), ("ro", 0x1a),
+    ("co", 0x20), ("do", 0x21), ("eo", 0x22), ("so", 0x23),
+    ("idtp", 0x28), ("xidtp", 0x29), ("idtl", 0x2a), ("xidtl", 0x2b),
+    ("pc", 0x2c), ("xpc", 0x2d), ("flags", 0x2e), ("xflags", 0x2f),
+];
+
+impl Assembly {
+    /// evaluates a value using the assembled labels
+    pub fn value(&self, expr: &str) -> Result<u32> {
+        let asm = Assembler { pass: 1, symbols: self.symbols.clone(), ..Default::default() };
+        asm.value(expr)
+    }
+}
+
+/// the id of a register by its name, without the %
+pub fn register(name: &str) -> Option<u8> {
+    REGISTERS.iter().find(|(n, _)| *n == name).map(|(_, id)| *id)
+}
+
+/// the most times the first pass runs before its symbols settle
+const FIRST_PASSES: usize = 8;
+
+/// the first pass only finds labels, which is possible since every operand's size is known up front.
+/// it runs again with what it found until that stops changing, so equates can use symbols defined after them
+fn first_pass(source: &str, object: bool) -> std::result::Result<Assembler, AsmError> {
+    let mut asm = Assembler::default();
+    for _ in 0..FIRST_PASSES {
+        let mut next = match object {
+            true => Assembler::object(0, BTreeMap::new(), asm.label_sections),
+            false => Assembler { pass: 0, ..Default::default() },
+        };
+        next.known = asm.symbols;
+        next.run(source)?;
+        let settled = next.symbols == next.known;
+        asm = next;
+        if settled {
+            break
+        }
+    }
+    Ok(asm)
+}
+
+pub fn assemble(source: &str) -> std::result::Result<Assembly, AsmError> {
+    let asm = first_pass(source, false)?;
+    let mut asm = Assembler { pass: 1, symbols: asm.symbols, ..Default::default() };
+    asm.run(source)?;
+    Ok(Assembly { segments: asm.segments, symbols: asm.symbols })
+}
+
+/// assembles a relocatable object, starting in a section called text
+pub fn assemble_object(source: &str) -> std::result::Result<Object, AsmError> {
+    let asm = first_pass(source, true)?;
+    let mut asm = Assembler::object(1, asm.symbols, asm.label_sections);
+    asm.run(source)?;
+    let symbols = asm.symbols.iter()
+        .map(|(name, value)| (name.clone(), Symbol {
+            section: asm.label_sections.get(name).copied(),
+            value: *value,
+            global: asm.globals.contains(name),
+        }))
+        .collect();
+    Ok(Object { sections: asm.sections, symbols })
+}
+
+/// which part of an address a value is
+#[derive(Default, Clone, Copy, PartialEq)]
+enum Part {
+    #[default]
+    Whole,
+    Seg,
+    Off,
+}
+
+/// a value, which in an object can depend on where sections and other objects' labels end up
+#[derive(Default)]
+struct Value {
+    constant: u32,
+    /// what gets added to the constant once it's linked, and how many times
+    terms: Vec<(Target, i32)>,
+    part: Part,
+}
+impl Value {
+    fn constant(v: u32) -> Value {
+        Value { constant: v, ..Default::default() }
+    }
+    fn target(target: Target, offset: u32) -> Value {
+        Value { constant: offset, terms: vec![(target, 1)], part: Part::Whole }
+    }
+}
+
+#[derive(Default)]
+struct Assembler {
+    pass: u32,
+    /// in an object, the offset into the current section
+    addr: u32,
+    mode32: bool,
+    segments: Vec<Segment>,
+    symbols: BTreeMap<String, u32>,
+    /// in the first pass, the symbols the last run of it found, for those that aren't defined yet
+    known: BTreeMap<String, u32>,
+    /// assembling an object rather than code with fixed addresses
+    object: bool,
+    sections: Vec<Section>,
+    section: usize,
+    /// which section each label of an object is in
+    label_sections: BTreeMap<String, usize>,
+    globals: BTreeSet<String>,
+}
+impl Assembler {
+    fn object(pass: u32, symbols: BTreeMap<String, u32>, label_sections: BTreeMap<String, usize>) -> Assembler {
+        let text = Section { name: "text".to_string(), data: Vec::new(), relocs: Vec::new() };
+        Assembler { pass, symbols, object: true, sections: vec![text], label_sections, ..Default::default() }
+    }
+    fn run(&mut self, source: &str) -> std::result::Result<(), AsmError> {
+        for (i, line) in source.lines().enumerate() {
+            self.line(line).map_err(|msg| AsmError { line: i + 1, msg })?;
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, line: &str) -> Result<()> {
+        let mut line = strip_comment(line).trim();
+        if let Some((label, rest)) = line.split_once(':') {
+            if is_ident(label.trim()) {
+                self.define(label.trim(), self.addr)?;
+                if self.object {
+                    self.label_sections.insert(label.trim().to_string(), self.section);
+                }
+                line = rest.trim();
+            }
+        }
+        if let Some((name, value)) = line.split_once('=') {
+            if is_ident(name.trim()) {
+                let value = self.value(value.trim())?;
+                return self.define(name.trim(), value)
+            }
+        }
+        if line.is_empty() {
+            return Ok(())
+        }
+        let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match word {
+            ".org" if self.object => Err(".org can't be used in objects, sections are placed by the linker".to_string()),
+            ".org" => {
+                self.addr = self.value(rest)?;
+                self.segments.push(Segment { addr: self.addr, data: Vec::new() });
+                Ok(())
+            }
+            ".section" if self.object => {
+                if !is_ident(rest) {
+                    return Err(format!("bad section name {}", rest))
+                }
+                self.section = match self.sections.iter().position(|s| s.name == rest) {
+                    Some(i) => i,
+                    None => {
+                        self.sections.push(Section { name: rest.to_string(), data: Vec::new(), relocs: Vec::new() });
+                        self.sections.len() - 1
+                    }
+                };
+                self.addr = self.sections[self.section].data.len() as u32;
+                Ok(())
+            }
+            ".section" => Err(".section can only be used in objects".to_string()),
+            // code with fixed addresses has nothing to export to, but can still say what it would
+            ".global" => {
+                for name in rest.split(',') {
+                    if !is_ident(name.trim()) {
+                        return Err(format!("bad label {}", name.trim()))
+                    }
+                    self.globals.insert(name.trim().to_string());
+                }
+                Ok(())
+            }
+            ".bc16" => { self.mode32 = false; Ok(()) }
+            ".bc32" => { self.mode32 = true; Ok(()) }
+            ".byte" => self.data(rest, 1),
+            ".word" => self.data(rest, 2),
+            ".dword" => self.data(rest, 4),
+            ".ascii" | ".asciz" => {
+                let mut bytes = string(rest)?;
+                if word == ".asciz" {
+                    bytes.push(0);
+                }
+                self.emit(&bytes);
+                Ok(())
+            }
+            _ if word.starts_with('.') => Err(format!("unknown directive {}", word)),
+            _ => self.instruction(word, rest),
+        }
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operands: &str) -> Result<()> {
+        let opcode = MNEMONICS.iter().find(|(m, _)| *m == mnemonic)
+            .ok_or(format!("unknown instruction {}", mnemonic))?.1;
+        self.emit(&[opcode]);
+        // the test prefix takes the next instruction as its operands
+        if opcode == 0xf0 && !operands.is_empty() {
+            let (word, rest) = operands.split_once(char::is_whitespace).unwrap_or((operands, ""));
+            return self.instruction(word, rest.trim())
+        }
+        if operands.is_empty() {
+            return Ok(())
+        }
+        for operand in operands.split(',') {
+            self.operand(operand.trim())?;
+        }
+        Ok(())
+    }
+
+    fn operand(&mut self, operand: &str) -> Result<()> {
+        if let Some(name) = operand.strip_prefix('%') {
+            let id = register(name).ok_or(format!("unknown register %{}", name))?;
+            self.emit(&[id]);
+            return Ok(())
+        }
+        let (size, value) = match operand.split_once(char::is_whitespace) {
+            Some(("byte", v)) => (1, v),
+            Some(("word", v)) => (2, v),
+            Some(("dword", v)) => (4, v),
+            _ if self.mode32 => (4, operand),
+            _ => (2, operand),
+        };
+        let value = self.expr(value.trim())?;
+        self.emit(&[match size { 1 => 0x70, 2 => 0x71, _ => 0x72 }]);
+        self.emit_value(value, size)
+    }
+
+    fn data(&mut self, values: &str, size: usize) -> Result<()> {
+        for v in values.split(',') {
+            let v = self.expr(v.trim())?;
+            self.emit_value(v, size)?;
+        }
+        Ok(())
+    }
+
+    /// emits a value, leaving a relocation for the linker to fill it in if it isn't known yet
+    fn emit_value(&mut self, value: Value, size: usize) -> Result<()> {
+        let target = match value.terms.as_slice() {
+            [] => None,
+            [(target, 1)] => Some(target.clone()),
+            _ if self.pass == 0 => None,
+            _ => return Err("a value can only add a single label from another section or object".to_string()),
+        };
+        let v = match target {
+            Some(target) if self.pass == 1 => {
+                let kind = match (value.part, size) {
+                    (Part::Whole, 1) => RelocKind::Abs8,
+                    (Part::Whole, 2) => RelocKind::Abs16,
+                    (Part::Whole, _) => RelocKind::Abs32,
+                    (Part::Seg, 2) => RelocKind::Seg16,
+                    (Part::Off, 2) => RelocKind::Off16,
+                    _ => return Err("seg and off are words".to_string()),
+                };
+                let reloc = Reloc { offset: self.addr, kind, target, addend: value.constant };
+                self.sections[self.section].relocs.push(reloc);
+                0
+            }
+            Some(_) => 0,
+            None => value.constant,
+        };
+        let bytes = self.sized(v, size)?;
+        self.emit(&bytes);
+        Ok(())
+    }
+
+    /// the little endian bytes of a value, which has to fit in size bytes either signed or unsigned
+    fn sized(&self, value: u32, size: usize) -> Result<Vec<u8>> {
+        let bits = size as u32 * 8;
+        let fits = bits == 32 || value >> bits == 0 || (value as i32) >> (bits - 1) == -1;
+        // labels aren't known in the first pass, so anything goes
+        if !fits && self.pass == 1 {
+            let name = match size { 1 => "byte", 2 => "word", _ => "dword" };
+            return Err(format!("{:#x} doesn't fit in a {}", value, name))
+        }
+        Ok(value.to_le_bytes()[..size].to_vec())
+    }
+
+    /// a value that has to be known now
+    fn value(&self, expr: &str) -> Result<u32> {
+        let v = self.expr(expr)?;
+        // the first pass doesn't know which labels are still to come
+        if !v.terms.is_empty() && self.pass == 1 {
+            return Err(format!("{} isn't known until linking", expr))
+        }
+        Ok(v.constant)
+    }
+
+    fn expr(&self, expr: &str) -> Result<Value> {
+        if expr.is_empty() {
+            return Err("missing value".to_string())
+        }
+        for (name, part) in [("seg(", Part::Seg), ("off(", Part::Off)] {
+            if let Some(inner) = expr.strip_prefix(name).and_then(|e| e.strip_suffix(')')) {
+                let v = self.expr(inner.trim())?;
+                if !v.terms.is_empty() {
+                    return Ok(Value { part, ..v })
+                }
+                let (seg, off) = split_addr(v.constant).ok_or(format!("{:#x} doesn't fit in a segment", v.constant))?;
+                return Ok(Value::constant(if part == Part::Seg { seg } else { off } as u32))
+            }
+        }
+        let mut total = Value::default();
+        let mut sign = 1i32;
+        let mut rest = expr;
+        loop {
+            // a leading sign belongs to the term, and quoted characters can be signs themselves
+            let end = match rest.starts_with('\'') {
+                true => rest.len(),
+                false => rest.char_indices().skip(1).find(|(_, c)| *c == '+' || *c == '-').map_or(rest.len(), |(i, _)| i),
+            };
+            let (term, tail) = rest.split_at(end);
+            let v = self.term(term.trim())?;
+            total.constant = total.constant.wrapping_add(v.constant.wrapping_mul(sign as u32));
+            for (target, n) in v.terms {
+                match total.terms.iter_mut().find(|(t, _)| *t == target) {
+                    Some((_, count)) => *count += n * sign,
+                    None => total.terms.push((target, n * sign)),
+                }
+            }
+            match tail.chars().next() {
+                Some(op) => {
+                    sign = if op == '-' { -1 } else { 1 };
+                    rest = tail[1..].trim();
+                }
+                None => break
+            }
+        }
+        // labels in the same section cancel out when subtracted
+        total.terms.retain(|(_, n)| *n != 0);
+        Ok(total)
+    }
+
+    fn term(&self, term: &str) -> Result<Value> {
+        let parsed = if let Some(neg) = term.strip_prefix('-') {
+            let v = self.term(neg)?;
+            return Ok(Value {
+                constant: v.constant.wrapping_neg(),
+                terms: v.terms.into_iter().map(|(t, n)| (t, -n)).collect(),
+                part: v.part,
+            })
+        }
+        else if term.starts_with("seg(") || term.starts_with("off(") {
+            return Err("seg and off have to be the whole value".to_string())
+        }
+        else if let Some(hex) = term.strip_prefix("0x") {
+            u32::from_str_radix(hex, 16).ok()
+        }
+        else if let Some(bin) = term.strip_prefix("0b") {
+            u32::from_str_radix(bin, 2).ok()
+        }
+        else if term.starts_with('\'') {
+            match string(term)?.as_slice() {
+                [c] => Some(*c as u32),
+                _ => None
+            }
+        }
+        else if term.starts_with(|c: char| c.is_ascii_digit()) {
+            term.parse().ok()
+        }
+        else if term == "." {
+            if self.object {
+                return Ok(Value::target(Target::Section(self.section), self.addr))
+            }
+            Some(self.addr)
+        }
+        else if is_ident(term) {
+            let section = self.label_sections.get(term).filter(|_| self.object);
+            match (self.symbols.get(term).or_else(|| self.known.get(term)), section) {
+                (Some(v), Some(s)) => return Ok(Value::target(Target::Section(*s), *v)),
+                (Some(v), None) => Some(*v),
+                // anything an object doesn't define comes from another one
+                (None, _) if self.object => return Ok(Value::target(Target::Symbol(term.to_string()), 0)),
+                (None, _) if self.pass == 0 => Some(0),
+                (None, _) => return Err(format!("undefined label {}", term)),
+            }
+        }
+        else {
+            None
+        };
+        parsed.map(Value::constant).ok_or(format!("bad value {}", term))
+    }
+
+    fn define(&mut self, name: &str, value: u32) -> Result<()> {
+        match self.pass {
+            0 if self.symbols.insert(name.to_string(), value).is_some() => Err(format!("{} is already defined", name)),
+            // it would have been used with the first pass's value
+            1 if self.symbols.get(name) != Some(&value) => Err(format!("{} never settles on a value", name)),
+            _ => Ok(()),
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        if self.object {
+            self.sections[self.section].data.extend_from_slice(bytes);
+            self.addr = self.addr.wrapping_add(bytes.len() as u32);
+            return
+        }
+        if self.segments.is_empty() {
+            self.segments.push(Segment { addr: self.addr, data: Vec::new() });
+        }
+        self.segments.last_mut().unwrap().data.extend_from_slice(bytes);
+        self.addr = self.addr.wrapping_add(bytes.len() as u32);
+    }
+}
+
+fn is_ident(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        let asm = assemble(source).unwrap();
+        asm.segments.into_iter().flat_map(|s| s.data).collect()
+    }
+
+    #[test]
+    fn instructions() {
+        assert_eq!(bytes("mov word 0x1234, %a"), [0x80, 0x71, 0x34, 0x12, 0x00]);
+        assert_eq!(bytes("ld %al, %si, byte 1"), [0x90, 0x02, 0x14, 0x70, 0x01]);
+        assert_eq!(bytes("test sub word -1"), [0xf0, 0xc6, 0x71, 0xff, 0xff]);
+        assert_eq!(bytes("hlt ; stop"), [0xf4]);
+        assert_eq!(bytes(".bc32\njmp 0x10000"), [0x88, 0x72, 0x00, 0x00, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn labels_and_data() {
+        let asm = assemble("
+            .org 0x100
+            start: jmp end
+            msg: .asciz \"a;b\"
+            .word end - start, 'x'
+            end: hlt
+            size = end - msg
+        ").unwrap();
+        assert_eq!(asm.segments, [Segment {
+            addr: 0x100,
+            data: vec![0x88, 0x71, 0x0c, 0x01, b'a', b';', b'b', 0, 0x0c, 0x00, b'x', 0x00, 0xf4],
+        }]);
+        assert_eq!(asm.symbols["size"], 8);
+    }
+
+    #[test]
+    fn forward_equates() {
+        let asm = assemble("
+            .org 0x100
+            x = y + 1
+            y = end
+            mov word x
+            end: hlt
+        ").unwrap();
+        assert_eq!(asm.symbols["x"], 0x105);
+        assert_eq!(asm.segments[0].data, [0x80, 0x71, 0x05, 0x01, 0xf4]);
+        assert_eq!(assemble("x = x + 1").unwrap_err().msg, "x never settles on a value");
+        assert_eq!(assemble("x = nowhere").unwrap_err().msg, "undefined label nowhere");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(assemble("nop\nfoo").unwrap_err(), AsmError { line: 2, msg: "unknown instruction foo".into() });
+        assert_eq!(assemble("jmp nowhere").unwrap_err().msg, "undefined label nowhere");
+        assert_eq!(assemble("mov byte 0x100").unwrap_err().msg, "0x100 doesn't fit in a byte");
+        assert_eq!(assemble("a: nop\na: nop").unwrap_err().msg, "a is already defined");
+        assert_eq!(assemble("mov %q").unwrap_err().msg, "unknown register %q");
+        assert_eq!(assemble(".section data").unwrap_err().msg, ".section can only be used in objects");
+        assert_eq!(assemble_object(".org 0").unwrap_err().msg, ".org can't be used in objects, sections are placed by the linker");
+        assert_eq!(assemble_object("jmp byte seg(x)").unwrap_err().msg, "seg and off are words");
+        assert_eq!(assemble_object("jmp word x + y").unwrap_err().msg, "a value can only add a single label from another section or object");
+    }
+
+    #[test]
+    fn objects() {
+        let obj = assemble_object("
+            .global start
+            start: jmp word later
+            .byte end - start
+            end:
+            .section data
+            value: .word seg(start), off(extern + 2)
+            size = 4
+            later: nop
+        ").unwrap();
+        assert_eq!(obj.sections[0].name, "text");
+        assert_eq!(obj.sections[0].data, [0x88, 0x71, 0, 0, 5]);
+        assert_eq!(obj.sections[0].relocs, [
+            Reloc { offset: 2, kind: RelocKind::Abs16, target: Target::Section(1), addend: 4 },
+        ]);
+        assert_eq!(obj.sections[1].name, "data");
+        assert_eq!(obj.sections[1].relocs, [
+            Reloc { offset: 0, kind: RelocKind::Seg16, target: Target::Section(0), addend: 0 },
+            Reloc { offset: 2, kind: RelocKind::Off16, target: Target::Symbol("extern".into()), addend: 2 },
+        ]);
+        assert_eq!(obj.symbols["start"], Symbol { section: Some(0), value: 0, global: true });
+        assert_eq!(obj.symbols["value"], Symbol { section: Some(1), value: 0, global: false });
+        assert_eq!(obj.symbols["size"], Symbol { section: None, value: 4, global: false });
+        assert_eq!(Object::from_bytes(&obj.to_bytes().unwrap()), Ok(obj));
+
+        let mut past_end = assemble_object(".word extern").unwrap();
+        past_end.sections[0].relocs[0].offset = 1;
+        assert_eq!(Object::from_bytes(&past_end.to_bytes().unwrap()), Err(object::ObjectError::BadReloc));
+
+        let long = "s".repeat(256);
+        let named = assemble_object(&format!(".section {}\nnop", long)).unwrap();
+        assert_eq!(named.to_bytes(), Err(object::ObjectError::NameTooLong(long)));
+    }
+}
@@ -0,0 +1,214 @@
+//! relocatable objects, which hold sections of code and data that haven't been given addresses yet.
+//! everything is little endian
+//!
+//! ```text
+//! header   "BCPO", version (u8), section count (u16), symbol count (u16)
+//! section  name, length (u32), the bytes, relocation count (u32), then the relocations
+//! reloc    offset (u32), kind (u8), target kind (u8), section index (u16) or symbol name, addend (u32)
+//! symbol   name, global (u8), section index (u16, or 0xffff for values that aren't addresses), value (u32)
+//! ```
+//!
+//! names are a length byte followed by that many bytes of utf-8
+
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::utils::ByteReader;
+
+const MAGIC: &[u8; 4] = b"BCPO";
+const VERSION: u8 = 1;
+const NO_SECTION: u16 = 0xffff;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RelocKind {
+    /// the address in a byte
+    Abs8,
+    /// the address in a word
+    Abs16,
+    /// the address in a dword
+    Abs32,
+    /// the segment offset of the address, the co to use to reach it
+    Seg16,
+    /// the address within its segment, the pc to use with Seg16
+    Off16,
+}
+impl RelocKind {
+    fn from_u8(b: u8) -> Option<RelocKind> {
+        Some(match b {
+            0 => Self::Abs8,
+            1 => Self::Abs16,
+            2 => Self::Abs32,
+            3 => Self::Seg16,
+            4 => Self::Off16,
+            _ => return None
+        })
+    }
+    /// how many bytes the value takes
+    pub fn width(self) -> usize {
+        match self {
+            Self::Abs8 => 1,
+            Self::Abs32 => 4,
+            Self::Abs16 | Self::Seg16 | Self::Off16 => 2,
+        }
+    }
+}
+
+/// what a relocation adds the address of
+#[derive(Debug, PartialEq, Clone)]
+pub enum Target {
+    /// the start of a section of the same object
+    Section(usize),
+    /// a symbol from another object
+    Symbol(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Reloc {
+    /// where in the section the value goes
+    pub offset: u32,
+    pub kind: RelocKind,
+    pub target: Target,
+    pub addend: u32,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub data: Vec<u8>,
+    pub relocs: Vec<Reloc>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Symbol {
+    /// the section a label is in, or none for values defined with =
+    pub section: Option<usize>,
+    pub value: u32,
+    /// whether other objects can see it
+    pub global: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: BTreeMap<String, Symbol>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ObjectError {
+    BadMagic,
+    UnknownVersion(u8),
+    Truncated,
+    BadName,
+    BadReloc,
+    /// a name longer than its length byte can hold
+    NameTooLong(String),
+    /// more sections or symbols than the header can count
+    TooMany,
+}
+impl fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a bcpu object"),
+            Self::UnknownVersion(v) => write!(f, "unknown object version {}", v),
+            Self::Truncated => write!(f, "object is truncated"),
+            Self::BadName => write!(f, "name isn't valid utf-8"),
+            Self::BadReloc => write!(f, "bad relocation"),
+            Self::NameTooLong(name) => write!(f, "name {} is longer than 255 bytes", name),
+            Self::TooMany => write!(f, "more than 65534 sections or 65535 symbols"),
+        }
+    }
+}
+
+fn push_name(out: &mut Vec<u8>, name: &str) -> Result<(), ObjectError> {
+    let len = u8::try_from(name.len()).map_err(|_| ObjectError::NameTooLong(name.to_string()))?;
+    out.push(len);
+    out.extend(name.as_bytes());
+    Ok(())
+}
+
+impl Object {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ObjectError> {
+        // the last section index means no section
+        let section_count = u16::try_from(self.sections.len()).ok()
+            .filter(|&n| n != NO_SECTION)
+            .ok_or(ObjectError::TooMany)?;
+        let symbol_count = u16::try_from(self.symbols.len()).map_err(|_| ObjectError::TooMany)?;
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.extend(section_count.to_le_bytes());
+        out.extend(symbol_count.to_le_bytes());
+        for s in &self.sections {
+            push_name(&mut out, &s.name)?;
+            out.extend((s.data.len() as u32).to_le_bytes());
+            out.extend(&s.data);
+            out.extend((s.relocs.len() as u32).to_le_bytes());
+            for r in &s.relocs {
+                out.extend(r.offset.to_le_bytes());
+                out.push(r.kind as u8);
+                match &r.target {
+                    Target::Section(i) => {
+                        out.push(0);
+                        out.extend((*i as u16).to_le_bytes());
+                    }
+                    Target::Symbol(name) => {
+                        out.push(1);
+                        push_name(&mut out, name)?;
+                    }
+                }
+                out.extend(r.addend.to_le_bytes());
+            }
+        }
+        for (name, sym) in &self.symbols {
+            push_name(&mut out, name)?;
+            out.push(sym.global as u8);
+            out.extend(sym.section.map_or(NO_SECTION, |s| s as u16).to_le_bytes());
+            out.extend(sym.value.to_le_bytes());
+        }
+        Ok(out)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Object, ObjectError> {
+        if !data.starts_with(MAGIC) {
+            return Err(ObjectError::BadMagic)
+        }
+        let mut r = ByteReader::new(data, MAGIC.len(), ObjectError::Truncated);
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(ObjectError::UnknownVersion(version))
+        }
+        let section_count = r.u16()?;
+        let symbol_count = r.u16()?;
+        let mut sections = Vec::new();
+        for _ in 0..section_count {
+            let name = r.name()?.ok_or(ObjectError::BadName)?.to_string();
+            let len = r.u32()? as usize;
+            let data = r.bytes(len)?.to_vec();
+            let mut relocs = Vec::new();
+            for _ in 0..r.u32()? {
+                let offset = r.u32()?;
+                let kind = RelocKind::from_u8(r.u8()?).ok_or(ObjectError::BadReloc)?;
+                let target = match r.u8()? {
+                    0 => Target::Section(r.u16()? as usize),
+                    1 => Target::Symbol(r.name()?.ok_or(ObjectError::BadName)?.to_string()),
+                    _ => return Err(ObjectError::BadReloc)
+                };
+                // the value has to be inside the section
+                if (offset as usize).checked_add(kind.width()).is_none_or(|end| end > data.len()) {
+                    return Err(ObjectError::BadReloc)
+                }
+                relocs.push(Reloc { offset, kind, target, addend: r.u32()? });
+            }
+            sections.push(Section { name, data, relocs });
+        }
+        let mut symbols = BTreeMap::new();
+        for _ in 0..symbol_count {
+            let name = r.name()?.ok_or(ObjectError::BadName)?.to_string();
+            let global = r.u8()? != 0;
+            let section = match r.u16()? {
+                NO_SECTION => None,
+                s => Some(s as usize),
+            };
+            symbols.insert(name, Symbol { section, value: r.u32()?, global });
+        }
+        Ok(Object { sections, symbols })
+    }
+}
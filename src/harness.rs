@@ -0,0 +1,387 @@
+//! runs guest programs as tests. a program is an assembly source file, with what to run it on and what to
+//! expect of it in `;!` comments, or in a sidecar file next to it with an .expect extension and no `;!`
+//!
+//! ```text
+//! ;! map ram 0x0 0x20000        ram over a range. 64KiB at 0 if nothing is mapped
+//! ;! map console 0xff00         bytes written here are collected as output. it shadows any ram under it
+//! ;! map uart 0xff10            a uart, whose output is collected along with the console's
+//! ;! input "abc\n"               what the uart receives
+//! ;! map timer 0xff20           a timer
+//! ;! map disk 0xfc00           a disk, with 16 sectors of zeros
+//! ;! disk "boot"                what's at the start of the disk
+//! ;! map dma 0xff40            a dma controller
+//! ;! map framebuffer 0xa000    a display, rendered once halted for `expect pixel`
+//! ;! map keyboard 0xff50       a keyboard
+//! ;! keys 1000 type "hi\n"       a line of its script of keys, from memory::keyboard
+//! ;! map pic 0xff30             an interrupt controller. devices can be put on its lines with `line`,
+//! ;! map timer 0xff20 line 1    rather than raising plain irqs
+//! ;! start 0x100                where to start. the `start` label or the first segment by default
+//! ;! cycles 100000              how long it gets to halt in. 1,000,000 by default
+//! ;! expect %a = 0x1234         a register once halted
+//! ;! expect mem result = 34 12  memory once halted, in hex bytes
+//! ;! expect output "hi\n"       everything written to the console
+//! ;! expect pixel 8 0 = ff ff 55 a pixel's red, green and blue, rendered from vram once halted
+//! ```
+//!
+//! values are assembler expressions, so they can use the program's labels
+
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::asm::{self, Assembly};
+use crate::image::{self, Image};
+use crate::memory::{disk, framebuffer, keyboard, pic, Device, DeviceKind, Disk, DiskImage, Dma, Framebuffer, Keyboard, KeyboardInput, MemoryMap, Pic, RustMemory, Timer, Uart, UartInput, UartOutput};
+use crate::processor::State;
+use crate::utils;
+use crate::Computer;
+
+const DEFAULT_CYCLES: u64 = 1_000_000;
+const BANK_SIZE: u32 = 0x1_0000;
+const DISK_SECTORS: usize = 16;
+
+/// collects everything written to it
+struct Console {
+    output: Rc<RefCell<Vec<u8>>>,
+}
+impl Device for Console {
+    fn write(&mut self, val: u8, _offset: u32, _range: u32) {
+        self.output.borrow_mut().push(val)
+    }
+    fn write16(&mut self, val: [u8; 2], _offset: u32, _range: u32) {
+        self.output.borrow_mut().extend(val)
+    }
+    fn can_interrupt(&self) -> bool { false }
+}
+
+#[derive(Default)]
+struct Spec {
+    ram: Vec<(u32, u32)>,
+    consoles: Vec<u32>,
+    /// devices mapped by name, at an address and maybe on a line of the pic
+    devices: Vec<(DeviceKind, u32, Option<u8>)>,
+    input: Vec<u8>,
+    disk: Vec<u8>,
+    keys: Vec<(u64, Vec<u8>)>,
+    pic: Option<u32>,
+    start: Option<u32>,
+    cycles: Option<u64>,
+    registers: Vec<(String, u8, u32)>,
+    memory: Vec<(u32, Vec<u8>)>,
+    output: Option<Vec<u8>>,
+    pixels: Vec<(u32, u32, [u8; 3])>,
+}
+
+/// the lines declaring what a program expects, from its sidecar if it has one
+fn spec_lines(path: &Path, source: &str) -> Result<Vec<(String, String)>, String> {
+    let sidecar = path.with_extension("expect");
+    if sidecar.exists() {
+        let text = std::fs::read_to_string(&sidecar).map_err(|e| format!("failed to read {}: {}", sidecar.display(), e))?;
+        Ok(text.lines()
+            .enumerate()
+            .map(|(i, l)| (format!("{}:{}", sidecar.display(), i + 1), l.trim().to_string()))
+            .filter(|(_, l)| !l.is_empty() && !l.starts_with(';'))
+            .collect())
+    }
+    else {
+        Ok(source.lines()
+            .enumerate()
+            .filter_map(|(i, l)| Some((format!("{}:{}", path.display(), i + 1), l.trim().strip_prefix(";!")?.trim().to_string())))
+            .collect())
+    }
+}
+
+/// the quoted string at the end of a line
+fn quoted(line: &str) -> Result<Vec<u8>, String> {
+    let quoted = line.split_once('"').map(|(_, s)| format!("\"{}", s)).unwrap_or_default();
+    utils::string(&quoted)
+}
+
+fn parse(lines: &[(String, String)], asm: &Assembly) -> Result<Spec, String> {
+    let mut spec = Spec::default();
+    for (at, line) in lines {
+        let value = |s: &str| asm.value(s.trim()).map_err(|e| format!("{}: {}", at, e));
+        let pic_line = |s: &str| match value(s)? {
+            n if n < pic::LINES as u32 => Ok(n as u8),
+            n => Err(format!("{}: the pic has no line {}", at, n)),
+        };
+        // anything mapped or expected at an address has to end before the address space does
+        let ends = |addr: u32, len: u32| match addr.checked_add(len) {
+            Some(_) => Ok(addr),
+            None => Err(format!("{}: {:#x} bytes at {:#x} go past the end of memory", at, len, addr)),
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["map", "ram", start, end] => spec.ram.push((value(start)?, value(end)?)),
+            ["map", "console", addr] => spec.consoles.push(ends(value(addr)?, 1)?),
+            ["map", "pic", addr] => spec.pic = Some(ends(value(addr)?, pic::SIZE)?),
+            ["map", name, addr, rest @ ..] => {
+                let kind = DeviceKind::named(name).ok_or(format!("{}: there's no device called {}", at, name))?;
+                let line = match rest {
+                    [] => None,
+                    ["line", n] => Some(pic_line(n)?),
+                    _ => return Err(format!("{}: can't parse {:?}", at, line)),
+                };
+                spec.devices.push((kind, ends(value(addr)?, kind.size())?, line));
+            }
+            ["start", addr] => spec.start = Some(value(addr)?),
+            ["cycles", n] => spec.cycles = Some(value(n)? as u64),
+            ["expect", "output", ..] => spec.output = Some(quoted(line).map_err(|e| format!("{}: {}", at, e))?),
+            ["disk", ..] => spec.disk = quoted(line).map_err(|e| format!("{}: {}", at, e))?,
+            ["keys", ..] => {
+                let keys = keyboard::parse_script_line(&line["keys".len()..]).map_err(|e| format!("{}: {}", at, e))?;
+                spec.keys.extend(keys);
+            }
+            ["input", ..] => spec.input = quoted(line).map_err(|e| format!("{}: {}", at, e))?,
+            ["expect", "mem", ..] => {
+                let (addr, bytes) = line["expect mem".len()..].split_once('=').ok_or(format!("{}: expected `=`", at))?;
+                let bytes = bytes.split_whitespace()
+                    .map(|b| u8::from_str_radix(b, 16).map_err(|_| format!("{}: bad byte {:?}", at, b)))
+                    .collect::<Result<Vec<_>, _>>()?;
+                spec.memory.push((ends(value(addr)?, bytes.len() as u32)?, bytes));
+            }
+            ["expect", "pixel", x, y, "=", r, g, b] => {
+                let (x, y) = (value(x)?, value(y)?);
+                if x >= framebuffer::WIDTH || y >= framebuffer::HEIGHT {
+                    return Err(format!("{}: ({}, {}) is off the screen", at, x, y))
+                }
+                let byte = |b: &str| u8::from_str_radix(b, 16).map_err(|_| format!("{}: bad byte {:?}", at, b));
+                spec.pixels.push((x, y, [byte(r)?, byte(g)?, byte(b)?]));
+            }
+            ["expect", reg, ..] if reg.starts_with('%') => {
+                let (reg, val) = line["expect".len()..].split_once('=').ok_or(format!("{}: expected `=`", at))?;
+                let name = reg.trim().trim_start_matches('%');
+                let id = asm::register(name).ok_or(format!("{}: unknown register %{}", at, name))?;
+                spec.registers.push((name.to_string(), id, value(val)?));
+            }
+            _ => return Err(format!("{}: can't parse {:?}", at, line)),
+        }
+    }
+    if spec.ram.is_empty() {
+        spec.ram.push((0, BANK_SIZE));
+    }
+    match spec.devices.iter().filter(|(kind, _, _)| *kind == DeviceKind::Framebuffer).count() {
+        0 if !spec.pixels.is_empty() => return Err("expecting pixels without a framebuffer".to_string()),
+        2.. => return Err("there's more than one framebuffer".to_string()),
+        _ => (),
+    }
+    if spec.pic.is_none() && spec.devices.iter().any(|(_, _, line)| line.is_some()) {
+        return Err("devices are on lines of a pic, but there isn't one".to_string())
+    }
+    spec.keys.sort_by_key(|(cycle, _)| *cycle);
+    Ok(spec)
+}
+
+/// assembles and runs a program, returning everything that didn't go as expected
+pub fn run(path: &Path) -> Result<(), Vec<String>> {
+    let source = std::fs::read_to_string(path).map_err(|e| vec![format!("failed to read {}: {}", path.display(), e)])?;
+    let asm = asm::assemble(&source).map_err(|e| vec![format!("{}:{}: {}", path.display(), e.line, e.msg)])?;
+    let spec = spec_lines(path, &source).and_then(|lines| parse(&lines, &asm)).map_err(|e| vec![e])?;
+
+    let mut mem = MemoryMap::new();
+    let output = Rc::new(RefCell::new(Vec::new()));
+    for addr in &spec.consoles {
+        mem.add_device_at(Box::new(Console { output: output.clone() }), *addr..addr + 1);
+    }
+    let pic = Pic::new();
+    let connect = |dev: Box<dyn Device>, line: Option<u8>| match line {
+        Some(line) => pic.connect(line, dev),
+        None => dev,
+    };
+    let mut screen = None;
+    for (kind, addr, line) in &spec.devices {
+        let dev: Box<dyn Device> = match kind {
+            DeviceKind::Uart => Box::new(Uart::new(UartInput::Buffer(spec.input.clone()), UartOutput::Capture(output.clone()))),
+            DeviceKind::Timer => Box::new(Timer::new()),
+            DeviceKind::Disk => {
+                let mut data = spec.disk.clone();
+                data.resize(data.len().max(DISK_SECTORS * disk::SECTOR_SIZE as usize), 0);
+                Box::new(Disk::new(DiskImage::Buffer(Rc::new(RefCell::new(data)))).unwrap())
+            }
+            DeviceKind::Dma => Box::new(Dma::new()),
+            DeviceKind::Framebuffer => {
+                let fb = Framebuffer::new(None);
+                screen = Some(fb.screen());
+                Box::new(fb)
+            }
+            DeviceKind::Keyboard => Box::new(Keyboard::new(KeyboardInput::Script(spec.keys.clone()))),
+        };
+        mem.add_device_at(connect(dev, *line), *addr..addr + kind.size());
+    }
+    if let Some(addr) = spec.pic {
+        mem.add_device_at(Box::new(pic), addr..addr + pic::SIZE);
+    }
+    // ram is made of 64KiB banks
+    for (start, end) in &spec.ram {
+        let mut bank = *start;
+        while bank < *end {
+            let bank_end = bank.checked_add(BANK_SIZE).map_or(*end, |e| e.min(*end));
+            mem.add_device_at(Box::new(RustMemory::new()), bank..bank_end);
+            bank = bank_end;
+        }
+    }
+    let mut computer = Computer::new(mem);
+    let mut image = Image::from_assembly(&asm).map_err(|e| vec![format!("{}: {}", path.display(), e)])?;
+    if let Some(start) = spec.start {
+        (image.entry_co, image.entry_pc) = image::split_addr(start)
+            .ok_or(vec![format!("{}: start {:#x} doesn't fit in a segment", path.display(), start)])?;
+    }
+    image.load(&mut computer.memory_map, &mut computer.processor);
+
+    let mut mismatches = Vec::new();
+    let max_cycles = spec.cycles.unwrap_or(DEFAULT_CYCLES);
+    // waiting is clocked by hand rather than fast forwarded, so a program waiting forever still runs out of cycles
+    while computer.processor.state() != State::Halted {
+        if computer.cycles >= max_cycles {
+            mismatches.push(format!("didn't halt within {} cycles", max_cycles));
+            break
+        }
+        if computer.processor.state() == State::Waiting {
+            computer.tick(1);
+        }
+        else {
+            computer.clock();
+        }
+    }
+
+    for (name, id, val) in &spec.registers {
+        match computer.processor.inspect(*id) {
+            Some(actual) if actual == *val => (),
+            Some(actual) => mismatches.push(format!("%{} is {:#x}, expected {:#x}", name, actual, val)),
+            None => mismatches.push(format!("%{} can't be read", name)),
+        }
+    }
+    for (addr, bytes) in &spec.memory {
+        let actual: Vec<u8> = (0..bytes.len() as u32).map(|i| computer.memory_map.read(addr + i)).collect();
+        if actual != *bytes {
+            mismatches.push(format!("memory at {:#x} is {:02x?}, expected {:02x?}", addr, actual, bytes));
+        }
+    }
+    if let Some(expected) = &spec.output {
+        let actual = output.borrow();
+        if *actual != *expected {
+            mismatches.push(format!("output is {:?}, expected {:?}",
+                String::from_utf8_lossy(&actual), String::from_utf8_lossy(expected)));
+        }
+    }
+    if let Some(screen) = screen.filter(|_| !spec.pixels.is_empty()) {
+        let frame = screen.render();
+        for (x, y, expected) in &spec.pixels {
+            let actual = frame.pixel(*x, *y);
+            if actual != *expected {
+                mismatches.push(format!("pixel ({}, {}) is {:02x?}, expected {:02x?}", x, y, actual, expected));
+            }
+        }
+    }
+    if mismatches.is_empty() { Ok(()) } else { Err(mismatches) }
+}
+
+/// the programs to run for some paths, taking every .s file in a directory
+pub fn programs(paths: &[PathBuf]) -> std::io::Result<Vec<PathBuf>> {
+    let mut programs = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut found: Vec<PathBuf> = std::fs::read_dir(path)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<std::io::Result<_>>()?;
+            found.retain(|p| p.extension().is_some_and(|e| e == "s"));
+            found.sort();
+            programs.extend(found);
+        }
+        else {
+            programs.push(path.clone());
+        }
+    }
+    Ok(programs)
+}
+
+/// runs programs, returning a report of how they went in the style of cargo test, and whether they all passed
+pub fn run_all(programs: &[PathBuf]) -> (String, bool) {
+    let mut report = String::new();
+    let mut failures = Vec::new();
+    writeln!(report, "running {} programs", programs.len()).unwrap();
+    for p in programs {
+        let result = run(p);
+        writeln!(report, "program {} ... {}", p.display(), if result.is_ok() { "ok" } else { "FAILED" }).unwrap();
+        if let Err(mismatches) = result {
+            failures.push((p, mismatches));
+        }
+    }
+    if !failures.is_empty() {
+        writeln!(report, "\nfailures:").unwrap();
+        for (p, mismatches) in &failures {
+            writeln!(report, "\n---- {} ----", p.display()).unwrap();
+            for m in mismatches {
+                writeln!(report, "{}", m).unwrap();
+            }
+        }
+    }
+    writeln!(report, "\nprogram result: {}. {} passed; {} failed",
+        if failures.is_empty() { "ok" } else { "FAILED" }, programs.len() - failures.len(), failures.len()).unwrap();
+    (report, failures.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn programs_dir() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("programs");
+        let programs = programs(&[dir]).unwrap();
+        assert!(!programs.is_empty());
+        let (report, ok) = run_all(&programs);
+        assert!(ok, "{}", report);
+    }
+
+    #[test]
+    fn bad_maps() {
+        let asm = asm::assemble("hlt").unwrap();
+        let error = |lines: &[&str]| {
+            let lines: Vec<_> = lines.iter().map(|l| ("t".to_string(), l.to_string())).collect();
+            parse(&lines, &asm).err()
+        };
+        assert_eq!(error(&["map timer 0xff20 line 1", "map pic 0xff30"]).as_deref(), None);
+        assert_eq!(error(&["map mouse 0xff20"]).as_deref(), Some("t: there's no device called mouse"));
+        assert_eq!(error(&["map timer 0xff20 line"]).as_deref(), Some("t: can't parse \"map timer 0xff20 line\""));
+        assert_eq!(error(&["map timer 0xff20 line 1"]).as_deref(), Some("devices are on lines of a pic, but there isn't one"));
+        assert_eq!(error(&["map framebuffer 0xa000", "map framebuffer 0xc000"]).as_deref(), Some("there's more than one framebuffer"));
+        assert_eq!(error(&["map disk 0xfffffe00"]).as_deref(), Some("t: 0x400 bytes at 0xfffffe00 go past the end of memory"));
+        assert_eq!(error(&["expect mem 0xffffffff = 01 02"]).as_deref(), Some("t: 0x2 bytes at 0xffffffff go past the end of memory"));
+    }
+
+    #[test]
+    fn reports_mismatches() {
+        let dir = std::env::temp_dir().join(format!("bcpu-harness-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wrong.s");
+        std::fs::write(&path, "\
+            ;! map console 0xff00\n\
+            ;! expect %a = 2\n\
+            ;! expect mem 0x10 = ff\n\
+            ;! expect output \"no\"\n\
+            mov word 1, %a\n\
+            mov byte 'y', %al\n\
+            st %al, word 0xff00\n\
+            hlt\n").unwrap();
+        let mismatches = run(&path).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(mismatches, [
+            "%a is 0x79, expected 0x2",
+            "memory at 0x10 is [00], expected [ff]",
+            "output is \"y\", expected \"no\"",
+        ]);
+    }
+
+    #[test]
+    fn runs_out_of_cycles() {
+        let dir = std::env::temp_dir().join(format!("bcpu-harness-loop-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("loop.s");
+        std::fs::write(&path, "loop: jmp loop\nhlt\n").unwrap();
+        std::fs::write(dir.join("loop.expect"), "cycles 1000\n").unwrap();
+        let mismatches = run(&path).unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(mismatches, ["didn't halt within 1000 cycles"]);
+    }
+}
@@ -0,0 +1,396 @@
+//! intel hex and motorola s-record files, the text formats eprom programmers and other toolchains use.
+//! both are lines of hex records, each with an address, some data and a checksum
+//!
+//! ```text
+//! :10010000214601360121470136007EFE09D2190140   intel hex, data at 0x100
+//! S1130100214601360121470136007EFE09D219013C    s-record, the same data
+//! ```
+//!
+//! intel hex reaches past 64KiB with extended segment (02) and linear (04) address records,
+//! s-records with S2 and S3 records that have 24 and 32 bit addresses
+//!
+//! the runner loads them in place of a binary and exports ranges with --dump. there's no debugger to hook them into yet,
+//! so nothing can load or dump them while a program is stopped
+
+use std::fmt;
+use std::ops::Range;
+use std::path::Path;
+use crate::memory::MemoryMap;
+
+/// bytes per data record when exporting
+const RECORD_LEN: usize = 16;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    IntelHex,
+    SRecord,
+}
+impl Format {
+    /// guesses the format from a file's extension
+    pub fn from_path(path: &Path) -> Option<Format> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "hex" | "ihex" | "ihx" => Some(Format::IntelHex),
+            "srec" | "s19" | "s28" | "s37" | "mot" => Some(Format::SRecord),
+            _ => None,
+        }
+    }
+    /// guesses the format from what a file starts with
+    pub fn detect(data: &[u8]) -> Option<Format> {
+        match data.iter().find(|b| !b.is_ascii_whitespace())? {
+            b':' => Some(Format::IntelHex),
+            b'S' if data.iter().all(|b| b.is_ascii_alphanumeric() || b.is_ascii_whitespace()) => Some(Format::SRecord),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HexError {
+    pub line: usize,
+    pub msg: String,
+}
+impl fmt::Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+/// the contents of a hex file
+#[derive(Debug, PartialEq, Default)]
+pub struct Records {
+    /// runs of bytes and where they go, with runs next to each other joined up
+    pub chunks: Vec<(u32, Vec<u8>)>,
+    /// the start address, if the file gives one
+    pub start: Option<u32>,
+}
+
+impl Records {
+    pub fn parse(text: &str, format: Format) -> Result<Records, HexError> {
+        match format {
+            Format::IntelHex => Records::parse_ihex(text),
+            Format::SRecord => Records::parse_srec(text),
+        }
+    }
+
+    fn parse_ihex(text: &str) -> Result<Records, HexError> {
+        let mut records = Records::default();
+        let mut base = 0u32;
+        // type 02 bases are segments, and the data's offsets wrap around within them
+        let mut segmented = false;
+        for (i, line) in text.lines().enumerate() {
+            let err = |msg: &str| HexError { line: i + 1, msg: msg.to_string() };
+            let line = line.trim();
+            if line.is_empty() {
+                continue
+            }
+            let bytes = line.strip_prefix(':').and_then(decode).ok_or(err("not an intel hex record"))?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(err("record length doesn't match its byte count"))
+            }
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(err("bad checksum"))
+            }
+            let addr = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            match (bytes[3], data) {
+                (0x00, _) if segmented => {
+                    let (before, after) = data.split_at(data.len().min((0x1_0000 - addr) as usize));
+                    records.add(base.wrapping_add(addr), before);
+                    if !after.is_empty() {
+                        records.add(base, after);
+                    }
+                }
+                (0x00, _) => records.add(base.wrapping_add(addr), data),
+                (0x01, _) => break,
+                (0x02, [hi, lo]) => {
+                    base = (u16::from_be_bytes([*hi, *lo]) as u32) << 4;
+                    segmented = true;
+                }
+                (0x03, [a, b, c, d]) => {
+                    let (cs, ip) = (u16::from_be_bytes([*a, *b]) as u32, u16::from_be_bytes([*c, *d]) as u32);
+                    records.start = Some((cs << 4).wrapping_add(ip));
+                }
+                (0x04, [hi, lo]) => {
+                    base = (u16::from_be_bytes([*hi, *lo]) as u32) << 16;
+                    segmented = false;
+                }
+                (0x05, [a, b, c, d]) => records.start = Some(u32::from_be_bytes([*a, *b, *c, *d])),
+                (0x02..=0x05, _) => return Err(err("wrong amount of data for the record type")),
+                (kind, _) => return Err(err(&format!("unknown record type {:02x}", kind))),
+            }
+        }
+        Ok(records)
+    }
+
+    fn parse_srec(text: &str) -> Result<Records, HexError> {
+        let mut records = Records::default();
+        for (i, line) in text.lines().enumerate() {
+            let err = |msg: &str| HexError { line: i + 1, msg: msg.to_string() };
+            let line = line.trim();
+            if line.is_empty() {
+                continue
+            }
+            let (kind, bytes) = line.strip_prefix('S')
+                .filter(|l| l.is_ascii())
+                .and_then(|l| Some((l.chars().next()?, decode(&l[1..])?)))
+                .ok_or(err("not an s-record"))?;
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                return Err(err("record length doesn't match its byte count"))
+            }
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+                return Err(err("bad checksum"))
+            }
+            let addr_len = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => return Err(err(&format!("unknown record type S{}", kind))),
+            };
+            if bytes.len() < addr_len + 2 {
+                return Err(err("record is too short for its address"))
+            }
+            let addr = bytes[1..=addr_len].iter().fold(0u32, |a, b| a << 8 | *b as u32);
+            let data = &bytes[addr_len + 1..bytes.len() - 1];
+            match kind {
+                '1' | '2' | '3' => records.add(addr, data),
+                '7' | '8' | '9' => records.start = Some(addr),
+                // the header and record counts don't say anything about what to load
+                _ => (),
+            }
+        }
+        Ok(records)
+    }
+
+    /// adds bytes at an address, joining them onto the last run if they follow it
+    pub fn add(&mut self, addr: u32, data: &[u8]) {
+        match self.chunks.last_mut() {
+            Some((start, bytes)) if start.wrapping_add(bytes.len() as u32) == addr => bytes.extend(data),
+            _ => self.chunks.push((addr, data.to_vec())),
+        }
+    }
+
+    /// reads a range of memory. reading goes through devices, so ones with side effects on reads will see them
+    pub fn from_memory(mem: &mut MemoryMap, range: Range<u32>) -> Records {
+        let data = range.clone().map(|addr| mem.read(addr)).collect();
+        Records { chunks: vec![(range.start, data)], start: None }
+    }
+
+    /// writes everything into memory, or nothing if any of it would be lost, returning the first address that would.
+    /// that's anywhere nothing is mapped, and anything read only like rom
+    pub fn load(&self, mem: &mut MemoryMap) -> Result<(), u32> {
+        for (addr, data) in &self.chunks {
+            let unwritable = (0..data.len() as u32).map(|i| addr.wrapping_add(i)).find(|a| !mem.is_writable(*a));
+            if let Some(a) = unwritable {
+                return Err(a)
+            }
+        }
+        for (addr, data) in &self.chunks {
+            for (i, b) in data.iter().enumerate() {
+                mem.write(*b, addr.wrapping_add(i as u32));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_string(&self, format: Format) -> String {
+        match format {
+            Format::IntelHex => self.to_ihex(),
+            Format::SRecord => self.to_srec(),
+        }
+    }
+
+    fn to_ihex(&self) -> String {
+        let mut out = String::new();
+        let mut upper = 0;
+        for (addr, data) in self.lines() {
+            if addr >> 16 != upper {
+                upper = addr >> 16;
+                ihex_record(&mut out, 0, 0x04, &(upper as u16).to_be_bytes());
+            }
+            ihex_record(&mut out, addr as u16, 0x00, data);
+        }
+        if let Some(start) = self.start {
+            ihex_record(&mut out, 0, 0x05, &start.to_be_bytes());
+        }
+        ihex_record(&mut out, 0, 0x01, &[]);
+        out
+    }
+
+    fn to_srec(&self) -> String {
+        let end = self.chunks.iter().map(|(a, d)| *a as u64 + d.len() as u64).max().unwrap_or(0);
+        let end = end.max(self.start.unwrap_or(0) as u64 + 1);
+        let (data_kind, start_kind, addr_len) = match end {
+            0..=0x1_0000 => ('1', '9', 2),
+            0x1_0001..=0x100_0000 => ('2', '8', 3),
+            _ => ('3', '7', 4),
+        };
+        let mut out = String::new();
+        srec_record(&mut out, '0', 0, 2, b"bcpu");
+        let mut count = 0u32;
+        for (addr, data) in self.lines() {
+            srec_record(&mut out, data_kind, addr, addr_len, data);
+            count += 1;
+        }
+        match count {
+            0..=0xffff => srec_record(&mut out, '5', count, 2, &[]),
+            _ => srec_record(&mut out, '6', count, 3, &[]),
+        }
+        srec_record(&mut out, start_kind, self.start.unwrap_or(0), addr_len, &[]);
+        out
+    }
+
+    /// the data split into records, none of which cross into the next 64KiB
+    fn lines(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        self.chunks.iter().flat_map(|(addr, data)| {
+            let mut addr = *addr;
+            let mut data = data.as_slice();
+            std::iter::from_fn(move || {
+                if data.is_empty() {
+                    return None
+                }
+                let len = data.len().min(RECORD_LEN).min(0x1_0000 - (addr & 0xffff) as usize);
+                let (line, rest) = data.split_at(len);
+                let at = addr;
+                addr = addr.wrapping_add(len as u32);
+                data = rest;
+                Some((at, line))
+            })
+        })
+    }
+}
+
+/// hex digits as bytes
+fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+fn ihex_record(out: &mut String, addr: u16, kind: u8, data: &[u8]) {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend(addr.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend(data);
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(sum.wrapping_neg());
+    out.push(':');
+    out.extend(bytes.iter().map(|b| format!("{:02X}", b)));
+    out.push('\n');
+}
+
+fn srec_record(out: &mut String, kind: char, addr: u32, addr_len: usize, data: &[u8]) {
+    let mut bytes = vec![(addr_len + data.len() + 1) as u8];
+    bytes.extend(&addr.to_be_bytes()[4 - addr_len..]);
+    bytes.extend(data);
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    bytes.push(!sum);
+    out.push('S');
+    out.push(kind);
+    out.extend(bytes.iter().map(|b| format!("{:02X}", b)));
+    out.push('\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{Rom, RustMemory};
+
+    #[test]
+    fn intel_hex() {
+        let text = "\
+            :10010000214601360121470136007EFE09D2190140\n\
+            :020000040001F9\n\
+            :0300FE00010203F9\n\
+            :0400000500010100F5\n\
+            :00000001FF\n\
+            :this is ignored after the end\n";
+        let records = Records::parse(text, Format::IntelHex).unwrap();
+        assert_eq!(records.chunks, [
+            (0x100, vec![0x21, 0x46, 0x01, 0x36, 0x01, 0x21, 0x47, 0x01, 0x36, 0x00, 0x7e, 0xfe, 0x09, 0xd2, 0x19, 0x01]),
+            (0x100fe, vec![1, 2, 3]),
+        ]);
+        assert_eq!(records.start, Some(0x10100));
+        assert_eq!(Records::parse(&records.to_string(Format::IntelHex), Format::IntelHex), Ok(records));
+
+        let segmented = Records::parse(":020000021000EC\n:01000400AA51\n:0400000300100020C9\n", Format::IntelHex).unwrap();
+        assert_eq!(segmented.chunks, [(0x10004, vec![0xaa])]);
+        assert_eq!(segmented.start, Some(0x120));
+        // offsets wrap around within a segment, but not within a linear base
+        let wrapped = Records::parse(":020000021000EC\n:04FFFE0001020304F5\n", Format::IntelHex).unwrap();
+        assert_eq!(wrapped.chunks, [(0x1fffe, vec![1, 2]), (0x10000, vec![3, 4])]);
+        let linear = Records::parse(":020000040001F9\n:04FFFE0001020304F5\n", Format::IntelHex).unwrap();
+        assert_eq!(linear.chunks, [(0x1fffe, vec![1, 2, 3, 4])]);
+    }
+
+    #[test]
+    fn s_records() {
+        let text = "\
+            S00600004844521B\n\
+            S1130100214601360121470136007EFE09D219013C\n\
+            S5030001FB\n\
+            S9030100FB\n";
+        let records = Records::parse(text, Format::SRecord).unwrap();
+        assert_eq!(records.chunks[0].0, 0x100);
+        assert_eq!(records.start, Some(0x100));
+        assert_eq!(records.to_string(Format::SRecord), "\
+            S0070000626370754E\n\
+            S1130100214601360121470136007EFE09D219013C\n\
+            S5030001FB\n\
+            S9030100FB\n");
+
+        let mut wide = Records::default();
+        wide.add(0x12_3456, &[1, 2]);
+        let text = wide.to_string(Format::SRecord);
+        assert!(text.contains("S2061234560102"));
+        assert_eq!(Records::parse(&text, Format::SRecord).unwrap().chunks, wide.chunks);
+    }
+
+    #[test]
+    fn long_data_is_split() {
+        let mut records = Records::default();
+        records.add(0xfff8, &[0x55; 40]);
+        let text = records.to_string(Format::IntelHex);
+        assert_eq!(text.lines().count(), 5);
+        assert_eq!(Records::parse(&text, Format::IntelHex).unwrap().chunks, records.chunks);
+        assert_eq!(Records::parse(&records.to_string(Format::SRecord), Format::SRecord).unwrap().chunks, records.chunks);
+    }
+
+    #[test]
+    fn errors() {
+        let err = |text, format| Records::parse(text, format).unwrap_err();
+        assert_eq!(err(":0100000001FF", Format::IntelHex), HexError { line: 1, msg: "bad checksum".into() });
+        assert_eq!(err("\n:02000000AA", Format::IntelHex).line, 2);
+        assert_eq!(err("hello", Format::IntelHex).msg, "not an intel hex record");
+        assert_eq!(err(":00000006FA", Format::IntelHex).msg, "unknown record type 06");
+        assert_eq!(err("S1030000FB", Format::SRecord).msg, "bad checksum");
+        assert_eq!(err("S4030000FC", Format::SRecord).msg, "unknown record type S4");
+        assert_eq!(err("S\u{e9}030000FC", Format::SRecord).msg, "not an s-record");
+        assert_eq!(Format::detect(b"\n:00000001FF"), Some(Format::IntelHex));
+        assert_eq!(Format::detect(b"S9030000FC\n"), Some(Format::SRecord));
+        assert_eq!(Format::detect(b"BCPU\x01"), None);
+    }
+
+    #[test]
+    fn memory() {
+        let mut mem = MemoryMap::new();
+        mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+        let mut records = Records::default();
+        records.add(0xfffe, &[1, 2, 3]);
+        assert_eq!(records.load(&mut mem), Err(0x1_0000));
+        assert_eq!(mem.read(0xfffe), 0);
+
+        records.chunks[0].1.pop();
+        records.load(&mut mem).unwrap();
+        assert_eq!(Records::from_memory(&mut mem, 0xfffd..0x1_0000).chunks, [(0xfffd, vec![0, 1, 2])]);
+
+        // rom would silently drop what's loaded into it, even when it doesn't fault
+        let mut mem = MemoryMap::new();
+        mem.add_device_at(Box::new(Rom::new(vec![0; 2], false)), 0xff00..0xff02);
+        mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+        let mut records = Records::default();
+        records.add(0xfeff, &[1, 2]);
+        assert_eq!(records.load(&mut mem), Err(0xff00));
+        assert_eq!(mem.read(0xfeff), 0);
+    }
+}
@@ -0,0 +1,274 @@
+//! the executable image format, which carries where code and data go, where to start and the symbols.
+//! everything is little endian
+//!
+//! ```text
+//! header   "BCPU", version (u8), segment count (u16), symbol count (u16), entry co (u16), entry pc (u16)
+//! segment  segment offset (u16), load address (u16), length (u32), then the bytes
+//! symbol   name length (u8), the name, value (u32)
+//! ```
+//!
+//! like co:pc, a segment's bytes go at its load address plus its segment offset shifted up by 8
+
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::asm::Assembly;
+use crate::memory::MemoryMap;
+use crate::processor::Processor;
+use crate::utils::ByteReader;
+
+const MAGIC: &[u8; 4] = b"BCPU";
+const VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq)]
+pub struct ImageSegment {
+    pub offset: u16,
+    pub addr: u16,
+    pub data: Vec<u8>,
+}
+impl ImageSegment {
+    pub fn flat_addr(&self) -> u32 {
+        self.addr as u32 + ((self.offset as u32) << 8)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Image {
+    pub entry_co: u16,
+    pub entry_pc: u16,
+    pub segments: Vec<ImageSegment>,
+    pub symbols: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ImageError {
+    BadMagic,
+    UnknownVersion(u8),
+    /// the data ended before everything the header declared
+    Truncated,
+    BadSymbol,
+    /// a symbol name longer than its length byte can hold
+    NameTooLong(String),
+    /// more segments or symbols than the header can count
+    TooMany,
+    /// a flat address past the 16MiB a segment offset can reach
+    OutOfReach(u32),
+}
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::BadMagic => write!(f, "not a bcpu image"),
+            Self::UnknownVersion(v) => write!(f, "unknown image version {}", v),
+            Self::Truncated => write!(f, "image is truncated"),
+            Self::BadSymbol => write!(f, "symbol name isn't valid utf-8"),
+            Self::NameTooLong(name) => write!(f, "symbol name {} is longer than 255 bytes", name),
+            Self::TooMany => write!(f, "more than 65535 segments or symbols"),
+            Self::OutOfReach(flat) => write!(f, "{:#x} is past the 16MiB segments can reach", flat),
+        }
+    }
+}
+
+/// splits a flat address into a segment offset and an address within it, like co:pc,
+/// or none past 16MiB where the offset no longer fits
+pub fn split_addr(flat: u32) -> Option<(u16, u16)> {
+    (flat < 0x100_0000).then_some(((flat >> 16 << 8) as u16, flat as u16))
+}
+
+/// whether some data looks like an image rather than a raw binary
+pub fn is_image(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+impl Image {
+    /// an image of assembled code, starting at the `start` label or the first segment
+    pub fn from_assembly(asm: &Assembly) -> Result<Image, ImageError> {
+        let start = asm.symbols.get("start").copied()
+            .or_else(|| asm.segments.first().map(|s| s.addr))
+            .unwrap_or(0);
+        let (entry_co, entry_pc) = split_addr(start).ok_or(ImageError::OutOfReach(start))?;
+        let mut image = Image { entry_co, entry_pc, segments: Vec::new(), symbols: asm.symbols.clone() };
+        for s in &asm.segments {
+            image.add_segment(s.addr, &s.data)?;
+        }
+        Ok(image)
+    }
+    /// adds data to load at a flat address, split up so no segment crosses into the next 64KiB,
+    /// since a segment's load address is only 16 bits
+    pub fn add_segment(&mut self, flat: u32, data: &[u8]) -> Result<(), ImageError> {
+        let (mut flat, mut data) = (flat, data);
+        while !data.is_empty() {
+            let len = data.len().min(0x1_0000 - (flat & 0xffff) as usize);
+            let (offset, addr) = split_addr(flat).ok_or(ImageError::OutOfReach(flat))?;
+            self.segments.push(ImageSegment { offset, addr, data: data[..len].to_vec() });
+            flat = flat.wrapping_add(len as u32);
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, ImageError> {
+        let segment_count = u16::try_from(self.segments.len()).map_err(|_| ImageError::TooMany)?;
+        let symbol_count = u16::try_from(self.symbols.len()).map_err(|_| ImageError::TooMany)?;
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        out.extend(segment_count.to_le_bytes());
+        out.extend(symbol_count.to_le_bytes());
+        out.extend(self.entry_co.to_le_bytes());
+        out.extend(self.entry_pc.to_le_bytes());
+        for s in &self.segments {
+            out.extend(s.offset.to_le_bytes());
+            out.extend(s.addr.to_le_bytes());
+            out.extend((s.data.len() as u32).to_le_bytes());
+            out.extend(&s.data);
+        }
+        for (name, value) in &self.symbols {
+            let len = u8::try_from(name.len()).map_err(|_| ImageError::NameTooLong(name.clone()))?;
+            out.push(len);
+            out.extend(name.as_bytes());
+            out.extend(value.to_le_bytes());
+        }
+        Ok(out)
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Image, ImageError> {
+        if !is_image(data) {
+            return Err(ImageError::BadMagic)
+        }
+        let mut r = ByteReader::new(data, MAGIC.len(), ImageError::Truncated);
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(ImageError::UnknownVersion(version))
+        }
+        let segment_count = r.u16()?;
+        let symbol_count = r.u16()?;
+        let entry_co = r.u16()?;
+        let entry_pc = r.u16()?;
+        let mut segments = Vec::new();
+        for _ in 0..segment_count {
+            let offset = r.u16()?;
+            let addr = r.u16()?;
+            let len = r.u32()? as usize;
+            segments.push(ImageSegment { offset, addr, data: r.bytes(len)?.to_vec() });
+        }
+        let mut symbols = BTreeMap::new();
+        for _ in 0..symbol_count {
+            let name = r.name()?.ok_or(ImageError::BadSymbol)?;
+            symbols.insert(name.to_string(), r.u32()?);
+        }
+        Ok(Image { entry_co, entry_pc, segments, symbols })
+    }
+
+    /// writes the segments into memory and points the processor at the entry
+    pub fn load(&self, mem: &mut MemoryMap, processor: &mut Processor) {
+        for s in &self.segments {
+            let start = s.flat_addr();
+            for (i, b) in s.data.iter().enumerate() {
+                mem.write(*b, start.wrapping_add(i as u32));
+            }
+        }
+        processor.set_entry(self.entry_co, self.entry_pc as u32);
+    }
+
+    /// names a flat address after the closest symbol at or before it, as name+0x12
+    pub fn symbolize(&self, addr: u32) -> Option<String> {
+        let (name, value) = self.symbols.iter()
+            .filter(|(_, v)| **v <= addr)
+            .max_by_key(|(_, v)| **v)?;
+        Some(match addr - value {
+            0 => name.clone(),
+            offset => format!("{}+{:#x}", name, offset),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::memory::RustMemory;
+    use crate::processor::State;
+
+    #[test]
+    fn round_trip() {
+        let asm = assemble("
+            .org 0x100
+            start: hlt
+            .org 0x120000
+            data: .byte 1, 2, 3
+        ").unwrap();
+        let image = Image::from_assembly(&asm).unwrap();
+        assert_eq!(image.segments, [
+            ImageSegment { offset: 0, addr: 0x100, data: vec![0xf4] },
+            ImageSegment { offset: 0x1200, addr: 0, data: vec![1, 2, 3] },
+        ]);
+        assert_eq!((image.entry_co, image.entry_pc), (0, 0x100));
+        assert_eq!(Image::from_bytes(&image.to_bytes().unwrap()), Ok(image));
+    }
+
+    #[test]
+    fn bad_images() {
+        assert_eq!(Image::from_bytes(b"\x7fELF"), Err(ImageError::BadMagic));
+        assert_eq!(Image::from_bytes(b"BCPU\x02"), Err(ImageError::UnknownVersion(2)));
+        let asm = assemble("hlt\nx: nop").unwrap();
+        let bytes = Image::from_assembly(&asm).unwrap().to_bytes().unwrap();
+        for len in 5..bytes.len() {
+            assert_eq!(Image::from_bytes(&bytes[..len]), Err(ImageError::Truncated));
+        }
+    }
+
+    #[test]
+    fn unwritable_images() {
+        let mut image = Image { entry_co: 0, entry_pc: 0, segments: Vec::new(), symbols: BTreeMap::new() };
+        let long = "é".repeat(128);
+        image.symbols.insert(long.clone(), 0);
+        assert_eq!(image.to_bytes(), Err(ImageError::NameTooLong(long)));
+        image.symbols.clear();
+        for i in 0..0x1_0000 {
+            image.add_segment(i << 8, &[0]).unwrap();
+        }
+        assert_eq!(image.to_bytes(), Err(ImageError::TooMany));
+        image.segments.pop();
+        assert!(image.to_bytes().is_ok());
+    }
+
+    #[test]
+    fn segments_crossing_64k_are_split() {
+        let asm = assemble(".org 0xfffe\n.byte 1, 2, 3, 4").unwrap();
+        let image = Image::from_assembly(&asm).unwrap();
+        assert_eq!(image.segments, [
+            ImageSegment { offset: 0, addr: 0xfffe, data: vec![1, 2] },
+            ImageSegment { offset: 0x100, addr: 0, data: vec![3, 4] },
+        ]);
+    }
+
+    #[test]
+    fn past_16m_is_out_of_reach() {
+        assert_eq!(split_addr(0xff_ffff), Some((0xff00, 0xffff)));
+        assert_eq!(split_addr(0x100_0000), None);
+        let asm = assemble(".org 0x1000000\nhlt").unwrap();
+        assert_eq!(Image::from_assembly(&asm), Err(ImageError::OutOfReach(0x100_0000)));
+        let mut image = Image { entry_co: 0, entry_pc: 0, segments: Vec::new(), symbols: BTreeMap::new() };
+        assert_eq!(image.add_segment(0xff_fffe, &[1, 2, 3]), Err(ImageError::OutOfReach(0x100_0000)));
+    }
+
+    #[test]
+    fn load_and_symbolize() {
+        let asm = assemble("
+            .org 0x10200
+            start: mov word 1, %a
+            done: hlt
+            nop
+        ").unwrap();
+        let image = Image::from_assembly(&asm).unwrap();
+        let mut mem = MemoryMap::new();
+        mem.add_device_at(Box::new(RustMemory::new()), 0x1_0000..0x2_0000);
+        let mut p = Processor::default();
+        image.load(&mut mem, &mut p);
+        while p.state() != State::Halted {
+            p.clock(&mut mem);
+        }
+        assert_eq!(p.inspect(0x00), Some(1));
+        assert_eq!(image.symbolize(p.flat_pc()).as_deref(), Some("done+0x1"));
+        assert_eq!(image.symbolize(0x1_0200).as_deref(), Some("start"));
+        assert_eq!(image.symbolize(0x100), None);
+    }
+}
@@ -1,23 +1,552 @@
-#![feature(result_flattening)]
-#![feature(exclusive_range_pattern)]
-#![feature(bigint_helper_methods)]
-#![feature(type_name_of_val)]
+#![cfg_attr(test, feature(test))]
 
-use memory::MemoryMap;
-use processor::Processor;
+use std::time::Duration;
+use memory::{keyboard, Device, DeviceKind, Disk, Dma, Framebuffer, Keyboard, KeyboardInput, MemoryMap, Pic, Rom, RustMemory, DevMsg, Timer, Uart, UartInput, UartOutput};
+use image::Image;
+use processor::{Processor, State};
 
+mod asm;
+mod harness;
+mod hex;
+mod image;
 mod memory;
 mod processor;
 mod utils;
 
+/// how many device clocks to fast forward through before sleeping, while waiting for an interrupt
+const WFI_BATCH: u32 = 10_000;
+const WFI_SLEEP: Duration = Duration::from_millis(1);
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("test") => return test(&args[2..]),
+        Some("asm") => return assemble(&args[2..]),
+        Some("ld") => return ld(&args[2..]),
+        _ => ()
+    }
+    let (flags, positional): (Vec<&String>, Vec<&String>) = args[1..].iter().partition(|a| a.starts_with("--"));
+    let path = match positional.as_slice() {
+        [p] => *p,
+        _ => {
+            eprintln!("usage: {0} [--no-decode-cache] [--translate] [--dump=<start>-<end>:<file>]... \
+                [--rom=<file>@<addr or start-end>,...]... [--rom-fault=...]... [--pic=<addr>] [--uart=<addr>[:<line>]] [--timer=<addr>[:<line>]] \
+                [--dma=<addr>[:<line>]]... [--framebuffer=<addr>[:<line>]] [--frame=<png or ppm>] [--frames=<pattern>] \
+                [--keyboard=<addr>[:<line>]] [--keys=<script>] [--disk=<file>@<addr>[:<line>]]... <image, hex or srec>\n       \
+                {0} asm [-c] <source> <image or object>\n       \
+                {0} ld [-T <script>] [--place <section>=<addr>] [--entry <label>] -o <image> <object>...\n       \
+                {0} test <program or directory>...", args[0]);
+            std::process::exit(1)
+        }
+    };
+    let data = match std::fs::read(path) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", path, e);
+            std::process::exit(1)
+        }
+    };
+
+    // roms are mapped first, so they shadow the ram under them
+    let mut mem = MemoryMap::new();
+    let (mut decode_cache, mut translate) = (true, false);
+    let mut dumps = Vec::new();
+    let pic = Pic::new();
+    let (mut pic_addr, mut on_lines) = (None, false);
+    // the framebuffer saves the first frames it shows to files named by this, with {} for the frame number
+    let frames = flags.iter().find_map(|f| f.strip_prefix("--frames=")).map(str::to_string);
+    let (mut screen, mut final_frame) = (None, None);
+    // keyboards type a script of keys if there's one, or whatever's typed in the terminal
+    let keys = flags.iter().find_map(|f| f.strip_prefix("--keys=")).map(|file| {
+        let script = std::fs::read_to_string(file).unwrap_or_else(|e| {
+            eprintln!("failed to read {}: {}", file, e);
+            std::process::exit(1)
+        });
+        keyboard::parse_script(&script).unwrap_or_else(|e| {
+            eprintln!("{}:{}", file, e);
+            std::process::exit(1)
+        })
+    });
+    // only one device can read stdin
+    let mut stdin_readers = 0;
+    for f in flags {
+        match f.as_str() {
+            "--no-decode-cache" => decode_cache = false,
+            "--translate" => translate = true,
+            f if f.starts_with("--pic=") => match utils::parse_addr(&f["--pic=".len()..]) {
+                Some(addr) => pic_addr = Some(addr),
+                None => {
+                    eprintln!("bad address in {}", f);
+                    std::process::exit(1)
+                }
+            }
+            f if f.starts_with("--frames=") || f.starts_with("--keys=") => (),
+            f if f.starts_with("--frame=") => final_frame = Some(f["--frame=".len()..].to_string()),
+            f if f.starts_with("--rom=") || f.starts_with("--rom-fault=") => {
+                let (flag, spec) = f.split_once('=').unwrap();
+                match parse_rom(spec, flag == "--rom-fault") {
+                    Ok((rom, ranges)) => mem.add_device(Box::new(rom), ranges),
+                    Err(e) => {
+                        eprintln!("bad rom {}: {}", f, e);
+                        std::process::exit(1)
+                    }
+                }
+            }
+            f if f.starts_with("--dump=") => match parse_dump(&f["--dump=".len()..]) {
+                Some(d) => dumps.push(d),
+                None => {
+                    eprintln!("bad dump {}, expected --dump=<start>-<end>:<file.hex or file.srec>", f);
+                    std::process::exit(1)
+                }
+            }
+            // anything else maps a device by name, as --<name>=<addr>[:<pic line>]
+            f => {
+                let Some((kind, spec)) = f[2..].split_once('=').and_then(|(name, spec)| Some((DeviceKind::named(name)?, spec))) else {
+                    eprintln!("unknown option {}", f);
+                    std::process::exit(1)
+                };
+                // disks also say which file they're of, as <file>@<addr>
+                let (file, spec) = match kind {
+                    DeviceKind::Disk => spec.split_once('@').map_or((None, ""), |(file, spec)| (Some(file), spec)),
+                    _ => (None, spec),
+                };
+                let Some((addr, line)) = parse_device(spec) else {
+                    let file = if kind == DeviceKind::Disk { "<file>@" } else { "" };
+                    eprintln!("bad device {}, expected {1}<addr> or {1}<addr>:<pic line>", f, file);
+                    std::process::exit(1)
+                };
+                let dev: Box<dyn Device> = match kind {
+                    // a console on stdin and stdout
+                    DeviceKind::Uart => {
+                        stdin_readers += 1;
+                        Box::new(Uart::new(UartInput::Stdin, UartOutput::Stdout))
+                    }
+                    DeviceKind::Timer => Box::new(Timer::new()),
+                    DeviceKind::Disk => {
+                        let file = file.unwrap_or_default();
+                        Box::new(Disk::open(file.as_ref()).unwrap_or_else(|e| {
+                            eprintln!("failed to open {}: {}", file, e);
+                            std::process::exit(1)
+                        }))
+                    }
+                    DeviceKind::Dma => Box::new(Dma::new()),
+                    DeviceKind::Framebuffer => {
+                        let fb = Framebuffer::new(frames.clone());
+                        screen = Some(fb.screen());
+                        Box::new(fb)
+                    }
+                    DeviceKind::Keyboard => {
+                        let input = match &keys {
+                            Some(keys) => KeyboardInput::Script(keys.clone()),
+                            None => {
+                                stdin_readers += 1;
+                                KeyboardInput::Terminal
+                            }
+                        };
+                        Box::new(Keyboard::new(input))
+                    }
+                };
+                let dev = match line {
+                    Some(line) => {
+                        on_lines = true;
+                        pic.connect(line, dev)
+                    }
+                    None => dev,
+                };
+                mem.add_device_at(dev, addr..addr + kind.size());
+            }
+        }
+    }
+    if (final_frame.is_some() || frames.is_some()) && screen.is_none() {
+        eprintln!("there's no framebuffer to save a frame of. map one with --framebuffer=<addr>");
+        std::process::exit(1)
+    }
+    if stdin_readers > 1 {
+        eprintln!("more than one device reads the terminal. give keyboards a script with --keys=<script>");
+        std::process::exit(1)
+    }
+    match pic_addr {
+        Some(addr) => mem.add_device_at(Box::new(pic), addr..addr + memory::pic::SIZE),
+        None if on_lines => {
+            eprintln!("devices are on lines of a pic, but there isn't one. map it with --pic=<addr>");
+            std::process::exit(1)
+        }
+        None => (),
+    }
+    add_ram(&mut mem);
+    let mut computer = Computer::new(mem);
+    computer.processor.set_decode_cache(decode_cache);
+    computer.processor.set_translation(translate);
+    // hex files go wherever their records say, and start at their start address or 0
+    let format = hex::Format::from_path(path.as_ref()).or(hex::Format::detect(&data));
+    let image = if let (Some(format), false) = (format, image::is_image(&data)) {
+        let text = String::from_utf8_lossy(&data);
+        let records = hex::Records::parse(&text, format).unwrap_or_else(|e| {
+            eprintln!("failed to load {}: {}", path, e);
+            std::process::exit(1)
+        });
+        if let Err(addr) = records.load(&mut computer.memory_map) {
+            eprintln!("failed to load {}: nothing writable is mapped at {:#x}", path, addr);
+            std::process::exit(1)
+        }
+        let start = records.start.unwrap_or(0);
+        let (co, pc) = image::split_addr(start).unwrap_or_else(|| {
+            eprintln!("failed to load {}: start {:#x} doesn't fit in a segment", path, start);
+            std::process::exit(1)
+        });
+        computer.processor.set_entry(co, pc as u32);
+        None
+    }
+    else if image::is_image(&data) {
+        match Image::from_bytes(&data) {
+            Ok(i) => Some(i),
+            Err(e) => {
+                eprintln!("failed to load {}: {}", path, e);
+                std::process::exit(1)
+            }
+        }
+    }
+    else {
+        None
+    };
+    match &image {
+        Some(i) => i.load(&mut computer.memory_map, &mut computer.processor),
+        None if format.is_some() => (),
+        // raw binaries are loaded at 0 and started from there
+        None => computer.load(&data, 0),
+    }
+    computer.run();
+    let saved = dumps.into_iter()
+        .try_for_each(|(range, file, format)| {
+            let text = hex::Records::from_memory(&mut computer.memory_map, range).to_string(format);
+            std::fs::write(&file, text).map_err(|e| format!("failed to write {}: {}", file, e))
+        })
+        .and_then(|_| match (final_frame, screen) {
+            (Some(file), Some(screen)) => screen.render().save(file.as_ref()).map_err(|e| format!("failed to write {}: {}", file, e)),
+            _ => Ok(()),
+        });
+    let (pc, cycles, state) = (computer.processor.flat_pc(), computer.cycles, computer.processor.state());
+    // devices are done with, so a keyboard gives the terminal back before anything's reported
+    drop(computer);
+    if let Err(e) = saved {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    }
+    let stopped = match state {
+        State::Halted => "halted",
+        _ => "stuck waiting for an interrupt that nothing can raise",
+    };
+    match image.and_then(|i| i.symbolize(pc)) {
+        Some(at) => println!("{} at {} after {} cycles", stopped, at, cycles),
+        None => println!("{} after {} cycles", stopped, cycles),
+    }
+}
+
+/// a rom from a file mapped at some addresses, as file@addr,start-end. a lone address maps it once,
+/// and a range repeats it to fill the range
+fn parse_rom(spec: &str, fault: bool) -> Result<(Rom, Vec<std::ops::Range<u32>>), String> {
+    let (file, addrs) = spec.split_once('@').ok_or("expected <file>@<addresses>")?;
+    let rom = Rom::from_file(file.as_ref(), fault).map_err(|e| format!("failed to read {}: {}", file, e))?;
+    let len = std::fs::metadata(file).map_err(|e| e.to_string())?.len() as u32;
+    let ranges = addrs.split(',')
+        .map(|a| {
+            let range = match a.split_once('-') {
+                Some((start, end)) => utils::parse_addr(start)?..utils::parse_addr(end)?,
+                None => utils::parse_addr(a).map(|start| start..start.wrapping_add(len))?,
+            };
+            Some(range)
+        })
+        .collect::<Option<_>>()
+        .ok_or(format!("bad address in {}", addrs))?;
+    Ok((rom, ranges))
+}
+
+/// where to map a device, as addr or addr:line to put it on a line of the pic
+fn parse_device(spec: &str) -> Option<(u32, Option<u8>)> {
+    match spec.split_once(':') {
+        Some((addr, line)) => {
+            let line = line.parse().ok().filter(|l| *l < memory::pic::LINES)?;
+            Some((utils::parse_addr(addr)?, Some(line)))
+        }
+        None => Some((utils::parse_addr(spec)?, None)),
+    }
+}
+
+/// a memory range to write to a hex file once halted, as start-end:file
+fn parse_dump(dump: &str) -> Option<(std::ops::Range<u32>, String, hex::Format)> {
+    let (range, file) = dump.split_once(':')?;
+    let (start, end) = range.split_once('-')?;
+    let format = hex::Format::from_path(file.as_ref())?;
+    Some((utils::parse_addr(start)?..utils::parse_addr(end)?, file.to_string(), format))
+}
+
+/// assembles a source file into an image, or with -c into an object to link
+fn assemble(args: &[String]) {
+    let (object, args) = match args {
+        [c, rest @ ..] if c == "-c" => (true, rest),
+        _ => (false, args),
+    };
+    let [source, out] = args else {
+        eprintln!("usage: asm [-c] <source> <image or object>");
+        std::process::exit(1)
+    };
+    let text = match std::fs::read_to_string(source) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", source, e);
+            std::process::exit(1)
+        }
+    };
+    let bytes = match object {
+        true => asm::assemble_object(&text).map(|o| o.to_bytes().map_err(|e| e.to_string())),
+        false => asm::assemble(&text).map(|a| Image::from_assembly(&a).and_then(|i| i.to_bytes()).map_err(|e| e.to_string())),
+    };
+    let bytes = match bytes {
+        Ok(Ok(b)) => b,
+        Ok(Err(e)) => {
+            eprintln!("{}: {}", source, e);
+            std::process::exit(1)
+        }
+        Err(e) => {
+            eprintln!("{}:{}: {}", source, e.line, e.msg);
+            std::process::exit(1)
+        }
+    };
+    if let Err(e) = std::fs::write(out, bytes) {
+        eprintln!("failed to write {}: {}", out, e);
+        std::process::exit(1)
+    }
+}
+
+/// links objects from asm -c into an image. it's the `ld` subcommand, rather than a binary of its own
+fn ld(args: &[String]) {
+    let usage = || -> ! {
+        eprintln!("usage: ld [-T <script>] [--place <section>=<addr>] [--entry <label>] -o <image> <object>...");
+        std::process::exit(1)
+    };
+    let fail = |msg: String| -> ! {
+        eprintln!("{}", msg);
+        std::process::exit(1)
+    };
+    let mut layout = asm::Layout::default();
+    let mut out = None;
+    let mut inputs = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => out = Some(args.next().unwrap_or_else(|| usage())),
+            "-T" => {
+                let path = args.next().unwrap_or_else(|| usage());
+                let script = std::fs::read_to_string(path).unwrap_or_else(|e| fail(format!("failed to read {}: {}", path, e)));
+                let script = asm::Layout::parse(&script).unwrap_or_else(|e| fail(format!("{}:{}", path, e)));
+                for (name, addr) in script.placements {
+                    layout.place(&name, addr);
+                }
+                layout.entry = script.entry.or(layout.entry);
+            }
+            "--place" => {
+                let place = args.next().unwrap_or_else(|| usage());
+                let (name, addr) = place.split_once('=').unwrap_or_else(|| usage());
+                let addr = utils::parse_addr(addr).unwrap_or_else(|| fail(format!("bad address {}", addr)));
+                layout.place(name, addr);
+            }
+            "--entry" => layout.entry = Some(args.next().unwrap_or_else(|| usage()).clone()),
+            a if a.starts_with('-') => usage(),
+            path => {
+                let data = std::fs::read(path).unwrap_or_else(|e| fail(format!("failed to read {}: {}", path, e)));
+                let object = asm::Object::from_bytes(&data).unwrap_or_else(|e| fail(format!("failed to load {}: {}", path, e)));
+                inputs.push((path.to_string(), object));
+            }
+        }
+    }
+    let Some(out) = out else { usage() };
+    if inputs.is_empty() {
+        usage()
+    }
+    let image = asm::link(&inputs, &layout).unwrap_or_else(|e| fail(e));
+    let bytes = image.to_bytes().unwrap_or_else(|e| fail(format!("failed to write {}: {}", out, e)));
+    if let Err(e) = std::fs::write(out, bytes) {
+        fail(format!("failed to write {}: {}", out, e))
+    }
+}
+
+/// assembles and runs guest programs, checking them against their expectations
+fn test(paths: &[String]) {
+    let paths: Vec<std::path::PathBuf> = paths.iter().map(Into::into).collect();
+    let programs = match harness::programs(&paths) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("failed to find programs: {}", e);
+            std::process::exit(1)
+        }
+    };
+    let (report, ok) = harness::run_all(&programs);
+    print!("{}", report);
+    if !ok {
+        std::process::exit(1)
+    }
+}
+
+/// 16MiB of ram, in 64KiB banks that are only allocated when they're written to
+fn add_ram(mem: &mut MemoryMap) {
+    for bank in 0..0x100 {
+        mem.add_device_at(Box::new(RustMemory::new()), bank << 16..(bank + 1) << 16);
+    }
 }
 
 struct Computer {
     memory_map: MemoryMap,
-    processor: Processor
+    processor: Processor,
+    /// total cycles run so far
+    cycles: u64,
 }
 impl Computer {
-    
+    fn new(memory_map: MemoryMap) -> Computer {
+        Computer {
+            memory_map,
+            processor: Processor::default(),
+            cycles: 0,
+        }
+    }
+    fn load(&mut self, data: &[u8], addr: u32) {
+        for (i, b) in data.iter().enumerate() {
+            self.memory_map.write(*b, addr.wrapping_add(i as u32));
+        }
+    }
+
+    /// runs until the processor halts, or waits for an interrupt nothing can raise
+    fn run(&mut self) {
+        while self.processor.state() != State::Halted {
+            if !self.clock() {
+                return
+            }
+        }
+    }
+    /// runs one instruction, then lets devices catch up with the cycles it took.  
+    /// false if the processor is waiting for an interrupt nothing can raise
+    fn clock(&mut self) -> bool {
+        match self.processor.state() {
+            State::Running => {
+                let cycles = self.processor.clock(&mut self.memory_map);
+                self.tick(cycles);
+                true
+            }
+            State::Waiting => self.fast_forward(),
+            State::Halted => true,
+        }
+    }
+    /// clocks devices by some number of cycles, and passes on any interrupt they raise
+    fn tick(&mut self, cycles: u32) -> bool {
+        // bus masters' accesses last time held up the cpu, so they pass before anything else happens
+        let cycles = cycles + self.memory_map.take_stolen(u32::MAX - cycles);
+        self.cycles += cycles as u64;
+        let msg = self.memory_map.clock(cycles);
+        let interrupted = !matches!(msg, DevMsg::None);
+        self.processor.signal(msg);
+        interrupted
+    }
+    /// clocks devices a cycle at a time without running the processor, until one of them interrupts.  
+    /// false straight away if none of them can
+    fn fast_forward(&mut self) -> bool {
+        if !self.memory_map.can_interrupt() {
+            return false
+        }
+        loop {
+            for _ in 0..WFI_BATCH {
+                if self.tick(1) {
+                    return true
+                }
+            }
+            std::thread::sleep(WFI_SLEEP);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+    use super::*;
+    use memory::Device;
+    use test::Bencher;
+
+    /// raises an irq every n clocks
+    struct Ticker {
+        n: u32,
+        count: u32,
+    }
+    impl Device for Ticker {
+        fn write(&mut self, _val: u8, _offset: u32, _range: u32) {}
+        fn write16(&mut self, _val: [u8; 2], _offset: u32, _range: u32) {}
+        fn clock(&mut self, cycles: u32) -> DevMsg {
+            self.count += cycles;
+            if self.count.is_multiple_of(self.n) { DevMsg::Irq } else { DevMsg::None }
+        }
+    }
+
+    #[test]
+    fn wfi_and_hlt() {
+        let mut mem = MemoryMap::new();
+        add_ram(&mut mem);
+        mem.add_device(Box::new(Ticker { n: 100_000, count: 0 }), vec![]);
+        let mut computer = Computer::new(mem);
+        let program = [
+            0x80, 0x71, 0x00, 0x01, 0x28, // mov word 0x100, %idtp
+            0x80, 0x71, 0x10, 0x00, 0x2a, // mov word 16, %idtl
+            0x80, 0x71, 0x00, 0x04, 0x10, // mov word 0x400, %sp
+            0x80, 0x71, 0x00, 0x02, 0x2e, // mov word 0x200, %flags
+            0xf5, // wfi
+            0xf4, // hlt
+        ];
+        computer.load(&program, 0);
+        // the irq vector points at the hlt
+        computer.load(&[0x15, 0x00, 0x00, 0x00], 0x100 + 3 * 4);
+        computer.run();
+        assert_eq!(computer.processor.state(), State::Halted);
+        // having taken the irq
+        assert_eq!(computer.processor.inspect(0x10), Some(0x3fa));
+        // the ticker only fires once 100k cycles have passed
+        assert!(computer.cycles >= 100_000);
+    }
+
+    #[test]
+    fn wfi_with_nothing_to_wake_it() {
+        let mut mem = MemoryMap::new();
+        add_ram(&mut mem);
+        let mut computer = Computer::new(mem);
+        computer.load(&[0xf5, 0xf4], 0); // wfi, hlt
+        computer.run();
+        assert_eq!((computer.processor.state(), computer.processor.flat_pc()), (State::Waiting, 1));
+    }
+
+    /// counts %a down from 10000 to 0, then halts
+    fn countdown(b: &mut Bencher, cached: bool, translated: bool) {
+        let program = [
+            0x80, 0x71, 0x10, 0x27, 0x00, // mov word 10000, %a
+            0xc6, 0x71, 0x01, 0x00, // sub word 1
+            0x8a, 0x71, 0x05, 0x00, // jnz word 5
+            0xf4, // hlt
+        ];
+        b.iter(|| {
+            let mut mem = MemoryMap::new();
+            mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+            let mut computer = Computer::new(mem);
+            computer.processor.set_decode_cache(cached);
+            computer.processor.set_translation(translated);
+            computer.load(&program, 0);
+            computer.run();
+            computer.cycles
+        })
+    }
+    #[bench]
+    fn countdown_cached(b: &mut Bencher) {
+        countdown(b, true, false)
+    }
+    #[bench]
+    fn countdown_uncached(b: &mut Bencher) {
+        countdown(b, false, false)
+    }
+    #[bench]
+    fn countdown_translated(b: &mut Bencher) {
+        countdown(b, true, true)
+    }
 }
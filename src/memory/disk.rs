@@ -0,0 +1,223 @@
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+use super::{DevMsg, Device};
+
+/// the 4 byte sector number the next command is for
+pub const SECTOR: u32 = 0;
+/// writing a command below starts it, reading gives the last one
+pub const COMMAND: u32 = 4;
+/// made of the status bits below. writing DONE or ERROR clears it
+pub const STATUS: u32 = 5;
+/// made of the control bits below
+pub const CONTROL: u32 = 6;
+/// the read only 4 byte number of sectors on the disk
+pub const SECTORS: u32 = 8;
+/// the sector commands read into and write from, which can be read at any time
+pub const BUFFER: u32 = 0x200;
+/// how much address space the registers and buffer take
+pub const SIZE: u32 = BUFFER + SECTOR_SIZE;
+pub const SECTOR_SIZE: u32 = 0x200;
+
+/// command: reads the sector into the buffer
+pub const READ: u8 = 0x01;
+/// command: writes the buffer to the sector
+pub const WRITE: u8 = 0x02;
+/// status: a command is running. writes to anything but STATUS while busy are ignored, including commands
+pub const BUSY: u8 = 0x01;
+/// status: the last command finished
+pub const DONE: u8 = 0x02;
+/// status: the last command failed, because the sector isn't on the disk or the host couldn't access it
+pub const ERROR: u8 = 0x04;
+/// control: irq when a command finishes
+pub const IRQ: u8 = 0x01;
+
+/// how many cycles a command takes
+const LATENCY: u32 = 100;
+
+/// what's on the disk
+pub enum DiskImage {
+    /// read and written in place, so changes last after the run. a partial sector at the end is ignored
+    File(File),
+    Buffer(Rc<RefCell<Vec<u8>>>),
+}
+
+/// a disk controller over sectors of an image, with registers from SECTOR to SECTORS and a sector buffer at BUFFER
+pub struct Disk {
+    image: DiskImage,
+    sectors: u32,
+    sector: u32,
+    command: u8,
+    status: u8,
+    control: u8,
+    buffer: [u8; SECTOR_SIZE as usize],
+    /// cycles until the running command finishes
+    remaining: u32,
+}
+impl Disk {
+    pub fn new(image: DiskImage) -> std::io::Result<Disk> {
+        let len = match &image {
+            DiskImage::File(f) => f.metadata()?.len(),
+            DiskImage::Buffer(data) => data.borrow().len() as u64,
+        };
+        let sectors = (len / SECTOR_SIZE as u64).min(u32::MAX as u64) as u32;
+        Ok(Disk { image, sectors, sector: 0, command: 0, status: 0, control: 0, buffer: [0; SECTOR_SIZE as usize], remaining: 0 })
+    }
+    /// opens an image file for reading and writing
+    pub fn open(path: &Path) -> std::io::Result<Disk> {
+        Disk::new(DiskImage::File(File::options().read(true).write(true).open(path)?))
+    }
+    /// does the running command, returning whether it worked
+    fn run(&mut self) -> bool {
+        if self.sector >= self.sectors {
+            return false
+        }
+        let start = self.sector as u64 * SECTOR_SIZE as u64;
+        match (&mut self.image, self.command) {
+            (DiskImage::File(f), READ) => f.seek(SeekFrom::Start(start)).and_then(|_| f.read_exact(&mut self.buffer)).is_ok(),
+            (DiskImage::File(f), WRITE) => f.seek(SeekFrom::Start(start)).and_then(|_| f.write_all(&self.buffer)).is_ok(),
+            (DiskImage::Buffer(data), READ) => {
+                let start = start as usize;
+                self.buffer.copy_from_slice(&data.borrow()[start..start + SECTOR_SIZE as usize]);
+                true
+            }
+            (DiskImage::Buffer(data), WRITE) => {
+                let start = start as usize;
+                data.borrow_mut()[start..start + SECTOR_SIZE as usize].copy_from_slice(&self.buffer);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Device for Disk {
+    fn write(&mut self, val: u8, offset: u32, _range: u32) {
+        if offset == STATUS {
+            self.status &= !(val & (DONE | ERROR));
+            return
+        }
+        if self.status & BUSY != 0 {
+            return
+        }
+        match offset {
+            o if (SECTOR..SECTOR + 4).contains(&o) => {
+                let mut bytes = self.sector.to_le_bytes();
+                bytes[(o - SECTOR) as usize] = val;
+                self.sector = u32::from_le_bytes(bytes);
+            }
+            COMMAND => {
+                self.command = val;
+                self.status = BUSY;
+                self.remaining = LATENCY;
+            }
+            CONTROL => self.control = val & IRQ,
+            o if o >= BUFFER => self.buffer[(o - BUFFER) as usize % SECTOR_SIZE as usize] = val,
+            _ => (),
+        }
+    }
+    fn write16(&mut self, val: [u8; 2], offset: u32, range: u32) {
+        self.write(val[0], offset, range);
+        self.write(val[1], offset + 1, range);
+    }
+    fn read(&mut self, offset: u32, _range: u32) -> u8 {
+        match offset {
+            o if (SECTOR..SECTOR + 4).contains(&o) => self.sector.to_le_bytes()[(o - SECTOR) as usize],
+            COMMAND => self.command,
+            STATUS => self.status,
+            CONTROL => self.control,
+            o if (SECTORS..SECTORS + 4).contains(&o) => self.sectors.to_le_bytes()[(o - SECTORS) as usize],
+            o if o >= BUFFER => self.buffer[(o - BUFFER) as usize % SECTOR_SIZE as usize],
+            _ => 0,
+        }
+    }
+    fn read16(&mut self, offset: u32, range: u32) -> [u8; 2] {
+        [self.read(offset, range), self.read(offset + 1, range)]
+    }
+    fn clock(&mut self, cycles: u32) -> DevMsg {
+        if self.status & BUSY == 0 {
+            return DevMsg::None
+        }
+        if cycles < self.remaining {
+            self.remaining -= cycles;
+            return DevMsg::None
+        }
+        self.status = if self.run() { DONE } else { DONE | ERROR };
+        if self.control & IRQ != 0 { DevMsg::Irq } else { DevMsg::None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finish(disk: &mut Disk) -> DevMsg {
+        assert!(matches!(disk.clock(LATENCY - 1), DevMsg::None));
+        assert_eq!(disk.read(STATUS, 0), BUSY);
+        disk.clock(1)
+    }
+
+    #[test]
+    fn read_and_write() {
+        let data = Rc::new(RefCell::new((0..SECTOR_SIZE * 3).map(|i| (i / SECTOR_SIZE) as u8 + 1).collect()));
+        let mut disk = Disk::new(DiskImage::Buffer(data.clone())).unwrap();
+        assert_eq!(disk.read16(SECTORS, 0), [3, 0]);
+
+        disk.write16([1, 0], SECTOR, 0);
+        disk.write(READ, COMMAND, 0);
+        assert!(matches!(finish(&mut disk), DevMsg::None));
+        assert_eq!(disk.read(STATUS, 0), DONE);
+        assert_eq!(disk.read16(BUFFER + 0x1fe, 0), [2, 2]);
+
+        disk.write(DONE, STATUS, 0);
+        disk.write(IRQ, CONTROL, 0);
+        disk.write(0xaa, BUFFER, 0);
+        disk.write(2, SECTOR, 0);
+        disk.write(WRITE, COMMAND, 0);
+        // commands started while busy are ignored, and so is the sector and buffer they use
+        disk.write(READ, COMMAND, 0);
+        disk.write(0, SECTOR, 0);
+        disk.write(0xbb, BUFFER, 0);
+        assert!(matches!(finish(&mut disk), DevMsg::Irq));
+        assert_eq!((disk.read(STATUS, 0), disk.read(COMMAND, 0)), (DONE, WRITE));
+        assert_eq!(data.borrow()[SECTOR_SIZE as usize * 2..][..2], [0xaa, 2]);
+        assert!(matches!(disk.clock(LATENCY), DevMsg::None));
+
+        // changing the sector during a read still reads the original one
+        disk.write(DONE, STATUS, 0);
+        disk.write(1, SECTOR, 0);
+        disk.write(READ, COMMAND, 0);
+        disk.write(2, SECTOR, 0);
+        assert!(matches!(finish(&mut disk), DevMsg::Irq));
+        assert_eq!((disk.read(SECTOR, 0), disk.read16(BUFFER, 0)), (1, [2, 2]));
+
+        // sectors past the end fail
+        disk.write(3, SECTOR, 0);
+        disk.write(READ, COMMAND, 0);
+        assert!(matches!(finish(&mut disk), DevMsg::Irq));
+        assert_eq!(disk.read(STATUS, 0), DONE | ERROR);
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("bcpu-disk-{}.img", std::process::id()));
+        std::fs::write(&path, vec![7; SECTOR_SIZE as usize * 2 + 5]).unwrap();
+        let mut disk = Disk::open(&path).unwrap();
+        assert_eq!(disk.read(SECTORS, 0), 2);
+        disk.write(1, SECTOR, 0);
+        disk.write(READ, COMMAND, 0);
+        finish(&mut disk);
+        assert_eq!(disk.read(BUFFER + 3, 0), 7);
+        disk.write(9, BUFFER + 3, 0);
+        disk.write(WRITE, COMMAND, 0);
+        finish(&mut disk);
+        assert_eq!(disk.read(STATUS, 0), DONE);
+        drop(disk);
+        let data = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(data[SECTOR_SIZE as usize + 2..][..2], [7, 9]);
+        assert_eq!(data.len(), SECTOR_SIZE as usize * 2 + 5);
+    }
+}
@@ -0,0 +1,225 @@
+use super::{DevMsg, Device, MemoryMap};
+
+/// the 4 byte address transfers read from. it moves on as they go, unless FIXED_SOURCE is set
+pub const SOURCE: u32 = 0;
+/// the 4 byte address transfers write to. it moves on as they go, unless FIXED_DEST is set
+pub const DEST: u32 = 4;
+/// the 4 byte number of units left to transfer, bytes or words, counting down as they go
+pub const COUNT: u32 = 8;
+/// made of the control bits below
+pub const CONTROL: u32 = 12;
+/// made of the status bits below. writing DONE or FAULT clears it
+pub const STATUS: u32 = 13;
+/// the most units to move each time it's clocked. it moves fewer if their accesses would take more
+/// cycles than it was clocked for, and 0 moves as many as the cycles allow
+pub const BURST: u32 = 14;
+/// how much address space the registers take
+pub const SIZE: u32 = 16;
+
+/// control: starts a transfer from what's in the registers. it's cleared when the transfer ends
+pub const START: u8 = 0x01;
+/// control: irq when a transfer ends
+pub const IRQ: u8 = 0x02;
+/// control: move words rather than bytes. unaligned words take two accesses, like the cpu's
+pub const WORDS: u8 = 0x04;
+/// control: keep reading the same address, eg. a device's data register
+pub const FIXED_SOURCE: u8 = 0x08;
+/// control: keep writing the same address
+pub const FIXED_DEST: u8 = 0x10;
+/// status: a transfer is going. writes to the registers other than STATUS are ignored until it ends
+pub const BUSY: u8 = 0x01;
+/// status: the last transfer ended
+pub const DONE: u8 = 0x02;
+/// status: the last transfer stopped at a write that bus faulted, with the registers saying where
+pub const FAULT: u8 = 0x04;
+
+/// a dma controller, copying between addresses on the bus while the cpu runs.
+/// the cycles its accesses take are stolen from the cpu
+#[derive(Default)]
+pub struct Dma {
+    source: u32,
+    dest: u32,
+    count: u32,
+    control: u8,
+    status: u8,
+    burst: u8,
+}
+impl Dma {
+    pub fn new() -> Dma {
+        Dma::default()
+    }
+    /// moves one unit, returning whether its write worked
+    fn transfer(&mut self, bus: &mut MemoryMap) -> bool {
+        let step = if self.control & WORDS != 0 { 2 } else { 1 };
+        let written = if step == 2 {
+            let val = bus.read16(self.source);
+            bus.write16(val, self.dest)
+        }
+        else {
+            let val = bus.read(self.source);
+            bus.write(val, self.dest)
+        };
+        if !written {
+            return false
+        }
+        if self.control & FIXED_SOURCE == 0 {
+            self.source = self.source.wrapping_add(step);
+        }
+        if self.control & FIXED_DEST == 0 {
+            self.dest = self.dest.wrapping_add(step);
+        }
+        self.count -= 1;
+        true
+    }
+}
+
+impl Device for Dma {
+    fn write(&mut self, val: u8, offset: u32, _range: u32) {
+        let set_byte = |reg: u32| {
+            let mut bytes = reg.to_le_bytes();
+            bytes[offset as usize & 3] = val;
+            u32::from_le_bytes(bytes)
+        };
+        if offset == STATUS {
+            self.status &= !(val & (DONE | FAULT));
+            return
+        }
+        if self.status & BUSY != 0 {
+            return
+        }
+        match offset {
+            o if o & !3 == SOURCE => self.source = set_byte(self.source),
+            o if o & !3 == DEST => self.dest = set_byte(self.dest),
+            o if o & !3 == COUNT => self.count = set_byte(self.count),
+            CONTROL => {
+                self.control = val & (START | IRQ | WORDS | FIXED_SOURCE | FIXED_DEST);
+                if val & START != 0 {
+                    self.status = BUSY;
+                }
+            }
+            BURST => self.burst = val,
+            _ => (),
+        }
+    }
+    fn write16(&mut self, val: [u8; 2], offset: u32, range: u32) {
+        self.write(val[0], offset, range);
+        self.write(val[1], offset + 1, range);
+    }
+    fn read(&mut self, offset: u32, _range: u32) -> u8 {
+        let byte = |reg: u32| reg.to_le_bytes()[offset as usize & 3];
+        match offset {
+            o if o & !3 == SOURCE => byte(self.source),
+            o if o & !3 == DEST => byte(self.dest),
+            o if o & !3 == COUNT => byte(self.count),
+            CONTROL => self.control,
+            STATUS => self.status,
+            BURST => self.burst,
+            _ => 0,
+        }
+    }
+    fn read16(&mut self, offset: u32, range: u32) -> [u8; 2] {
+        [self.read(offset, range), self.read(offset + 1, range)]
+    }
+    fn bus_master(&self) -> bool {
+        true
+    }
+    /// moves units until the burst is done or their accesses have used up the cycles, always moving at least one
+    fn clock_master(&mut self, cycles: u32, bus: &mut MemoryMap) -> DevMsg {
+        if self.status & BUSY == 0 {
+            return DevMsg::None
+        }
+        let mut units = if self.burst == 0 { self.count } else { self.count.min(self.burst as u32) };
+        let start = bus.cycles();
+        let mut faulted = false;
+        while units > 0 && !faulted && bus.cycles() - start < cycles as u64 {
+            faulted = !self.transfer(bus);
+            units -= 1;
+        }
+        if self.count > 0 && !faulted {
+            return DevMsg::None
+        }
+        self.status = if faulted { DONE | FAULT } else { DONE };
+        self.control &= !START;
+        if self.control & IRQ != 0 { DevMsg::Irq } else { DevMsg::None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{Rom, RustMemory};
+
+    const DMA: u32 = 0xff00;
+
+    fn memory() -> MemoryMap {
+        let mut mem = MemoryMap::new();
+        mem.add_device_at(Box::new(Dma::new()), DMA..DMA + SIZE);
+        mem.add_device_at(Box::new(Rom::new(vec![0; 0x100], true)), 0x8000..0x8100);
+        mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+        mem
+    }
+
+    fn program(mem: &mut MemoryMap, source: u32, dest: u32, count: u32, burst: u8, control: u8) {
+        for (reg, val) in [(SOURCE, source), (DEST, dest), (COUNT, count)] {
+            for (i, b) in val.to_le_bytes().into_iter().enumerate() {
+                mem.write(b, DMA + reg + i as u32);
+            }
+        }
+        mem.write(burst, DMA + BURST);
+        mem.write(control, DMA + CONTROL);
+    }
+
+    #[test]
+    fn bursts() {
+        let mut mem = memory();
+        for i in 0..10 {
+            mem.write(i + 1, 0x100 + i as u32);
+        }
+        program(&mut mem, 0x100, 0x201, 5, 2, START | IRQ | WORDS);
+        assert_eq!(mem.read(DMA + STATUS), BUSY);
+        // writes are ignored while it's busy
+        mem.write(0, DMA + COUNT);
+        mem.take_stolen(u32::MAX);
+
+        assert!(matches!(mem.clock(100), DevMsg::None));
+        // an aligned word read, and an unaligned word write, for each word
+        assert_eq!(mem.take_stolen(u32::MAX), 6);
+        assert_eq!(mem.read(DMA + COUNT), 3);
+        assert!(matches!(mem.clock(100), DevMsg::None));
+        assert!(matches!(mem.clock(100), DevMsg::Irq));
+        assert_eq!(mem.take_stolen(u32::MAX), 9);
+        assert_eq!((mem.read(DMA + STATUS), mem.read(DMA + CONTROL)), (DONE, IRQ | WORDS));
+        assert_eq!((0..11).map(|i| mem.read(0x200 + i)).collect::<Vec<_>>(), [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert!(matches!(mem.clock(100), DevMsg::None));
+        assert_eq!(mem.take_stolen(u32::MAX), 0);
+
+        // a fixed source fills, with no burst size, as fast as the cycles allow
+        mem.write(DONE, DMA + STATUS);
+        program(&mut mem, 0x105, 0x300, 4, 0, START | FIXED_SOURCE);
+        mem.take_stolen(u32::MAX);
+        assert!(matches!(mem.clock(1), DevMsg::None));
+        assert_eq!((mem.read(DMA + COUNT), mem.take_stolen(u32::MAX)), (3, 2));
+        assert!(matches!(mem.clock(3), DevMsg::None));
+        assert_eq!((mem.read(DMA + COUNT), mem.take_stolen(u32::MAX)), (1, 4));
+        assert!(matches!(mem.clock(100), DevMsg::None));
+        assert_eq!(mem.read(DMA + STATUS), DONE);
+        assert_eq!(mem.read16(0x300), [6, 6]);
+        assert_eq!(mem.read16(0x302), [6, 6]);
+    }
+
+    #[test]
+    fn faults() {
+        let mut mem = memory();
+        program(&mut mem, 0x100, 0x80fe, 4, 0, START);
+        mem.clock(1);
+        assert_eq!(mem.read(DMA + STATUS), DONE | FAULT);
+        assert_eq!(mem.read(DMA + SOURCE), 0);
+        assert_eq!(mem.read16(DMA + DEST), [0xfe, 0x80]);
+        assert_eq!(mem.read(DMA + COUNT), 4);
+        // its own registers aren't on the bus while it has it
+        mem.write(DONE | FAULT, DMA + STATUS);
+        program(&mut mem, DMA + CONTROL, 0x100, 1, 0, START);
+        mem.clock(1);
+        assert_eq!(mem.read(0x100), 0);
+    }
+}
@@ -0,0 +1,327 @@
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+use super::{font, DevMsg, Device};
+
+/// which of the modes below it's showing
+pub const MODE: u32 = 0;
+/// made of the control bits below
+pub const CONTROL: u32 = 1;
+/// made of the status bits below. writing a bit clears it
+pub const STATUS: u32 = 2;
+/// the read only word counting frames shown, wrapping around
+pub const FRAME: u32 = 4;
+/// 256 colours of red, green and blue bytes. the first 16 start as the cga colours, and the rest as black
+pub const PALETTE: u32 = 0x100;
+/// what's shown, as the mode says
+pub const VRAM: u32 = 0x400;
+/// how much address space the registers, palette and vram take
+pub const SIZE: u32 = VRAM + 0x4000;
+
+/// mode: TEXT_COLUMNS by TEXT_ROWS characters, each a character byte then an attribute byte,
+/// with the foreground colour in the low nibble and the background in the high one
+pub const TEXT: u8 = 0;
+/// mode: BITMAP_WIDTH by BITMAP_HEIGHT pixels, each a byte of palette index, shown at double size
+pub const BITMAP: u8 = 1;
+/// control: irq at each vblank
+pub const VBLANK_IRQ: u8 = 0x01;
+/// status: a vblank happened
+pub const VBLANK: u8 = 0x01;
+
+pub const TEXT_COLUMNS: u32 = 40;
+pub const TEXT_ROWS: u32 = 25;
+pub const BITMAP_WIDTH: u32 = 160;
+pub const BITMAP_HEIGHT: u32 = 100;
+/// the size frames are rendered at, in both modes
+pub const WIDTH: u32 = TEXT_COLUMNS * 8;
+pub const HEIGHT: u32 = TEXT_ROWS * 8;
+// bitmaps fill the same screen as text, at double size
+const _: () = assert!(BITMAP_WIDTH * 2 == WIDTH && BITMAP_HEIGHT * 2 == HEIGHT);
+/// how many cycles there are between vblanks
+pub const FRAME_CYCLES: u32 = 20_000;
+/// how many frames are saved to files at most, so a long run can't fill the disk
+pub const SAVED_FRAMES: u32 = 10_000;
+
+const CGA: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00], [0x00, 0x00, 0xaa], [0x00, 0xaa, 0x00], [0x00, 0xaa, 0xaa],
+    [0xaa, 0x00, 0x00], [0xaa, 0x00, 0xaa], [0xaa, 0x55, 0x00], [0xaa, 0xaa, 0xaa],
+    [0x55, 0x55, 0x55], [0x55, 0x55, 0xff], [0x55, 0xff, 0x55], [0x55, 0xff, 0xff],
+    [0xff, 0x55, 0x55], [0xff, 0x55, 0xff], [0xff, 0xff, 0x55], [0xff, 0xff, 0xff],
+];
+
+/// what's on the screen, shared between the device and whatever renders it
+struct Video {
+    mode: u8,
+    palette: [[u8; 3]; 256],
+    vram: Vec<u8>,
+}
+
+/// a rendered frame, as rows of rgb pixels from the top left
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<[u8; 3]>,
+}
+impl Frame {
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 3] {
+        self.pixels[(y * self.width + x) as usize]
+    }
+    /// a binary ppm
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut out = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        out.extend(self.pixels.iter().flatten());
+        out
+    }
+    /// an uncompressed png, as there's nothing to deflate with
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut raw = Vec::new();
+        for row in self.pixels.chunks(self.width as usize) {
+            raw.push(0); // no filter
+            raw.extend(row.iter().flatten());
+        }
+        // a zlib stream of stored deflate blocks
+        let mut zlib = vec![0x78, 0x01];
+        let blocks: Vec<&[u8]> = raw.chunks(0xffff).collect();
+        for (i, block) in blocks.iter().enumerate() {
+            zlib.push((i + 1 == blocks.len()) as u8);
+            zlib.extend((block.len() as u16).to_le_bytes());
+            zlib.extend((!(block.len() as u16)).to_le_bytes());
+            zlib.extend(*block);
+        }
+        zlib.extend(adler32(&raw).to_be_bytes());
+
+        let mut header = Vec::new();
+        header.extend(self.width.to_be_bytes());
+        header.extend(self.height.to_be_bytes());
+        header.extend([8, 2, 0, 0, 0]); // 8 bit rgb, not interlaced
+        let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, data) in [(b"IHDR", header), (b"IDAT", zlib), (b"IEND", Vec::new())] {
+            out.extend((data.len() as u32).to_be_bytes());
+            let start = out.len();
+            out.extend(kind);
+            out.extend(&data);
+            let crc = crc32(&out[start..]);
+            out.extend(crc.to_be_bytes());
+        }
+        out
+    }
+    /// writes a png or ppm, going by the extension
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("png") => std::fs::write(path, self.to_png()),
+            _ => std::fs::write(path, self.to_ppm()),
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for d in data {
+        a = (a + *d as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+/// renders what a framebuffer is showing, after it's been mapped
+#[derive(Clone)]
+pub struct Screen(Rc<RefCell<Video>>);
+impl Screen {
+    pub fn render(&self) -> Frame {
+        let video = self.0.borrow();
+        let mut pixels = vec![[0; 3]; (WIDTH * HEIGHT) as usize];
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let colour = match video.mode {
+                    TEXT => {
+                        let cell = ((y / 8 * TEXT_COLUMNS + x / 8) * 2) as usize;
+                        let (c, attr) = (video.vram[cell], video.vram[cell + 1]);
+                        let lit = font::glyph(c)[(y % 8) as usize] & 1 << (x % 8) != 0;
+                        if lit { attr & 0x0f } else { attr >> 4 }
+                    }
+                    BITMAP => video.vram[(y / 2 * BITMAP_WIDTH + x / 2) as usize],
+                    // modes that don't exist show nothing
+                    _ => 0,
+                };
+                pixels[(y * WIDTH + x) as usize] = video.palette[colour as usize];
+            }
+        }
+        Frame { width: WIDTH, height: HEIGHT, pixels }
+    }
+}
+
+/// a display with text and bitmap modes, showing a frame every FRAME_CYCLES.
+/// the first SAVED_FRAMES frames can be saved as they're shown, to files named by a pattern with `{}` for the frame number
+pub struct Framebuffer {
+    video: Rc<RefCell<Video>>,
+    control: u8,
+    status: u8,
+    frame: u16,
+    cycles: u64,
+    pattern: Option<String>,
+    /// how many frames have been saved, which names them rather than the wrapping frame count
+    saved: u32,
+}
+impl Framebuffer {
+    pub fn new(pattern: Option<String>) -> Framebuffer {
+        let mut palette = [[0; 3]; 256];
+        palette[..16].copy_from_slice(&CGA);
+        let video = Video { mode: TEXT, palette, vram: vec![0; (SIZE - VRAM) as usize] };
+        Framebuffer { video: Rc::new(RefCell::new(video)), control: 0, status: 0, frame: 0, cycles: 0, pattern, saved: 0 }
+    }
+    pub fn screen(&self) -> Screen {
+        Screen(self.video.clone())
+    }
+    fn vblank(&mut self) {
+        if let Some(pattern) = self.pattern.as_ref().filter(|_| self.saved < SAVED_FRAMES) {
+            let path = pattern.replace("{}", &format!("{:05}", self.saved));
+            // a frame that can't be saved isn't the guest's problem, so it carries on
+            if let Err(e) = self.screen().render().save(path.as_ref()) {
+                eprintln!("failed to write {}: {}", path, e);
+            }
+            self.saved += 1;
+            if self.saved == SAVED_FRAMES {
+                eprintln!("saved {} frames, so no more will be", SAVED_FRAMES);
+            }
+        }
+        self.frame = self.frame.wrapping_add(1);
+        self.status |= VBLANK;
+    }
+}
+
+impl Device for Framebuffer {
+    fn write(&mut self, val: u8, offset: u32, _range: u32) {
+        let mut video = self.video.borrow_mut();
+        match offset {
+            MODE => video.mode = val,
+            CONTROL => self.control = val & VBLANK_IRQ,
+            STATUS => self.status &= !val,
+            o if (PALETTE..VRAM).contains(&o) => {
+                let i = (o - PALETTE) as usize;
+                if let Some(colour) = video.palette.get_mut(i / 3) {
+                    colour[i % 3] = val;
+                }
+            }
+            o if o >= VRAM => video.vram[(o - VRAM) as usize] = val,
+            _ => (),
+        }
+    }
+    fn write16(&mut self, val: [u8; 2], offset: u32, range: u32) {
+        self.write(val[0], offset, range);
+        self.write(val[1], offset + 1, range);
+    }
+    fn read(&mut self, offset: u32, _range: u32) -> u8 {
+        let video = self.video.borrow();
+        match offset {
+            MODE => video.mode,
+            CONTROL => self.control,
+            STATUS => self.status,
+            o if o & !1 == FRAME => self.frame.to_le_bytes()[o as usize & 1],
+            o if (PALETTE..VRAM).contains(&o) => {
+                let i = (o - PALETTE) as usize;
+                video.palette.get(i / 3).map_or(0, |colour| colour[i % 3])
+            }
+            o if o >= VRAM => video.vram[(o - VRAM) as usize],
+            _ => 0,
+        }
+    }
+    fn read16(&mut self, offset: u32, range: u32) -> [u8; 2] {
+        [self.read(offset, range), self.read(offset + 1, range)]
+    }
+    fn clock(&mut self, cycles: u32) -> DevMsg {
+        self.cycles += cycles as u64;
+        let mut shown = false;
+        while self.cycles >= FRAME_CYCLES as u64 {
+            self.cycles -= FRAME_CYCLES as u64;
+            self.vblank();
+            shown = true;
+        }
+        if shown && self.control & VBLANK_IRQ != 0 { DevMsg::Irq } else { DevMsg::None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text() {
+        let mut fb = Framebuffer::new(None);
+        // a yellow A on blue at the second column
+        fb.write(b'A', VRAM + 2, 0);
+        fb.write(0x1e, VRAM + 3, 0);
+        let frame = fb.screen().render();
+        assert_eq!((frame.width, frame.height), (WIDTH, HEIGHT));
+        assert_eq!(frame.pixel(0, 0), CGA[0]);
+        // the top row of A is 0x0c, so pixels 2 and 3 are lit
+        assert_eq!(frame.pixel(8 + 1, 0), CGA[1]);
+        assert_eq!(frame.pixel(8 + 2, 0), CGA[14]);
+        assert_eq!(frame.pixel(8 + 3, 0), CGA[14]);
+        assert_eq!(frame.pixel(8 + 4, 0), CGA[1]);
+    }
+
+    #[test]
+    fn bitmap_and_palette() {
+        let mut fb = Framebuffer::new(None);
+        fb.write(BITMAP, MODE, 0);
+        fb.write16([0x12, 0x34], PALETTE + 0x20 * 3, 0);
+        fb.write(0x56, PALETTE + 0x20 * 3 + 2, 0);
+        assert_eq!(fb.read16(PALETTE + 0x20 * 3 + 2, 0), [0x56, 0]);
+        fb.write(0x20, VRAM + BITMAP_WIDTH + 1, 0);
+        let frame = fb.screen().render();
+        assert_eq!(frame.pixel(2, 2), [0x12, 0x34, 0x56]);
+        assert_eq!(frame.pixel(3, 3), [0x12, 0x34, 0x56]);
+        assert_eq!(frame.pixel(1, 2), [0, 0, 0]);
+    }
+
+    #[test]
+    fn vblank() {
+        let mut fb = Framebuffer::new(None);
+        assert!(matches!(fb.clock(FRAME_CYCLES - 1), DevMsg::None));
+        assert!(matches!(fb.clock(1), DevMsg::None));
+        assert_eq!((fb.read(STATUS, 0), fb.read16(FRAME, 0)), (VBLANK, [1, 0]));
+        fb.write(VBLANK, STATUS, 0);
+        fb.write(VBLANK_IRQ, CONTROL, 0);
+        // more than one in a clock is one irq
+        assert!(matches!(fb.clock(FRAME_CYCLES * 2), DevMsg::Irq));
+        assert_eq!((fb.read(STATUS, 0), fb.read16(FRAME, 0)), (VBLANK, [3, 0]));
+    }
+
+    #[test]
+    fn saved_frames() {
+        let dir = std::env::temp_dir().join(format!("bcpu-frames-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut fb = Framebuffer::new(Some(dir.join("{}.ppm").to_str().unwrap().to_string()));
+        fb.clock(FRAME_CYCLES);
+        // the last frames allowed are saved, and then no more
+        fb.saved = SAVED_FRAMES - 1;
+        fb.clock(FRAME_CYCLES * 2);
+        let mut names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name()).collect();
+        names.sort();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(names, ["00000.ppm", "09999.ppm"]);
+    }
+
+    #[test]
+    fn images() {
+        let frame = Frame { width: 2, height: 1, pixels: vec![[1, 2, 3], [4, 5, 6]] };
+        assert_eq!(frame.to_ppm(), b"P6\n2 1\n255\n\x01\x02\x03\x04\x05\x06");
+        let png = frame.to_png();
+        assert_eq!(png[..8], *b"\x89PNG\r\n\x1a\n");
+        // IHDR's crc, and the adler32 of the one scanline
+        assert_eq!(png[29..33], [0x7b, 0x40, 0xe8, 0xdd]);
+        assert_eq!(adler32(&[0, 1, 2, 3, 4, 5, 6]), 0x003f_0016);
+        assert_eq!(crc32(b"IEND"), 0xae42_6082);
+    }
+}
@@ -0,0 +1,332 @@
+//! a keyboard controller, giving pc style set 1 scan codes: a key's make code when it's pressed,
+//! and its make code | 0x80 when it's released. arrows are extended, with 0xe0 before each code
+//!
+//! ```text
+//! ; a script of keys, each at a cycle
+//! 1000 type "Hi\n"      presses and releases each character's keys, with shift for capitals
+//! 5000 press ctrl       keys by name, as a character or esc, backspace, tab, enter, space,
+//! 5100 press c          shift, ctrl, alt, capslock, f1 to f10, up, down, left or right
+//! 5200 release c
+//! 5300 release ctrl
+//! 6000 code 0x1e 0x9e   scan codes as they are
+//! ```
+
+use std::collections::VecDeque;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use crate::utils;
+use super::{DevMsg, Device};
+
+/// reading takes the next scan code, or 0 if there isn't one
+pub const DATA: u32 = 0;
+/// made of the status bits below. writing OVERFLOW clears it
+pub const STATUS: u32 = 1;
+/// made of the control bits below
+pub const CONTROL: u32 = 2;
+/// how much address space the registers take
+pub const SIZE: u32 = CONTROL + 1;
+
+/// status: there's a scan code to read
+pub const READY: u8 = 0x01;
+/// status: scan codes have been lost since this was cleared, because the fifo was full
+pub const OVERFLOW: u8 = 0x02;
+/// control: irq when scan codes come in, and when this is enabled with some waiting
+pub const IRQ: u8 = 0x01;
+
+/// scan codes past this many are lost, like on a real controller
+const FIFO_SIZE: usize = 16;
+const EXTENDED: u8 = 0xe0;
+const RELEASE: u8 = 0x80;
+const SHIFT: u8 = 0x2a;
+const CTRL_C: u8 = 0x03;
+
+/// keys by name with their make codes, and the characters they type unshifted and shifted
+const KEYS: &[(&str, u8, u8, u8)] = &[
+    ("esc", 0x01, 0x1b, 0), ("1", 0x02, b'1', b'!'), ("2", 0x03, b'2', b'@'), ("3", 0x04, b'3', b'#'),
+    ("4", 0x05, b'4', b'$'), ("5", 0x06, b'5', b'%'), ("6", 0x07, b'6', b'^'), ("7", 0x08, b'7', b'&'),
+    ("8", 0x09, b'8', b'*'), ("9", 0x0a, b'9', b'('), ("0", 0x0b, b'0', b')'), ("-", 0x0c, b'-', b'_'),
+    ("=", 0x0d, b'=', b'+'), ("backspace", 0x0e, 0x08, 0), ("tab", 0x0f, b'\t', 0),
+    ("q", 0x10, b'q', b'Q'), ("w", 0x11, b'w', b'W'), ("e", 0x12, b'e', b'E'), ("r", 0x13, b'r', b'R'),
+    ("t", 0x14, b't', b'T'), ("y", 0x15, b'y', b'Y'), ("u", 0x16, b'u', b'U'), ("i", 0x17, b'i', b'I'),
+    ("o", 0x18, b'o', b'O'), ("p", 0x19, b'p', b'P'), ("[", 0x1a, b'[', b'{'), ("]", 0x1b, b']', b'}'),
+    ("enter", 0x1c, b'\n', 0), ("ctrl", 0x1d, 0, 0),
+    ("a", 0x1e, b'a', b'A'), ("s", 0x1f, b's', b'S'), ("d", 0x20, b'd', b'D'), ("f", 0x21, b'f', b'F'),
+    ("g", 0x22, b'g', b'G'), ("h", 0x23, b'h', b'H'), ("j", 0x24, b'j', b'J'), ("k", 0x25, b'k', b'K'),
+    ("l", 0x26, b'l', b'L'), (";", 0x27, b';', b':'), ("'", 0x28, b'\'', b'"'), ("`", 0x29, b'`', b'~'),
+    ("shift", SHIFT, 0, 0), ("\\", 0x2b, b'\\', b'|'),
+    ("z", 0x2c, b'z', b'Z'), ("x", 0x2d, b'x', b'X'), ("c", 0x2e, b'c', b'C'), ("v", 0x2f, b'v', b'V'),
+    ("b", 0x30, b'b', b'B'), ("n", 0x31, b'n', b'N'), ("m", 0x32, b'm', b'M'), (",", 0x33, b',', b'<'),
+    (".", 0x34, b'.', b'>'), ("/", 0x35, b'/', b'?'), ("alt", 0x38, 0, 0), ("space", 0x39, b' ', 0),
+    ("capslock", 0x3a, 0, 0), ("f1", 0x3b, 0, 0), ("f2", 0x3c, 0, 0), ("f3", 0x3d, 0, 0), ("f4", 0x3e, 0, 0),
+    ("f5", 0x3f, 0, 0), ("f6", 0x40, 0, 0), ("f7", 0x41, 0, 0), ("f8", 0x42, 0, 0), ("f9", 0x43, 0, 0),
+    ("f10", 0x44, 0, 0),
+];
+/// extended keys by name, with their make codes and the letters ending their terminal escape sequences
+const ARROWS: &[(&str, u8, u8)] = &[("up", 0x48, b'A'), ("down", 0x50, b'B'), ("right", 0x4d, b'C'), ("left", 0x4b, b'D')];
+
+/// the make code of a key by name, with EXTENDED before it for extended keys
+fn make_code(name: &str) -> Option<Vec<u8>> {
+    match KEYS.iter().find(|k| k.0 == name) {
+        Some(k) => Some(vec![k.1]),
+        None => ARROWS.iter().find(|k| k.0 == name).map(|k| vec![EXTENDED, k.1]),
+    }
+}
+
+/// the scan codes for typing a character, pressing and releasing its keys
+fn type_char(c: u8) -> Option<Vec<u8>> {
+    let c = match c {
+        b'\r' => b'\n',
+        0x7f => 0x08,
+        c => c,
+    };
+    let (code, shifted) = KEYS.iter()
+        .find_map(|k| if k.2 == c && c != 0 { Some((k.1, false)) } else if k.3 == c && c != 0 { Some((k.1, true)) } else { None })?;
+    Some(if shifted {
+        vec![SHIFT, code, code | RELEASE, SHIFT | RELEASE]
+    }
+    else {
+        vec![code, code | RELEASE]
+    })
+}
+
+/// reads a script of keys, into the scan codes that come in at each cycle
+pub fn parse_script(script: &str) -> Result<Vec<(u64, Vec<u8>)>, String> {
+    let mut events = Vec::new();
+    for (i, line) in script.lines().enumerate() {
+        let event = parse_script_line(line).map_err(|e| format!("{}: {}", i + 1, e))?;
+        events.extend(event);
+    }
+    // keys come in the order of their cycles, and then the order they're in the script
+    events.sort_by_key(|(cycle, _)| *cycle);
+    Ok(events)
+}
+
+/// reads one line of a script of keys, which is nothing if it's blank or a comment
+pub fn parse_script_line(line: &str) -> Result<Option<(u64, Vec<u8>)>, String> {
+    let line = utils::strip_comment(line).trim();
+    if line.is_empty() {
+        return Ok(None)
+    }
+    let (cycle, action) = line.split_once(char::is_whitespace).ok_or(format!("can't parse {:?}", line))?;
+    let cycle = utils::parse_addr(cycle).ok_or(format!("bad cycle {}", cycle))? as u64;
+    let codes = match action.trim().split_once(char::is_whitespace) {
+        Some(("type", text)) => {
+            let text = utils::string(text.trim())?;
+            text.iter()
+                .map(|c| type_char(*c).ok_or(format!("no key types {:?}", *c as char)))
+                .collect::<Result<Vec<_>, _>>()?
+                .concat()
+        }
+        Some(("press", key)) => make_code(key.trim()).ok_or(format!("unknown key {}", key.trim()))?,
+        Some(("release", key)) => {
+            let mut codes = make_code(key.trim()).ok_or(format!("unknown key {}", key.trim()))?;
+            *codes.last_mut().unwrap() |= RELEASE;
+            codes
+        }
+        Some(("code", codes)) => codes.split_whitespace()
+            .map(|c| utils::parse_addr(c).filter(|c| *c <= 0xff).map(|c| c as u8).ok_or(format!("bad scan code {}", c)))
+            .collect::<Result<_, _>>()?,
+        _ => return Err(format!("can't parse {:?}", action.trim())),
+    };
+    Ok(Some((cycle, codes)))
+}
+
+/// where key presses come from
+pub enum KeyboardInput {
+    /// the host terminal, put in raw mode from when the keyboard is first clocked until it's dropped.
+    /// ctrl-c still quits
+    Terminal,
+    /// scan codes that come in at cycles, from parse_script
+    Script(Vec<(u64, Vec<u8>)>),
+}
+
+enum Source {
+    /// the terminal, before it's been put in raw mode
+    Terminal,
+    Host { rx: Receiver<Vec<u8>>, saved_mode: Option<String> },
+    Script(VecDeque<(u64, Vec<u8>)>),
+}
+
+/// runs stty on the terminal, returning what it printed
+fn stty(args: &[&str]) -> Option<String> {
+    let tty = std::fs::File::open("/dev/tty").ok()?;
+    let out = Command::new("stty").args(args).stdin(tty).stderr(Stdio::null()).output().ok()?;
+    out.status.success().then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+/// reads key presses from the terminal, turning characters and arrow escape sequences into scan codes
+fn read_terminal(tx: mpsc::Sender<Vec<u8>>, saved_mode: Option<String>) {
+    let mut bytes = std::io::stdin().lock().bytes().map_while(Result::ok);
+    while let Some(b) = bytes.next() {
+        let codes = match b {
+            CTRL_C => {
+                if let Some(mode) = &saved_mode {
+                    stty(&[mode]);
+                }
+                std::process::exit(130)
+            }
+            0x1b => match (bytes.next(), bytes.next()) {
+                (Some(b'['), Some(end)) => match ARROWS.iter().find(|k| k.2 == end) {
+                    Some(k) => vec![EXTENDED, k.1, EXTENDED, k.1 | RELEASE],
+                    None => continue,
+                },
+                // a lone escape only comes through with the next key
+                _ => type_char(0x1b).unwrap_or_default(),
+            },
+            b => match type_char(b) {
+                Some(codes) => codes,
+                None => continue,
+            },
+        };
+        if tx.send(codes).is_err() {
+            break
+        }
+    }
+}
+
+/// a keyboard controller with a fifo of scan codes, with registers at DATA, STATUS and CONTROL
+pub struct Keyboard {
+    source: Source,
+    fifo: VecDeque<u8>,
+    status: u8,
+    control: u8,
+    /// cycles it's been clocked with, which scripted keys are timed by
+    cycles: u64,
+    /// an irq to raise on the next clock
+    irq: bool,
+}
+impl Keyboard {
+    pub fn new(input: KeyboardInput) -> Keyboard {
+        let source = match input {
+            KeyboardInput::Terminal => Source::Terminal,
+            KeyboardInput::Script(events) => Source::Script(events.into()),
+        };
+        Keyboard { source, fifo: VecDeque::new(), status: 0, control: 0, cycles: 0, irq: false }
+    }
+    /// puts scan codes in the fifo, losing any that don't fit
+    fn receive(&mut self, codes: &[u8]) {
+        for c in codes {
+            if self.fifo.len() < FIFO_SIZE {
+                self.fifo.push_back(*c);
+            }
+            else {
+                self.status |= OVERFLOW;
+            }
+        }
+        self.irq |= !codes.is_empty() && self.control & IRQ != 0;
+    }
+    fn status(&self) -> u8 {
+        self.status | if self.fifo.is_empty() { 0 } else { READY }
+    }
+}
+impl Drop for Keyboard {
+    fn drop(&mut self) {
+        if let Source::Host { saved_mode: Some(mode), .. } = &self.source {
+            stty(&[mode]);
+        }
+    }
+}
+
+impl Device for Keyboard {
+    fn write(&mut self, val: u8, offset: u32, _range: u32) {
+        match offset {
+            STATUS => self.status &= !(val & OVERFLOW),
+            CONTROL => {
+                self.irq |= val & !self.control & IRQ != 0 && !self.fifo.is_empty();
+                self.control = val & IRQ;
+            }
+            _ => (),
+        }
+    }
+    fn write16(&mut self, val: [u8; 2], offset: u32, range: u32) {
+        self.write(val[0], offset, range);
+        self.write(val[1], offset + 1, range);
+    }
+    fn read(&mut self, offset: u32, _range: u32) -> u8 {
+        match offset {
+            DATA => self.fifo.pop_front().unwrap_or(0),
+            STATUS => self.status(),
+            CONTROL => self.control,
+            _ => 0,
+        }
+    }
+    fn read16(&mut self, offset: u32, range: u32) -> [u8; 2] {
+        [self.read(offset, range), self.read(offset + 1, range)]
+    }
+    fn clock(&mut self, cycles: u32) -> DevMsg {
+        self.cycles += cycles as u64;
+        let mut codes = Vec::new();
+        if let Source::Terminal = self.source {
+            let saved_mode = stty(&["-g"]);
+            if saved_mode.is_some() {
+                stty(&["raw", "-echo"]);
+            }
+            let (tx, rx) = mpsc::channel();
+            let thread_mode = saved_mode.clone();
+            std::thread::spawn(move || read_terminal(tx, thread_mode));
+            self.source = Source::Host { rx, saved_mode };
+        }
+        match &mut self.source {
+            Source::Host { rx, .. } => codes.extend(rx.try_iter().flatten()),
+            Source::Terminal => (),
+            Source::Script(events) => {
+                while events.front().is_some_and(|(cycle, _)| *cycle <= self.cycles) {
+                    codes.extend(events.pop_front().unwrap().1);
+                }
+            }
+        }
+        self.receive(&codes);
+        if std::mem::take(&mut self.irq) { DevMsg::Irq } else { DevMsg::None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts() {
+        let script = "
+            ; a comment
+            20 press up
+            10 type \"Hi;\\n\"
+            20 release up
+            30 code 0x01 0x81
+        ";
+        assert_eq!(parse_script(script).unwrap(), [
+            (10, vec![0x2a, 0x23, 0xa3, 0xaa, 0x17, 0x97, 0x27, 0xa7, 0x1c, 0x9c]),
+            (20, vec![0xe0, 0x48]),
+            (20, vec![0xe0, 0xc8]),
+            (30, vec![0x01, 0x81]),
+        ]);
+        assert_eq!(parse_script("1 press hyper").unwrap_err(), "1: unknown key hyper");
+        assert_eq!(parse_script("x type \"a\"").unwrap_err(), "1: bad cycle x");
+        assert_eq!(parse_script("1 code 0x100").unwrap_err(), "1: bad scan code 0x100");
+        assert_eq!(parse_script("\n\n2 code 1 x").unwrap_err(), "3: bad scan code x");
+        assert_eq!(parse_script_line("1 press hyper").unwrap_err(), "unknown key hyper");
+        assert_eq!(parse_script_line("  ; nothing").unwrap(), None);
+    }
+
+    #[test]
+    fn fifo_and_irqs() {
+        let events = vec![(5, vec![0x1e, 0x9e]), (10, (0..20).collect())];
+        let mut kb = Keyboard::new(KeyboardInput::Script(events));
+        kb.write(IRQ, CONTROL, 0);
+        assert!(matches!(kb.clock(4), DevMsg::None));
+        assert_eq!(kb.read(STATUS, 0), 0);
+        assert!(matches!(kb.clock(1), DevMsg::Irq));
+        assert_eq!(kb.read16(DATA, 0), [0x1e, READY]);
+        assert_eq!(kb.read(DATA, 0), 0x9e);
+        assert_eq!(kb.read(DATA, 0), 0);
+
+        // codes that don't fit are lost
+        assert!(matches!(kb.clock(100), DevMsg::Irq));
+        assert_eq!(kb.read(STATUS, 0), READY | OVERFLOW);
+        let codes: Vec<u8> = (0..FIFO_SIZE).map(|_| kb.read(DATA, 0)).collect();
+        assert_eq!(codes, (0..FIFO_SIZE as u8).collect::<Vec<_>>());
+        kb.write(OVERFLOW, STATUS, 0);
+        assert_eq!(kb.read(STATUS, 0), 0);
+        assert!(matches!(kb.clock(100), DevMsg::None));
+    }
+}
@@ -1,11 +1,13 @@
-use hlua::{Lua, LuaFunction};
+use hlua::Lua;
 use super::{DevMsg, Device};
 
+#[allow(dead_code)] // not hooked up to anything yet
 pub struct LuaDevice<'a> {
     lua: Lua<'a>,
 }
+#[allow(dead_code)]
 impl<'a> LuaDevice<'a> {
-    fn new(code: &str) -> (LuaDevice, String) {
+    fn new(code: &str) -> (LuaDevice<'a>, String) {
         let mut lua = Lua::new();
         lua.execute::<()>(code).unwrap();
         let id: String = lua.get("DEVICE_ID").unwrap();
@@ -16,9 +18,9 @@ impl<'a> LuaDevice<'a> {
     }
 }
 impl Device for LuaDevice<'_> {
-    fn write(&mut self, val: u8, offset: u32, range: u32) {}
-    fn write16(&mut self, val: [u8; 2], offset: u32, range: u32) {}
-    fn read(&mut self, offset: u32, range: u32) -> u8 { 0 }
-    fn read16(&mut self, offset: u32, range: u32) -> [u8; 2] { [0, 0] }
-    fn clock(&mut self) -> DevMsg { DevMsg::None }
+    fn write(&mut self, _val: u8, _offset: u32, _range: u32) {}
+    fn write16(&mut self, _val: [u8; 2], _offset: u32, _range: u32) {}
+    fn read(&mut self, _offset: u32, _range: u32) -> u8 { 0 }
+    fn read16(&mut self, _offset: u32, _range: u32) -> [u8; 2] { [0, 0] }
+    fn clock(&mut self, _cycles: u32) -> DevMsg { DevMsg::None }
 }
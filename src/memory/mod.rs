@@ -1,13 +1,125 @@
+use std::collections::HashSet;
 use std::ops::Range;
-use lua_device::LuaDevice;
+pub use disk::{Disk, DiskImage};
+pub use dma::Dma;
+pub use framebuffer::Framebuffer;
+pub use keyboard::{Keyboard, KeyboardInput};
+pub use pic::Pic;
+pub use rom::Rom;
+pub use rustmemory::RustMemory;
+pub use timer::Timer;
+pub use uart::{Uart, UartInput, UartOutput};
 
+mod font;
+mod rom;
 mod rustmemory;
 mod lua_device;
+pub mod disk;
+pub mod dma;
+pub mod framebuffer;
+pub mod keyboard;
+pub mod pic;
+pub mod timer;
+pub mod uart;
+
+/// the devices that can be mapped by name, from the command line or a test program
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DeviceKind {
+    Uart,
+    Timer,
+    Disk,
+    Dma,
+    Framebuffer,
+    Keyboard,
+}
+/// each device's name, and how much address space it takes
+pub const DEVICES: [(&str, DeviceKind, u32); 6] = [
+    ("uart", DeviceKind::Uart, uart::SIZE),
+    ("timer", DeviceKind::Timer, timer::SIZE),
+    ("disk", DeviceKind::Disk, disk::SIZE),
+    ("dma", DeviceKind::Dma, dma::SIZE),
+    ("framebuffer", DeviceKind::Framebuffer, framebuffer::SIZE),
+    ("keyboard", DeviceKind::Keyboard, keyboard::SIZE),
+];
+impl DeviceKind {
+    pub fn named(name: &str) -> Option<DeviceKind> {
+        DEVICES.iter().find(|(n, _, _)| *n == name).map(|(_, kind, _)| *kind)
+    }
+    pub fn size(self) -> u32 {
+        DEVICES.iter().find(|(_, kind, _)| *kind == self).map_or(0, |(_, _, size)| *size)
+    }
+}
+
+/// how long a single bus access takes
+const ACCESS_CYCLES: u64 = 1;
+/// writes are tracked in pages of this many bits, for the decode cache
+pub const PAGE_BITS: u32 = 8;
 
 pub struct MemoryMap {
-    devices: Vec<MMapDevice>
+    devices: Vec<MMapDevice>,
+    /// total cycles spent on bus accesses
+    cycles: u64,
+    /// pages to report writes to
+    watched: HashSet<u32>,
+    /// watched pages that have been written to since the last take_written_pages
+    written: Vec<u32>,
+    /// cycles bus masters have taken from the cpu since the last take_stolen
+    stolen: u64,
 }
 impl MemoryMap {
+    pub fn new() -> MemoryMap {
+        MemoryMap {
+            devices: Vec::new(),
+            cycles: 0,
+            watched: HashSet::new(),
+            written: Vec::new(),
+            stolen: 0,
+        }
+    }
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    /// the next write to a page will be reported by take_written_pages
+    pub fn watch_page(&mut self, page: u32) {
+        self.watched.insert(page);
+    }
+    pub fn take_written_pages(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.written)
+    }
+    fn note_write(&mut self, addr: u32) {
+        let page = addr >> PAGE_BITS;
+        if self.watched.remove(&page) {
+            self.written.push(page)
+        }
+    }
+    /// the cycles bus masters have spent on the bus since this was last called, which the cpu couldn't use.  
+    /// any more than `max` are left for next time
+    pub fn take_stolen(&mut self, max: u32) -> u32 {
+        let taken = self.stolen.min(max as u64);
+        self.stolen -= taken;
+        taken as u32
+    }
+    pub fn add_device(&mut self, dev: Box<dyn Device>, mem_ranges: Vec<Range<u32>>) {
+        let write_faults = dev.write_faults();
+        let bus_master = dev.bus_master();
+        let can_interrupt = dev.can_interrupt();
+        let read_only = dev.read_only();
+        self.devices.push(MMapDevice { dev, mem_ranges, write_faults, bus_master, can_interrupt, read_only })
+    }
+    /// maps a device over a single range
+    pub fn add_device_at(&mut self, dev: Box<dyn Device>, range: Range<u32>) {
+        self.add_device(dev, vec![range])
+    }
+    /// whether any device might ever raise an interrupt
+    pub fn can_interrupt(&self) -> bool {
+        self.devices.iter().any(|d| d.can_interrupt)
+    }
+    /// whether writes to an address land somewhere, rather than faulting or being lost
+    pub fn is_writable(&self, addr: u32) -> bool {
+        self.devices.iter()
+            .find(|d| d.mem_ranges.iter().any(|r| r.contains(&addr)))
+            .is_some_and(|d| !d.write_faults && !d.read_only)
+    }
     fn find_device_get_offset(&mut self, addr: u32) -> Option<(&mut MMapDevice, u32, u32)> {
         for (dev_idx, d) in self.devices.iter().enumerate() {
             if let Some(range_idx) = d.mem_ranges.iter().position(|r| addr >= r.start && addr < r.end) {
@@ -18,47 +130,171 @@ impl MemoryMap {
         };
         None
     }
+    /// clocks every device by the given number of cycles, returning the most urgent interrupt any of them raised
+    pub fn clock(&mut self, cycles: u32) -> DevMsg {
+        let mut msg = DevMsg::None;
+        for i in 0..self.devices.len() {
+            let d = &mut self.devices[i];
+            let dev_msg = if d.bus_master {
+                // it's taken out while it has the bus, so it can be given the rest of the map
+                let mut dev = std::mem::replace(&mut d.dev, Box::new(Detached));
+                let start = self.cycles;
+                let dev_msg = dev.clock_master(cycles, self);
+                self.stolen += self.cycles - start;
+                self.devices[i].dev = dev;
+                dev_msg
+            }
+            else {
+                d.dev.clock(cycles)
+            };
+            if dev_msg.urgency() >= msg.urgency() {
+                msg = dev_msg;
+            }
+        }
+        msg
+    }
+    /// tells the devices the cpu has taken a vector, so the one that raised it can stop raising it
+    pub fn acknowledge(&mut self, vector: u8) {
+        for d in &mut self.devices {
+            d.dev.acknowledge(vector);
+        }
+    }
     pub fn read(&mut self, addr: u32) -> u8 {
+        self.cycles += ACCESS_CYCLES;
         if let Some((dev, offset, range_idx)) = self.find_device_get_offset(addr) {
-            dev.dev.read(offset, range_idx as u32)
+            dev.dev.read(offset, range_idx)
         } else { 0 }
     }
+    /// unaligned reads are split into two byte reads
     pub fn read16(&mut self, addr: u32) -> [u8; 2] {
         if addr & 1 != 0 {
-            [self.read(addr), self.read(addr + 1)]
+            return [self.read(addr), self.read(addr.wrapping_add(1))]
         }
-        else if let Some((dev, offset, range_idx)) = self.find_device_get_offset(addr) {
-            dev.dev.read16(offset, range_idx as u32)
+        self.cycles += ACCESS_CYCLES;
+        if let Some((dev, offset, range_idx)) = self.find_device_get_offset(addr) {
+            dev.dev.read16(offset, range_idx)
         } else { [0, 0] }
     }
-    pub fn write(&mut self, val: u8, addr: u32) {
-        if let Some((dev, offset, range_idx)) = self.find_device_get_offset(addr) {
-            dev.dev.write(val, offset, range_idx as u32)
+    /// returns false for a bus fault, when the device doesn't take writes. writes to nothing are just lost
+    pub fn write(&mut self, val: u8, addr: u32) -> bool {
+        self.cycles += ACCESS_CYCLES;
+        self.note_write(addr);
+        match self.find_device_get_offset(addr) {
+            Some((dev, _, _)) if dev.write_faults => false,
+            Some((dev, offset, range_idx)) => {
+                dev.dev.write(val, offset, range_idx);
+                true
+            }
+            None => true,
         }
     }
-    pub fn write16(&mut self, val: [u8; 2], addr: u32) {
-        if let Some((dev, offset, range_idx)) = self.find_device_get_offset(addr) {
-            dev.dev.write16(val, offset, range_idx as u32)
+    /// unaligned writes are split into two byte writes
+    pub fn write16(&mut self, val: [u8; 2], addr: u32) -> bool {
+        if addr & 1 != 0 {
+            let lo = self.write(val[0], addr);
+            return self.write(val[1], addr.wrapping_add(1)) && lo
+        }
+        self.cycles += ACCESS_CYCLES;
+        self.note_write(addr);
+        match self.find_device_get_offset(addr) {
+            Some((dev, _, _)) if dev.write_faults => false,
+            Some((dev, offset, range_idx)) => {
+                dev.dev.write16(val, offset, range_idx);
+                true
+            }
+            None => true,
         }
     }
 }
 
 struct MMapDevice {
     dev: Box<dyn Device>,
-    mem_ranges: Vec<Range<u32>>
+    mem_ranges: Vec<Range<u32>>,
+    write_faults: bool,
+    bus_master: bool,
+    can_interrupt: bool,
+    read_only: bool,
+}
+
+/// stands in for a bus master while it has the bus
+struct Detached;
+impl Device for Detached {
+    fn write(&mut self, _val: u8, _offset: u32, _range: u32) {}
+    fn write16(&mut self, _val: [u8; 2], _offset: u32, _range: u32) {}
 }
 
 pub trait Device {
     fn write(&mut self, val: u8, offset: u32, range: u32);
     /// offset will ALWAYS be a multiple of 2
     fn write16(&mut self, val: [u8; 2], offset: u32, range: u32);
-    fn read(&mut self, offset: u32, range: u32) -> u8 { 0 }
+    fn read(&mut self, _offset: u32, _range: u32) -> u8 { 0 }
     /// offset will ALWAYS be a multiple of 2
-    fn read16(&mut self, offset: u32, range: u32) -> [u8; 2] { [0, 0] }
-    fn clock(&mut self) -> DevMsg { DevMsg::None }
+    fn read16(&mut self, _offset: u32, _range: u32) -> [u8; 2] { [0, 0] }
+    /// called after every instruction with the number of cycles it took
+    fn clock(&mut self, _cycles: u32) -> DevMsg { DevMsg::None }
+    /// whether writing to it is a bus fault rather than a call to write. asked once, when it's mapped
+    fn write_faults(&self) -> bool { false }
+    /// whether it's clocked with clock_master rather than clock, to use the bus. asked once, when it's mapped
+    fn bus_master(&self) -> bool { false }
+    /// clock for bus masters, which can access everything else mapped. its own ranges read as 0 to it
+    fn clock_master(&mut self, cycles: u32, _bus: &mut MemoryMap) -> DevMsg { self.clock(cycles) }
+    /// called when the cpu takes a vector. one raised by this device should be raised again until it is
+    fn acknowledge(&mut self, _vector: u8) {}
+    /// whether it might ever raise an interrupt, so waiting for one when nothing can isn't waiting forever.
+    /// asked once, when it's mapped
+    fn can_interrupt(&self) -> bool { true }
+    /// whether writes to it are ignored, so loaders can say their data won't land rather than lose it.
+    /// asked once, when it's mapped
+    fn read_only(&self) -> bool { false }
 }
 pub enum DevMsg {
     None,
     Irq,
+    /// an irq for a particular interrupt vector, from an interrupt controller
+    Vector(u8),
+    #[allow(dead_code)] // no devices raise them yet
     Nmi
 }
+impl DevMsg {
+    /// how urgent it is, so a more urgent interrupt isn't replaced by a less urgent one raised with it
+    pub fn urgency(&self) -> u8 {
+        match self {
+            DevMsg::None => 0,
+            DevMsg::Irq => 1,
+            DevMsg::Vector(_) => 2,
+            DevMsg::Nmi => 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_cycles() {
+        let mut mem = MemoryMap::new();
+        mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+
+        mem.write16([0x34, 0x12], 0x100);
+        mem.write16([0x78, 0x56], 0x103);
+        assert_eq!(mem.cycles(), 3);
+        assert_eq!(mem.read16(0x100), [0x34, 0x12]);
+        assert_eq!(mem.cycles(), 4);
+        assert_eq!(mem.read16(0x103), [0x78, 0x56]);
+        assert_eq!(mem.cycles(), 6);
+        // unmapped addresses still take a bus cycle
+        assert_eq!(mem.read(0x1_0000), 0);
+        assert_eq!(mem.cycles(), 7);
+    }
+
+    #[test]
+    fn stolen_cycles() {
+        let mut mem = MemoryMap::new();
+        mem.stolen = u32::MAX as u64 + 5;
+        assert_eq!(mem.take_stolen(u32::MAX), u32::MAX);
+        assert_eq!(mem.take_stolen(2), 2);
+        assert_eq!(mem.take_stolen(u32::MAX), 3);
+        assert_eq!(mem.take_stolen(u32::MAX), 0);
+    }
+}
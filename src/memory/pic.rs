@@ -0,0 +1,279 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use super::{DevMsg, Device, MemoryMap};
+
+/// read only, the lines that have been raised and not yet taken by the cpu
+pub const PENDING: u32 = 0;
+/// masked lines still become pending, but aren't delivered until they're unmasked
+pub const MASK: u32 = 1;
+/// read only, the lines the cpu has taken and not yet ended
+pub const IN_SERVICE: u32 = 2;
+/// write a line to end its interrupt, or NON_SPECIFIC_EOI to end the most urgent one in service
+pub const EOI: u32 = 3;
+/// line n raises this vector plus n. 0x10 by default, past the ones the cpu uses
+pub const VECTOR_BASE: u32 = 4;
+/// a byte per line. higher is more urgent, and lower lines win ties, so by default line 0 is the most urgent
+pub const PRIORITY: u32 = 8;
+
+pub const LINES: u8 = 8;
+pub const NON_SPECIFIC_EOI: u8 = 0xff;
+/// how many bytes of registers there are to map
+pub const SIZE: u32 = PRIORITY + LINES as u32;
+
+struct Lines {
+    pending: u8,
+    mask: u8,
+    in_service: u8,
+    base: u8,
+    priority: [u8; LINES as usize],
+}
+impl Lines {
+    /// the most urgent of some lines
+    fn most_urgent(&self, lines: u8) -> Option<u8> {
+        (0..LINES).filter(|l| lines & 1 << l != 0).max_by_key(|l| self.urgency(*l))
+    }
+    fn urgency(&self, line: u8) -> (u8, u8) {
+        (self.priority[line as usize], LINES - line)
+    }
+    /// the most urgent unmasked pending line, if it's more urgent than every line in service
+    fn deliverable(&self) -> Option<u8> {
+        let line = self.most_urgent(self.pending & !self.mask)?;
+        match self.most_urgent(self.in_service) {
+            Some(serving) if self.urgency(line) <= self.urgency(serving) => None,
+            _ => Some(line),
+        }
+    }
+}
+
+/// an interrupt controller, which gathers the irqs of the devices connected to it onto lines
+/// and raises a vector for each line, so a handler knows where its interrupt came from.
+/// lines are delivered one at a time, and only interrupt handlers of less urgent lines
+pub struct Pic {
+    lines: Rc<RefCell<Lines>>,
+}
+impl Pic {
+    pub fn new() -> Pic {
+        let lines = Lines { pending: 0, mask: 0, in_service: 0, base: 0x10, priority: [0; LINES as usize] };
+        Pic { lines: Rc::new(RefCell::new(lines)) }
+    }
+    /// connects a device's irqs to a line, returning it to be mapped instead of the device
+    pub fn connect(&self, line: u8, dev: Box<dyn Device>) -> Box<dyn Device> {
+        assert!(line < LINES, "the pic only has {} lines", LINES);
+        Box::new(Connected { dev, line, lines: self.lines.clone() })
+    }
+}
+
+impl Device for Pic {
+    fn write(&mut self, val: u8, offset: u32, _range: u32) {
+        let mut lines = self.lines.borrow_mut();
+        match offset {
+            MASK => lines.mask = val,
+            EOI => {
+                let line = match val {
+                    NON_SPECIFIC_EOI => lines.most_urgent(lines.in_service),
+                    l if l < LINES => Some(l),
+                    _ => None,
+                };
+                if let Some(l) = line {
+                    lines.in_service &= !(1 << l);
+                }
+            }
+            VECTOR_BASE => lines.base = val,
+            o if (PRIORITY..SIZE).contains(&o) => lines.priority[(o - PRIORITY) as usize] = val,
+            _ => (),
+        }
+    }
+    fn write16(&mut self, val: [u8; 2], offset: u32, range: u32) {
+        self.write(val[0], offset, range);
+        self.write(val[1], offset + 1, range);
+    }
+    fn read(&mut self, offset: u32, _range: u32) -> u8 {
+        let lines = self.lines.borrow();
+        match offset {
+            PENDING => lines.pending,
+            MASK => lines.mask,
+            IN_SERVICE => lines.in_service,
+            VECTOR_BASE => lines.base,
+            o if (PRIORITY..SIZE).contains(&o) => lines.priority[(o - PRIORITY) as usize],
+            _ => 0,
+        }
+    }
+    fn read16(&mut self, offset: u32, range: u32) -> [u8; 2] {
+        [self.read(offset, range), self.read(offset + 1, range)]
+    }
+    /// raises the vector of the line to deliver, every clock until the cpu takes it
+    fn clock(&mut self, _cycles: u32) -> DevMsg {
+        let lines = self.lines.borrow();
+        match lines.deliverable() {
+            Some(line) => DevMsg::Vector(lines.base.wrapping_add(line)),
+            None => DevMsg::None,
+        }
+    }
+    fn acknowledge(&mut self, vector: u8) {
+        let mut lines = self.lines.borrow_mut();
+        let line = vector.wrapping_sub(lines.base);
+        if lines.deliverable() == Some(line) {
+            lines.pending &= !(1 << line);
+            lines.in_service |= 1 << line;
+        }
+    }
+    /// it only passes on what its lines raise, and they're mapped on their own
+    fn can_interrupt(&self) -> bool { false }
+}
+
+/// a device connected to a line, which raises the line rather than an irq of its own
+struct Connected {
+    dev: Box<dyn Device>,
+    line: u8,
+    lines: Rc<RefCell<Lines>>,
+}
+impl Connected {
+    /// turns the device's irqs into its line being pending
+    fn route(&self, msg: DevMsg) -> DevMsg {
+        match msg {
+            DevMsg::Irq => {
+                self.lines.borrow_mut().pending |= 1 << self.line;
+                DevMsg::None
+            }
+            msg => msg,
+        }
+    }
+}
+impl Device for Connected {
+    fn write(&mut self, val: u8, offset: u32, range: u32) {
+        self.dev.write(val, offset, range)
+    }
+    fn write16(&mut self, val: [u8; 2], offset: u32, range: u32) {
+        self.dev.write16(val, offset, range)
+    }
+    fn read(&mut self, offset: u32, range: u32) -> u8 {
+        self.dev.read(offset, range)
+    }
+    fn read16(&mut self, offset: u32, range: u32) -> [u8; 2] {
+        self.dev.read16(offset, range)
+    }
+    fn clock(&mut self, cycles: u32) -> DevMsg {
+        let msg = self.dev.clock(cycles);
+        self.route(msg)
+    }
+    fn write_faults(&self) -> bool {
+        self.dev.write_faults()
+    }
+    fn bus_master(&self) -> bool {
+        self.dev.bus_master()
+    }
+    fn clock_master(&mut self, cycles: u32, bus: &mut MemoryMap) -> DevMsg {
+        let msg = self.dev.clock_master(cycles, bus);
+        self.route(msg)
+    }
+    fn acknowledge(&mut self, vector: u8) {
+        self.dev.acknowledge(vector)
+    }
+    fn can_interrupt(&self) -> bool {
+        self.dev.can_interrupt()
+    }
+    fn read_only(&self) -> bool {
+        self.dev.read_only()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// raises an irq when told to
+    struct Button {
+        pressed: Rc<RefCell<bool>>,
+    }
+    impl Device for Button {
+        fn write(&mut self, _val: u8, _offset: u32, _range: u32) {}
+        fn write16(&mut self, _val: [u8; 2], _offset: u32, _range: u32) {}
+        fn clock(&mut self, _cycles: u32) -> DevMsg {
+            if std::mem::take(&mut *self.pressed.borrow_mut()) { DevMsg::Irq } else { DevMsg::None }
+        }
+    }
+
+    /// clocks the pic, and takes the vector it raises like the cpu would
+    fn vector(pic: &mut Pic) -> Option<u8> {
+        match pic.clock(1) {
+            DevMsg::Vector(v) => {
+                pic.acknowledge(v);
+                Some(v)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn lines() {
+        let mut pic = Pic::new();
+        let buttons: Vec<_> = (0..3).map(|_| Rc::new(RefCell::new(false))).collect();
+        let mut devices: Vec<_> = [4, 1, 2].iter().zip(&buttons)
+            .map(|(line, pressed)| pic.connect(*line, Box::new(Button { pressed: pressed.clone() })))
+            .collect();
+        let mut clock = |pic: &mut Pic| {
+            for d in &mut devices {
+                assert!(matches!(d.clock(1), DevMsg::None));
+            }
+            vector(pic)
+        };
+
+        *buttons[0].borrow_mut() = true;
+        *buttons[1].borrow_mut() = true;
+        assert_eq!(clock(&mut pic), Some(0x11));
+        assert_eq!((pic.read(PENDING, 0), pic.read(IN_SERVICE, 0)), (0x10, 0x02));
+        // line 4 is less urgent than line 1, so it waits
+        assert_eq!(clock(&mut pic), None);
+        pic.write(1, EOI, 0);
+        assert_eq!(clock(&mut pic), Some(0x14));
+
+        // line 2 is more urgent than line 4, so it interrupts its handler, unless it's masked
+        pic.write(0x04, MASK, 0);
+        *buttons[2].borrow_mut() = true;
+        assert_eq!(clock(&mut pic), None);
+        pic.write(0, MASK, 0);
+        pic.write(0x20, VECTOR_BASE, 0);
+        assert_eq!(clock(&mut pic), Some(0x22));
+        pic.write(NON_SPECIFIC_EOI, EOI, 0);
+        assert_eq!(pic.read(IN_SERVICE, 0), 0x10);
+        pic.write(NON_SPECIFIC_EOI, EOI, 0);
+        assert_eq!(pic.read(IN_SERVICE, 0), 0);
+    }
+
+    #[test]
+    fn priorities() {
+        let mut pic = Pic::new();
+        pic.write(3, PRIORITY + 5, 0);
+        let pressed = Rc::new(RefCell::new(true));
+        let mut low = pic.connect(0, Box::new(Button { pressed: Rc::new(RefCell::new(true)) }));
+        let mut high = pic.connect(5, Box::new(Button { pressed: pressed.clone() }));
+        low.clock(1);
+        high.clock(1);
+        assert_eq!(vector(&mut pic), Some(0x15));
+        assert_eq!(vector(&mut pic), None);
+        pic.write(5, EOI, 0);
+        assert_eq!(vector(&mut pic), Some(0x10));
+        // line 5 is more urgent, so it nests inside line 0's handler
+        *pressed.borrow_mut() = true;
+        high.clock(1);
+        assert_eq!(vector(&mut pic), Some(0x15));
+        assert_eq!(pic.read(PRIORITY + 5, 0), 3);
+    }
+
+    #[test]
+    fn acknowledgement() {
+        let mut pic = Pic::new();
+        let mut dev = pic.connect(3, Box::new(Button { pressed: Rc::new(RefCell::new(true)) }));
+        dev.clock(1);
+        // a vector the cpu didn't take is raised again
+        assert!(matches!(pic.clock(1), DevMsg::Vector(0x13)));
+        assert!(matches!(pic.clock(1), DevMsg::Vector(0x13)));
+        assert_eq!((pic.read(PENDING, 0), pic.read(IN_SERVICE, 0)), (0x08, 0));
+        // one that isn't the line being delivered changes nothing
+        pic.acknowledge(0x12);
+        assert_eq!((pic.read(PENDING, 0), pic.read(IN_SERVICE, 0)), (0x08, 0));
+        pic.acknowledge(0x13);
+        assert_eq!((pic.read(PENDING, 0), pic.read(IN_SERVICE, 0)), (0, 0x08));
+        assert!(matches!(pic.clock(1), DevMsg::None));
+    }
+}
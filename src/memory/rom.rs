@@ -0,0 +1,64 @@
+use std::path::Path;
+use super::Device;
+
+/// read-only memory, like boot firmware. every range it's mapped at sees the same data,
+/// repeating if a range is bigger than it, as if the upper address lines weren't connected
+pub struct Rom {
+    data: Vec<u8>,
+    /// writes are bus faults, rather than ignored
+    fault: bool,
+}
+impl Rom {
+    pub fn new(data: Vec<u8>, fault: bool) -> Rom {
+        Rom { data, fault }
+    }
+    pub fn from_file(path: &Path, fault: bool) -> std::io::Result<Rom> {
+        Ok(Rom::new(std::fs::read(path)?, fault))
+    }
+}
+
+impl Device for Rom {
+    fn write(&mut self, _val: u8, _offset: u32, _range: u32) {}
+    fn write16(&mut self, _val: [u8; 2], _offset: u32, _range: u32) {}
+    fn read(&mut self, offset: u32, _range: u32) -> u8 {
+        match self.data.len() {
+            0 => 0,
+            len => self.data[offset as usize % len],
+        }
+    }
+    fn read16(&mut self, offset: u32, range: u32) -> [u8; 2] {
+        [self.read(offset, range), self.read(offset + 1, range)]
+    }
+    fn write_faults(&self) -> bool {
+        self.fault
+    }
+    fn can_interrupt(&self) -> bool { false }
+    fn read_only(&self) -> bool { true }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{MemoryMap, RustMemory};
+
+    #[test]
+    fn mirrored_and_read_only() {
+        let mut mem = MemoryMap::new();
+        mem.add_device(Box::new(Rom::new(vec![1, 2, 3, 4], false)), vec![0..0x100, 0x1000..0x1004]);
+        mem.add_device_at(Box::new(Rom::new(vec![5, 6], true)), 0x2000..0x2002);
+        mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+        assert_eq!(mem.read16(0x2), [3, 4]);
+        assert_eq!(mem.read16(0x1002), [3, 4]);
+        // smaller than its range, so it repeats
+        assert_eq!(mem.read16(0xfe), [3, 4]);
+
+        assert!(mem.write(0xff, 0x1000));
+        assert_eq!(mem.read(0x1000), 1);
+        assert!(!mem.write16([0xff, 0xff], 0x2000));
+        assert!(!mem.write16([0xff, 0xff], 0x2001));
+        assert_eq!(mem.read16(0x2000), [5, 6]);
+        // ram around it is still writable
+        assert!(mem.write16([7, 8], 0x2002));
+        assert_eq!(mem.read16(0x2002), [7, 8]);
+    }
+}
@@ -1,16 +1,16 @@
-use std::alloc::{alloc, dealloc, Layout};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
 use std::ptr::null_mut;
 use super::Device;
 
 const BANK_SIZE: usize = 2usize.pow(16);
 const BANK_LAYOUT: Layout = Layout::new::<[u8; BANK_SIZE]>();
 
-struct RustMemory {
+pub struct RustMemory {
     init: bool,
     mem: *mut u8
 }
 impl RustMemory {
-    fn new() -> RustMemory {
+    pub fn new() -> RustMemory {
         RustMemory {
             init: false,
             mem: null_mut()
@@ -18,7 +18,7 @@ impl RustMemory {
     }
     fn init(&mut self) {
         unsafe {
-            let ptr = alloc(BANK_LAYOUT);
+            let ptr = alloc_zeroed(BANK_LAYOUT);
             self.mem = ptr
         }
         self.init = true
@@ -26,8 +26,10 @@ impl RustMemory {
 }
 impl Drop for RustMemory {
     fn drop(&mut self) {
-        unsafe {
-            dealloc(self.mem, BANK_LAYOUT)
+        if self.init {
+            unsafe {
+                dealloc(self.mem, BANK_LAYOUT)
+            }
         }
     }
 }
@@ -64,6 +66,7 @@ impl Device for RustMemory {
             }
         }
     }
+    fn can_interrupt(&self) -> bool { false }
 }
 
 #[cfg(test)]
@@ -0,0 +1,188 @@
+use super::{DevMsg, Device};
+
+/// the word the counter starts from, and goes back to in periodic mode. 0 counts 0x10000 ticks
+pub const RELOAD: u32 = 0;
+/// the word counting down to 0, once per tick
+pub const COUNTER: u32 = 2;
+/// the word saying how many cycles there are in a tick, less one
+pub const PRESCALER: u32 = 4;
+/// made of the control bits below
+pub const CONTROL: u32 = 6;
+/// made of the status bits below. writing a bit clears it
+pub const STATUS: u32 = 7;
+/// how much address space the registers take
+pub const SIZE: u32 = STATUS + 1;
+
+/// control: count down. one-shot timers clear this when they expire
+pub const ENABLE: u8 = 0x01;
+/// control: start again from reload on expiring, rather than stopping
+pub const PERIODIC: u8 = 0x02;
+/// control: irq on expiring
+pub const IRQ: u8 = 0x04;
+/// status: the counter has reached 0
+pub const EXPIRED: u8 = 0x01;
+
+/// a down counter for periodic interrupts, driven by the cycles it's clocked with.
+/// enabling it with the counter at 0 starts it from reload
+#[derive(Default)]
+pub struct Timer {
+    reload: u16,
+    /// up to 0x10000, which reads as 0
+    counter: u32,
+    prescaler: u16,
+    control: u8,
+    status: u8,
+    /// cycles towards the next tick
+    cycles: u64,
+}
+impl Timer {
+    pub fn new() -> Timer {
+        Timer::default()
+    }
+    fn period(&self) -> u32 {
+        if self.reload == 0 { 0x1_0000 } else { self.reload as u32 }
+    }
+    /// counts some ticks down, returning whether it expired. expiring more than once only counts once
+    fn tick(&mut self, ticks: u32) -> bool {
+        if ticks < self.counter {
+            self.counter -= ticks;
+            return false
+        }
+        if self.control & PERIODIC != 0 {
+            let period = self.period();
+            self.counter = period - (ticks - self.counter) % period;
+        }
+        else {
+            self.counter = 0;
+            self.control &= !ENABLE;
+        }
+        self.status |= EXPIRED;
+        true
+    }
+}
+
+impl Device for Timer {
+    fn write(&mut self, val: u8, offset: u32, _range: u32) {
+        let set_byte = |word: u16| {
+            let mut bytes = word.to_le_bytes();
+            bytes[offset as usize & 1] = val;
+            u16::from_le_bytes(bytes)
+        };
+        match offset {
+            o if o & !1 == RELOAD => self.reload = set_byte(self.reload),
+            o if o & !1 == COUNTER => self.counter = set_byte(self.counter as u16) as u32,
+            o if o & !1 == PRESCALER => self.prescaler = set_byte(self.prescaler),
+            CONTROL => {
+                if val & ENABLE != 0 && self.control & ENABLE == 0 && self.counter == 0 {
+                    self.counter = self.period();
+                }
+                if val & ENABLE == 0 {
+                    self.cycles = 0;
+                }
+                self.control = val & (ENABLE | PERIODIC | IRQ);
+            }
+            STATUS => self.status &= !val,
+            _ => (),
+        }
+    }
+    fn write16(&mut self, val: [u8; 2], offset: u32, range: u32) {
+        self.write(val[0], offset, range);
+        self.write(val[1], offset + 1, range);
+    }
+    fn read(&mut self, offset: u32, _range: u32) -> u8 {
+        let byte = |word: u16| word.to_le_bytes()[offset as usize & 1];
+        match offset {
+            o if o & !1 == RELOAD => byte(self.reload),
+            o if o & !1 == COUNTER => byte(self.counter as u16),
+            o if o & !1 == PRESCALER => byte(self.prescaler),
+            CONTROL => self.control,
+            STATUS => self.status,
+            _ => 0,
+        }
+    }
+    fn read16(&mut self, offset: u32, range: u32) -> [u8; 2] {
+        [self.read(offset, range), self.read(offset + 1, range)]
+    }
+    fn clock(&mut self, cycles: u32) -> DevMsg {
+        if self.control & ENABLE == 0 {
+            return DevMsg::None
+        }
+        let per_tick = self.prescaler as u64 + 1;
+        self.cycles += cycles as u64;
+        // what's left over is less than a tick, so the ticks still fit in a u32
+        let ticks = (self.cycles / per_tick) as u32;
+        self.cycles %= per_tick;
+        if ticks > 0 && self.tick(ticks) && self.control & IRQ != 0 {
+            DevMsg::Irq
+        }
+        else {
+            DevMsg::None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counter(t: &mut Timer) -> u16 {
+        u16::from_le_bytes(t.read16(COUNTER, 0))
+    }
+
+    #[test]
+    fn periodic() {
+        let mut t = Timer::new();
+        t.write16(10u16.to_le_bytes(), RELOAD, 0);
+        t.write16(1u16.to_le_bytes(), PRESCALER, 0); // a tick every 2 cycles
+        t.write(ENABLE | PERIODIC | IRQ, CONTROL, 0);
+        assert_eq!(counter(&mut t), 10);
+
+        assert!(matches!(t.clock(3), DevMsg::None));
+        assert_eq!(counter(&mut t), 9);
+        assert!(matches!(t.clock(16), DevMsg::None));
+        assert_eq!(counter(&mut t), 1);
+        assert!(matches!(t.clock(1), DevMsg::Irq));
+        assert_eq!(counter(&mut t), 10);
+        assert_eq!(t.read(STATUS, 0), EXPIRED);
+        t.write(EXPIRED, STATUS, 0);
+        assert_eq!(t.read(STATUS, 0), 0);
+
+        // expiring more than once in a clock is one irq
+        assert!(matches!(t.clock(50), DevMsg::Irq));
+        assert_eq!(counter(&mut t), 5);
+
+        // leftover cycles don't overflow a long clock
+        t.clock(1);
+        t.clock(u32::MAX);
+        assert_eq!(counter(&mut t), 7);
+    }
+
+    #[test]
+    fn one_shot() {
+        let mut t = Timer::new();
+        t.write16(3u16.to_le_bytes(), RELOAD, 0);
+        t.write(ENABLE, CONTROL, 0);
+        // no irq without IRQ set, but it still expires
+        assert!(matches!(t.clock(5), DevMsg::None));
+        assert_eq!((counter(&mut t), t.read(CONTROL, 0), t.read(STATUS, 0)), (0, 0, EXPIRED));
+        assert!(matches!(t.clock(5), DevMsg::None));
+
+        // the counter can be set directly, rather than starting from reload
+        t.write16(2u16.to_le_bytes(), COUNTER, 0);
+        t.write(ENABLE | IRQ, CONTROL, 0);
+        assert!(matches!(t.clock(1), DevMsg::None));
+        assert!(matches!(t.clock(1), DevMsg::Irq));
+        assert_eq!(t.read(CONTROL, 0), IRQ);
+    }
+
+    #[test]
+    fn zero_reload_is_the_longest_period() {
+        let mut t = Timer::new();
+        t.write(ENABLE | PERIODIC, CONTROL, 0);
+        assert_eq!(counter(&mut t), 0);
+        assert!(matches!(t.clock(0xffff), DevMsg::None));
+        assert_eq!(counter(&mut t), 1);
+        t.clock(1);
+        assert_eq!((counter(&mut t), t.read(STATUS, 0)), (0, EXPIRED));
+    }
+}
@@ -0,0 +1,181 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver};
+use super::{DevMsg, Device};
+
+/// reading takes the next received byte, writing sends one
+pub const DATA: u32 = 0;
+/// read only, made of the status bits below
+pub const STATUS: u32 = 1;
+/// which interrupts are enabled, made of the control bits below
+pub const CONTROL: u32 = 2;
+/// how much address space the registers take
+pub const SIZE: u32 = CONTROL + 1;
+
+/// status: there's a received byte to read
+pub const RX_READY: u8 = 0x01;
+/// status: another byte can be sent. sending is instant, so this is always set
+pub const TX_EMPTY: u8 = 0x02;
+/// control: irq when bytes are received, and when this is enabled with some waiting
+pub const RX_IRQ: u8 = 0x01;
+/// control: irq when a byte has been sent, and when this is enabled
+pub const TX_IRQ: u8 = 0x02;
+
+/// received bytes wait here until they're read, and the rest wait on the host side
+const FIFO_SIZE: usize = 16;
+
+/// where received bytes come from
+pub enum UartInput {
+    /// read on another thread, so waiting for them doesn't block the processor
+    Stdin,
+    Buffer(Vec<u8>),
+}
+/// where sent bytes go
+pub enum UartOutput {
+    Stdout,
+    Capture(Rc<RefCell<Vec<u8>>>),
+}
+
+enum Source {
+    Host(Receiver<u8>),
+    Buffer(VecDeque<u8>),
+}
+
+/// a serial port for a console, with registers at DATA, STATUS and CONTROL
+pub struct Uart {
+    source: Source,
+    output: UartOutput,
+    rx: VecDeque<u8>,
+    control: u8,
+    /// an irq to raise on the next clock
+    irq: bool,
+}
+impl Uart {
+    pub fn new(input: UartInput, output: UartOutput) -> Uart {
+        let source = match input {
+            UartInput::Stdin => {
+                let (tx, rx) = mpsc::sync_channel(FIFO_SIZE);
+                std::thread::spawn(move || {
+                    for b in std::io::stdin().lock().bytes() {
+                        match b {
+                            Ok(b) if tx.send(b).is_ok() => (),
+                            _ => break,
+                        }
+                    }
+                });
+                Source::Host(rx)
+            }
+            UartInput::Buffer(data) => Source::Buffer(data.into()),
+        };
+        Uart { source, output, rx: VecDeque::new(), control: 0, irq: false }
+    }
+    fn status(&self) -> u8 {
+        TX_EMPTY | if self.rx.is_empty() { 0 } else { RX_READY }
+    }
+    fn send(&mut self, val: u8) {
+        match &self.output {
+            UartOutput::Stdout => {
+                let mut out = std::io::stdout();
+                // the guest decides when lines end, so nothing waits for one
+                let _ = out.write_all(&[val]).and_then(|_| out.flush());
+            }
+            UartOutput::Capture(buf) => buf.borrow_mut().push(val),
+        }
+        self.irq |= self.control & TX_IRQ != 0;
+    }
+}
+
+impl Device for Uart {
+    fn write(&mut self, val: u8, offset: u32, _range: u32) {
+        match offset {
+            DATA => self.send(val),
+            CONTROL => {
+                // each irq's control bit is the same as the status bit it's for
+                let enabled = val & !self.control;
+                self.irq |= enabled & self.status() & (RX_IRQ | TX_IRQ) != 0;
+                self.control = val & (RX_IRQ | TX_IRQ);
+            }
+            _ => (),
+        }
+    }
+    fn write16(&mut self, val: [u8; 2], offset: u32, range: u32) {
+        self.write(val[0], offset, range);
+        self.write(val[1], offset + 1, range);
+    }
+    fn read(&mut self, offset: u32, _range: u32) -> u8 {
+        match offset {
+            DATA => self.rx.pop_front().unwrap_or(0),
+            STATUS => self.status(),
+            CONTROL => self.control,
+            _ => 0,
+        }
+    }
+    fn read16(&mut self, offset: u32, range: u32) -> [u8; 2] {
+        [self.read(offset, range), self.read(offset + 1, range)]
+    }
+    fn clock(&mut self, _cycles: u32) -> DevMsg {
+        let mut received = false;
+        while self.rx.len() < FIFO_SIZE {
+            let b = match &mut self.source {
+                Source::Host(rx) => rx.try_recv().ok(),
+                Source::Buffer(data) => data.pop_front(),
+            };
+            match b {
+                Some(b) => self.rx.push_back(b),
+                None => break,
+            }
+            received = true;
+        }
+        self.irq |= received && self.control & RX_IRQ != 0;
+        if std::mem::take(&mut self.irq) { DevMsg::Irq } else { DevMsg::None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_and_receive() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let input = (0..20).collect();
+        let mut uart = Uart::new(UartInput::Buffer(input), UartOutput::Capture(output.clone()));
+        assert_eq!(uart.read(STATUS, 0), TX_EMPTY);
+        assert!(matches!(uart.clock(1), DevMsg::None));
+        assert_eq!(uart.read(STATUS, 0), TX_EMPTY | RX_READY);
+
+        // the fifo only holds some of it, and the rest comes in as there's room
+        let received: Vec<u8> = (0..FIFO_SIZE).map(|_| uart.read(DATA, 0)).collect();
+        assert_eq!(received, (0..FIFO_SIZE as u8).collect::<Vec<_>>());
+        assert_eq!(uart.read(STATUS, 0), TX_EMPTY);
+        uart.clock(1);
+        assert_eq!(uart.read16(DATA, 0), [16, TX_EMPTY | RX_READY]);
+
+        uart.write16([b'h', 0], DATA, 0);
+        uart.write(b'i', DATA, 0);
+        assert_eq!(*output.borrow(), b"hi");
+    }
+
+    #[test]
+    fn irqs() {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let mut uart = Uart::new(UartInput::Buffer(vec![1, 2]), UartOutput::Capture(output));
+        uart.write(RX_IRQ, CONTROL, 0);
+        assert!(matches!(uart.clock(1), DevMsg::Irq));
+        assert!(matches!(uart.clock(1), DevMsg::None));
+        // enabling rx irqs with bytes already waiting raises one too
+        uart.write(0, CONTROL, 0);
+        uart.write(RX_IRQ, CONTROL, 0);
+        assert!(matches!(uart.clock(1), DevMsg::Irq));
+
+        // enabling tx irqs raises one straight away, since it's already empty
+        uart.write(RX_IRQ | TX_IRQ, CONTROL, 0);
+        assert!(matches!(uart.clock(1), DevMsg::Irq));
+        assert!(matches!(uart.clock(1), DevMsg::None));
+        uart.write(b'x', DATA, 0);
+        assert!(matches!(uart.clock(1), DevMsg::Irq));
+        assert_eq!(uart.read(CONTROL, 0), RX_IRQ | TX_IRQ);
+    }
+}
@@ -0,0 +1,65 @@
+use std::rc::Rc;
+use crate::memory::{MemoryMap, PAGE_BITS};
+use super::Operand;
+
+pub struct Decoded {
+    pub instruction: u8,
+    pub operands: Vec<Operand>,
+    /// bytes taken up by the instruction and its operands
+    pub len: u32,
+    /// bus cycles spent fetching it
+    pub fetch_cycles: u32,
+}
+impl Decoded {
+    /// the pages holding the instruction, and the byte after it that ended the operand list
+    pub fn pages(&self, flat_pc: u32) -> std::ops::RangeInclusive<u32> {
+        (flat_pc >> PAGE_BITS)..=(flat_pc.wrapping_add(self.len) >> PAGE_BITS)
+    }
+}
+
+/// number of entries in the cache. must be a power of 2
+const SLOTS: usize = 4096;
+
+/// decoded instructions, direct mapped by flat pc and tagged with whether they were decoded in bc32 mode.  
+/// the pages they were decoded from are watched, so that writes to them can invalidate them
+pub struct DecodeCache {
+    enabled: bool,
+    entries: Vec<Option<(u32, bool, Rc<Decoded>)>>,
+}
+impl Default for DecodeCache {
+    fn default() -> DecodeCache {
+        DecodeCache {
+            enabled: true,
+            entries: vec![None; SLOTS],
+        }
+    }
+}
+impl DecodeCache {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.entries.fill(None);
+    }
+    pub fn get(&self, flat_pc: u32, mode32: bool) -> Option<Rc<Decoded>> {
+        match &self.entries[flat_pc as usize & (SLOTS - 1)] {
+            Some((pc, m, d)) if *pc == flat_pc && *m == mode32 => Some(d.clone()),
+            _ => None
+        }
+    }
+    pub fn insert(&mut self, flat_pc: u32, mode32: bool, decoded: Rc<Decoded>, mem: &mut MemoryMap) {
+        if !self.enabled {
+            return
+        }
+        for page in decoded.pages(flat_pc) {
+            mem.watch_page(page)
+        }
+        self.entries[flat_pc as usize & (SLOTS - 1)] = Some((flat_pc, mode32, decoded));
+    }
+    /// drops everything decoded from a page
+    pub fn invalidate(&mut self, page: u32) {
+        for e in self.entries.iter_mut() {
+            if matches!(e, Some((pc, _, d)) if d.pages(*pc).contains(&page)) {
+                *e = None
+            }
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//! runs the instruction test vectors in conformance/, which are written against the design documents
+//!
+//! each vector starts with `test <name>`, followed by any of
+//! - `set reg=val ...` sets registers before running
+//! - `mem addr byte ...` fills memory before running
+//! - `code byte ...` the code to run, loaded at pc (0x100 unless set). a nop is added after it
+//! - `steps n` how many times to clock the processor, 1 by default
+//! - `expect reg=val ...` registers after running. any not listed must be unchanged, apart from pc which
+//!   defaults to the end of the code
+//! - `expect mem addr byte ...` memory after running
+//! - `expect fault vector` the code faulted, so pc is at the handler and the stack holds the interrupt frame
+//! - `expect cycles n` cycles taken over every step
+//! - `expect halted` or `expect waiting`
+//!
+//! registers are a b c d sp bp si di rp ro co do eo so idtp idtl pc flags, and are set and compared whole.
+//! values are hex with 0x, negative or positive decimal, or flag names joined with | (C N O Z T DSEG USER M32).
+//! bytes are hex without 0x.
+//! the idt has 16 entries at 0x1000, and vector n jumps to 0x2000 + n. sp starts at 0x400
+
+use super::*;
+use crate::memory::RustMemory;
+
+const VECTORS: &[(&str, &str)] = &[
+    ("mov.txt", include_str!("conformance/mov.txt")),
+    ("memory.txt", include_str!("conformance/memory.txt")),
+    ("jumps.txt", include_str!("conformance/jumps.txt")),
+    ("arithmetic.txt", include_str!("conformance/arithmetic.txt")),
+    ("logic.txt", include_str!("conformance/logic.txt")),
+    ("block.txt", include_str!("conformance/block.txt")),
+    ("system.txt", include_str!("conformance/system.txt")),
+];
+
+/// every opcode in the design documents that's implemented (so not calls and returns yet).
+/// each has to start the code of at least one vector
+const OPCODES: &[u8] = &[
+    0x80, 0x81, 0x84, 0x85, 0x86,
+    0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e, 0x8f,
+    0x90, 0x91,
+    0x98, 0x99, 0x9a, 0x9b, 0x9c, 0x9d, 0x9e,
+    0xa0, 0xa1, 0xb0, 0xb1,
+    0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8,
+    0xd0, 0xd1, 0xd2, 0xd3, 0xd8, 0xd9, 0xda, 0xdc, 0xdd, 0xde, 0xdf,
+    0xe8, 0xe9, 0xea, 0xeb, 0xec, 0xed, 0xee, 0xef,
+    0xf0, 0xf2, 0xf3, 0xf4, 0xf5,
+];
+
+const REGISTERS: &[&str] = &[
+    "a", "b", "c", "d", "sp", "bp", "si", "di", "rp", "ro",
+    "co", "do", "eo", "so", "idtp", "idtl", "pc", "flags",
+];
+
+const IDT: u32 = 0x1000;
+const HANDLERS: u32 = 0x2000;
+
+struct Vector {
+    name: String,
+    /// where it was declared, for reporting
+    file: &'static str,
+    line: usize,
+    set: Vec<(String, u32)>,
+    mem: Vec<(u32, Vec<u8>)>,
+    code: Vec<u8>,
+    steps: u32,
+    expect: Vec<(String, u32)>,
+    expect_mem: Vec<(u32, Vec<u8>)>,
+    fault: Option<u32>,
+    cycles: Option<u64>,
+    state: State,
+}
+
+fn parse(file: &'static str, text: &str) -> Vec<Vector> {
+    let mut vectors: Vec<Vector> = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        let mut words = line.split_whitespace();
+        let directive = match words.next() {
+            Some(d) => d,
+            None => continue,
+        };
+        let at = format!("{}:{}", file, i + 1);
+        if directive == "test" {
+            vectors.push(Vector {
+                name: words.collect::<Vec<_>>().join(" "),
+                file,
+                line: i + 1,
+                set: Vec::new(),
+                mem: Vec::new(),
+                code: Vec::new(),
+                steps: 1,
+                expect: Vec::new(),
+                expect_mem: Vec::new(),
+                fault: None,
+                cycles: None,
+                state: State::Running,
+            });
+            continue
+        }
+        let v = vectors.last_mut().unwrap_or_else(|| panic!("{}: {} outside of a test", at, directive));
+        let args: Vec<&str> = words.collect();
+        match (directive, args.as_slice()) {
+            ("set", regs) => v.set.extend(regs.iter().map(|r| assignment(&at, r))),
+            ("mem", [addr, bytes @ ..]) => v.mem.push((value(&at, addr), hex_bytes(&at, bytes))),
+            ("code", bytes) => v.code.extend(hex_bytes(&at, bytes)),
+            ("steps", [n]) => v.steps = value(&at, n),
+            ("expect", ["mem", addr, bytes @ ..]) => v.expect_mem.push((value(&at, addr), hex_bytes(&at, bytes))),
+            ("expect", ["fault", vector]) => v.fault = Some(value(&at, vector)),
+            ("expect", ["cycles", n]) => v.cycles = Some(value(&at, n) as u64),
+            ("expect", ["halted"]) => v.state = State::Halted,
+            ("expect", ["waiting"]) => v.state = State::Waiting,
+            ("expect", regs) => v.expect.extend(regs.iter().map(|r| assignment(&at, r))),
+            _ => panic!("{}: can't parse {:?}", at, line),
+        }
+    }
+    vectors
+}
+
+fn assignment(at: &str, s: &str) -> (String, u32) {
+    match s.split_once('=') {
+        Some((reg, val)) if REGISTERS.contains(&reg) => (reg.to_string(), value(at, val)),
+        _ => panic!("{}: bad register assignment {:?}", at, s),
+    }
+}
+
+fn value(at: &str, s: &str) -> u32 {
+    let parsed = if let Some(hex) = s.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16).ok()
+    }
+    else if let Some(neg) = s.strip_prefix('-') {
+        neg.parse::<u32>().ok().map(|n| n.wrapping_neg())
+    }
+    else if s.starts_with(|c: char| c.is_ascii_digit()) {
+        s.parse().ok()
+    }
+    else {
+        s.split('|').map(flag).sum()
+    };
+    parsed.unwrap_or_else(|| panic!("{}: bad value {:?}", at, s))
+}
+
+fn flag(name: &str) -> Option<u32> {
+    Some(match name {
+        "C" => CARRY_MASK,
+        "N" => NEGATIVE_MASK,
+        "O" => OVERFLOW_MASK,
+        "Z" => ZERO_MASK,
+        "T" => TEST_MASK,
+        "DSEG" => DSEG_MASK,
+        "USER" => 0x80,
+        "M32" => MODE32_MASK,
+        _ => return None
+    })
+}
+
+fn hex_bytes(at: &str, bytes: &[&str]) -> Vec<u8> {
+    bytes.iter()
+        .map(|b| u8::from_str_radix(b, 16).unwrap_or_else(|_| panic!("{}: bad byte {:?}", at, b)))
+        .collect()
+}
+
+fn register(p: &Processor, name: &str) -> u32 {
+    match name {
+        "a" => p.xa, "b" => p.xb, "c" => p.xc, "d" => p.xd,
+        "sp" => p.xsp, "bp" => p.xbp, "si" => p.xsi, "di" => p.xdi, "rp" => p.xrp, "ro" => p.ro as u32,
+        "co" => p.co as u32, "do" => p.do_ as u32, "eo" => p.eo as u32, "so" => p.so as u32,
+        "idtp" => p.xidtp, "idtl" => p.xidtl, "pc" => p.xpc, "flags" => p.xflags,
+        _ => unreachable!()
+    }
+}
+
+fn set_register(p: &mut Processor, name: &str, val: u32) {
+    match name {
+        "a" => p.xa = val, "b" => p.xb = val, "c" => p.xc = val, "d" => p.xd = val,
+        "sp" => p.xsp = val, "bp" => p.xbp = val, "si" => p.xsi = val, "di" => p.xdi = val,
+        "rp" => p.xrp = val, "ro" => p.ro = val as u16,
+        "co" => p.co = val as u16, "do" => p.do_ = val as u16, "eo" => p.eo = val as u16, "so" => p.so = val as u16,
+        "idtp" => p.xidtp = val, "idtl" => p.xidtl = val, "pc" => p.xpc = val, "flags" => p.xflags = val,
+        _ => unreachable!()
+    }
+}
+
+/// runs a vector, returning everything that didn't match
+fn run(v: &Vector) -> Vec<String> {
+    let mut p = Processor::default();
+    let mut mem = MemoryMap::new();
+    mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+    mem.add_device_at(Box::new(RustMemory::new()), 0x1_0000..0x2_0000);
+    p.xidtp = IDT;
+    p.xidtl = 16;
+    for n in 0..16 {
+        mem.write16((HANDLERS as u16 + n as u16).to_le_bytes(), IDT + n * 4);
+        mem.write(0x85, HANDLERS + n);
+    }
+    p.xsp = 0x400;
+    p.xpc = 0x100;
+    for (reg, val) in &v.set {
+        set_register(&mut p, reg, *val);
+    }
+    for (addr, bytes) in &v.mem {
+        for (i, b) in bytes.iter().enumerate() {
+            mem.write(*b, addr + i as u32);
+        }
+    }
+    let start = p.get_flat_pc();
+    for (i, b) in v.code.iter().chain(&[0x85]).enumerate() {
+        mem.write(*b, start + i as u32);
+    }
+
+    let mut expected: Vec<(&str, u32)> = REGISTERS.iter().map(|r| (*r, register(&p, r))).collect();
+    let mut expect = |name: &str, val: u32| expected.iter_mut().find(|(r, _)| *r == name).unwrap().1 = val;
+    expect("pc", p.xpc.wrapping_add(v.code.len() as u32));
+    if let Some(vector) = v.fault {
+        let frame = if p.is_mode32() { 8 } else { 6 };
+        expect("pc", HANDLERS + vector);
+        expect("sp", p.xsp.wrapping_sub(frame));
+        expect("co", 0);
+        expect("flags", p.xflags & !(PRIV_MASK | TEST_MASK));
+    }
+    for (reg, val) in &v.expect {
+        expect(reg, *val);
+    }
+
+    let mut cycles = 0;
+    for _ in 0..v.steps {
+        cycles += p.clock(&mut mem) as u64;
+    }
+
+    let mut mismatches = Vec::new();
+    for (reg, val) in expected {
+        let actual = register(&p, reg);
+        if actual != val {
+            mismatches.push(format!("{} is {:#x}, expected {:#x}", reg, actual, val));
+        }
+    }
+    for (addr, bytes) in &v.expect_mem {
+        let actual: Vec<u8> = (0..bytes.len() as u32).map(|i| mem.read(addr + i)).collect();
+        if actual != *bytes {
+            mismatches.push(format!("memory at {:#x} is {:02x?}, expected {:02x?}", addr, actual, bytes));
+        }
+    }
+    if let Some(c) = v.cycles {
+        if c != cycles {
+            mismatches.push(format!("took {} cycles, expected {}", cycles, c));
+        }
+    }
+    if p.state() != v.state {
+        mismatches.push(format!("state is {:?}, expected {:?}", p.state(), v.state));
+    }
+    mismatches
+}
+
+#[test]
+fn conformance() {
+    let vectors: Vec<Vector> = VECTORS.iter().flat_map(|(file, text)| parse(file, text)).collect();
+    let mut failures = Vec::new();
+    for v in &vectors {
+        for m in run(v) {
+            failures.push(format!("{}:{} {}: {}", v.file, v.line, v.name, m));
+        }
+    }
+    assert!(failures.is_empty(), "{} mismatches:\n{}", failures.len(), failures.join("\n"));
+
+    let missing: Vec<String> = OPCODES.iter()
+        .filter(|op| !vectors.iter().any(|v| v.code.first() == Some(op)))
+        .map(|op| format!("{:#04x}", op))
+        .collect();
+    assert!(missing.is_empty(), "no vectors for {}", missing.join(", "));
+}
@@ -1,3 +1,6 @@
+// the full register map, not all of which is referred to by name
+#![allow(dead_code, clippy::upper_case_acronyms)]
+
 pub const GPR_MASK: u8 = 0b1100;
 pub const GPR_SEL_MASK: u8 = 0b11;
 pub const PTR_MASK: u8 = 0b1110;
@@ -13,6 +16,8 @@ pub const TEST_MASK: u32     = 0b0000_0000_0001_0000;
 pub const DSEG_MASK: u32     = 0b0000_0000_0010_0000;
 pub const PRIV_MASK: u32     = 0b0000_0000_1100_0000;
 pub const MODE32_MASK: u32   = 0b0000_0001_0000_0000;
+/// hardware irqs are only taken while it's set. nmis always are
+pub const IRQ_MASK: u32      = 0b0000_0010_0000_0000;
 
 pub enum GPRs {
     A	= 0x00,
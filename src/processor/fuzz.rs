@@ -0,0 +1,485 @@
+//! differential fuzzing of the execution core. random register states and instruction streams are run through the
+//! interpreter an instruction at a time, and compared against a reference model written from the design documents
+//! with plain integer maths rather than RegVal.
+//!
+//! the model covers the instructions that don't touch memory (mov, movsx, swr, jumps, arithmetic, logic, shifts
+//! and the test prefix) over the general purpose and pointer registers, along with the faults they raise.
+//! a stream ends at its first fault or taken jump.
+//!
+//! every stream has its own seed, so a failure can be rerun alone with BCPU_FUZZ_SEED=seed BCPU_FUZZ_STREAMS=1.
+//! raise BCPU_FUZZ_STREAMS (and use --release) to fuzz for longer
+
+use super::*;
+use crate::memory::RustMemory;
+use crate::utils::Rng;
+
+const CODE: u32 = 0x100;
+const IDT: u32 = 0x1000;
+const HANDLERS: u32 = 0x2000;
+const STACK: u32 = 0x400;
+const STREAM_LEN: usize = 8;
+const DEFAULT_SEED: u64 = 0xbc9;
+const DEFAULT_STREAMS: u64 = 3000;
+
+const OPCODES: &[u8] = &[
+    0x80, 0x81, 0x85,
+    0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e, 0x8f, 0x98, 0x99, 0x9a, 0x9b, 0x9c, 0x9d, 0x9e,
+    0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7, 0xc8,
+    0xd0, 0xd1, 0xd2, 0xd3, 0xd8, 0xd9, 0xda, 0xdc, 0xdd, 0xde, 0xdf,
+    0xf0,
+];
+const INVALID_OPERATION: u32 = 0;
+const ILLEGAL_OPERATION: u32 = 1;
+const DIVIDE_BY_ZERO: u32 = 2;
+const DIVISION_OVERFLOW: u32 = 6;
+
+#[derive(Clone, Copy, Debug)]
+enum Arg {
+    Reg(u8),
+    /// bits and value
+    Const(u32, u32),
+}
+
+struct Insn {
+    opcode: u8,
+    args: Vec<Arg>,
+}
+impl Insn {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![self.opcode];
+        for a in &self.args {
+            match *a {
+                Arg::Reg(r) => bytes.push(r),
+                Arg::Const(8, v) => bytes.extend([0x70, v as u8]),
+                Arg::Const(16, v) => bytes.extend([0x71, v as u8, (v >> 8) as u8]),
+                Arg::Const(_, v) => { bytes.push(0x72); bytes.extend(v.to_le_bytes()) }
+            }
+        }
+        bytes
+    }
+}
+
+/// everything the model can change
+#[derive(Clone, Debug, PartialEq)]
+struct Regs {
+    gprs: [u32; 4],
+    /// sp, bp, si, di and rp
+    ptrs: [u32; 5],
+    ro: u16,
+    pc: u32,
+    flags: u32,
+}
+impl Regs {
+    fn of(p: &Processor) -> Regs {
+        Regs {
+            gprs: [p.xa, p.xb, p.xc, p.xd],
+            ptrs: [p.xsp, p.xbp, p.xsi, p.xdi, p.xrp],
+            ro: p.ro,
+            pc: p.xpc,
+            flags: p.xflags,
+        }
+    }
+}
+
+fn mask(bits: u32) -> u32 {
+    if bits == 32 { u32::MAX } else { (1 << bits) - 1 }
+}
+fn sext(v: u32, bits: u32) -> i64 {
+    let shift = 64 - bits;
+    ((v as i64) << shift) >> shift
+}
+fn top(v: u32, bits: u32) -> bool {
+    (v >> (bits - 1)) & 1 != 0
+}
+fn fits(v: i128, bits: u32) -> bool {
+    v >= -(1 << (bits - 1)) && v < 1 << (bits - 1)
+}
+
+struct Model {
+    regs: Regs,
+}
+impl Model {
+    fn mode32(&self) -> bool {
+        self.regs.flags & MODE32_MASK != 0
+    }
+    fn testing(&self) -> bool {
+        self.regs.flags & TEST_MASK != 0
+    }
+    fn pointer_bits(&self) -> u32 {
+        if self.mode32() { 32 } else { 16 }
+    }
+
+    /// the size of a register in bits, with the gprs split into word, dword, low byte and high byte views
+    fn width(id: u8) -> Option<u32> {
+        match id {
+            0x00..0x10 => Some([16, 32, 8, 8][id as usize & 3]),
+            0x10..0x1a => Some([16, 32][id as usize & 1]),
+            0x1a => Some(16),
+            _ => None
+        }
+    }
+    fn get(&self, id: u8) -> u32 {
+        match id {
+            0x00..0x10 => {
+                let v = self.regs.gprs[id as usize >> 2];
+                [v & 0xffff, v, v & 0xff, (v >> 8) & 0xff][id as usize & 3]
+            }
+            0x10..0x1a => {
+                let v = self.regs.ptrs[(id as usize - 0x10) >> 1];
+                if id & 1 == 0 { v & 0xffff } else { v }
+            }
+            _ => self.regs.ro as u32,
+        }
+    }
+    fn set(&mut self, id: u8, val: u32) {
+        let merge = |old: u32, keep: u32, new: u32| (old & keep) | (new & !keep);
+        match id {
+            0x00..0x10 => {
+                let r = &mut self.regs.gprs[id as usize >> 2];
+                *r = match id & 3 {
+                    0 => merge(*r, 0xffff_0000, val),
+                    1 => val,
+                    2 => merge(*r, 0xffff_ff00, val),
+                    _ => merge(*r, 0xffff_00ff, val << 8),
+                }
+            }
+            0x10..0x1a => {
+                let r = &mut self.regs.ptrs[(id as usize - 0x10) >> 1];
+                *r = if id & 1 == 0 { merge(*r, 0xffff_0000, val) } else { val }
+            }
+            _ => self.regs.ro = val as u16,
+        }
+    }
+    /// writes a result, unless the instruction is being tested
+    fn write(&mut self, arg: Arg, val: u32) {
+        if let (Arg::Reg(id), false) = (arg, self.testing()) {
+            self.set(id, val)
+        }
+    }
+    /// an operand's size and value
+    fn value(&self, arg: Arg) -> std::result::Result<(u32, u32), u32> {
+        match arg {
+            Arg::Const(bits, v) => Ok((bits, v)),
+            Arg::Reg(id) => Model::width(id).map(|bits| (bits, self.get(id))).ok_or(INVALID_OPERATION),
+        }
+    }
+    fn flags(&mut self, carry: bool, negative: bool, overflow: bool, zero: bool) {
+        let flags = carry as u32 | (negative as u32) << 1 | (overflow as u32) << 2 | (zero as u32) << 3;
+        self.regs.flags = (self.regs.flags & !0xf) | flags;
+    }
+    /// sets the flags for a result, with negative and zero coming from the result itself
+    fn set_flags(&mut self, carry: bool, overflow: bool, res: u32, bits: u32) {
+        self.flags(carry, top(res, bits), overflow, res == 0)
+    }
+    fn flag(&self, mask: u32) -> bool {
+        self.regs.flags & mask != 0
+    }
+
+    /// runs an instruction, returning the vector of the fault it raises
+    fn step(&mut self, insn: &Insn, len: u32) -> std::result::Result<(), u32> {
+        if !self.mode32() && insn.args.iter().any(|a| matches!(a, Arg::Const(32, _))) {
+            return Err(INVALID_OPERATION)
+        }
+        let pc_mask = mask(self.pointer_bits());
+        self.regs.pc = (self.regs.pc & !pc_mask) | (self.regs.pc.wrapping_add(len) & pc_mask);
+        if insn.opcode == 0xf0 {
+            self.regs.flags |= TEST_MASK;
+            return Ok(())
+        }
+        let res = self.execute(insn);
+        self.regs.flags &= !TEST_MASK;
+        res
+    }
+    fn execute(&mut self, insn: &Insn) -> std::result::Result<(), u32> {
+        let arg = |i: usize, default: u8| insn.args.get(i).copied().unwrap_or(Arg::Reg(default));
+        let is_const = |a: Arg| matches!(a, Arg::Const(..));
+        let op = insn.opcode;
+        match op {
+            0x80 | 0x81 => {
+                let (src, dest) = (arg(0, 0x00), arg(1, 0x00));
+                if is_const(dest) {
+                    return Err(INVALID_OPERATION)
+                }
+                let (bits, v) = self.value(src)?;
+                let Arg::Reg(id) = dest else { unreachable!() };
+                let dest_bits = Model::width(id).ok_or(INVALID_OPERATION)?;
+                if bits > dest_bits {
+                    return Err(INVALID_OPERATION)
+                }
+                let v = if op == 0x81 { sext(v, bits) as u32 & mask(dest_bits) } else { v };
+                self.write(dest, v);
+            }
+            0x85 => {
+                let (src, dest) = (arg(0, 0x00), arg(1, 0x00));
+                if is_const(src) || is_const(dest) {
+                    return Err(INVALID_OPERATION)
+                }
+                let (src_bits, src_v) = self.value(src)?;
+                let (dest_bits, dest_v) = self.value(dest)?;
+                if src_bits != dest_bits {
+                    return Err(INVALID_OPERATION)
+                }
+                self.write(dest, src_v);
+                self.write(src, dest_v);
+            }
+            0x88..=0x8f | 0x98..=0x9e => {
+                if self.testing() {
+                    return Err(ILLEGAL_OPERATION)
+                }
+                let (c, n, o, z) = (self.flag(CARRY_MASK), self.flag(NEGATIVE_MASK), self.flag(OVERFLOW_MASK), self.flag(ZERO_MASK));
+                let taken = match op {
+                    0x88 => true,
+                    0x89 => z, 0x8a => !z,
+                    0x8b => c, 0x8c => !c,
+                    0x8d => n, 0x8e => !n,
+                    0x8f => o, 0x98 => !o,
+                    0x99 => c && !z, 0x9a => !c || z,
+                    0x9b => n != o, 0x9c => n == o,
+                    0x9d => !z && n == o, _ => z || n != o,
+                };
+                if taken {
+                    let (bits, target) = self.value(arg(0, 0x00))?;
+                    if bits > self.pointer_bits() {
+                        return Err(INVALID_OPERATION)
+                    }
+                    let pc_mask = mask(self.pointer_bits());
+                    self.regs.pc = (self.regs.pc & !pc_mask) | target;
+                }
+            }
+            0xc0..=0xc3 => {
+                let (base, other) = (arg(0, 0x00), arg(1, 0x04));
+                let (bits, a) = self.value(base)?;
+                let (b_bits, b) = self.value(other)?;
+                if bits != b_bits {
+                    return Err(INVALID_OPERATION)
+                }
+                let m = mask(bits) as i128;
+                // the flags come from the whole product, or the quotient
+                let (lo, hi, overflow, negative, zero) = match op {
+                    0xc0 | 0xc1 => {
+                        let product = if op == 0xc0 {
+                            a as i128 * b as i128
+                        }
+                        else {
+                            sext(a, bits) as i128 * sext(b, bits) as i128
+                        };
+                        let overflow = if op == 0xc0 { product > m } else { !fits(product, bits) };
+                        let hi = ((product >> bits) & m) as u32;
+                        ((product & m) as u32, hi, overflow, top(hi, bits), product == 0)
+                    }
+                    _ => {
+                        let (a, b) = if op == 0xc2 { (a as i128, b as i128) } else { (sext(a, bits) as i128, sext(b, bits) as i128) };
+                        if b == 0 {
+                            return Err(DIVIDE_BY_ZERO)
+                        }
+                        let quot = a / b;
+                        if op == 0xc3 && !fits(quot, bits) {
+                            return Err(DIVISION_OVERFLOW)
+                        }
+                        let quot = (quot & m) as u32;
+                        (quot, ((a % b) & m) as u32, false, top(quot, bits), quot == 0)
+                    }
+                };
+                if is_const(base) || is_const(other) {
+                    return Err(INVALID_OPERATION)
+                }
+                self.write(base, lo);
+                self.write(other, hi);
+                self.flags(false, negative, overflow, zero);
+            }
+            0xc4..=0xc8 => {
+                let (lhs, rhs) = if op == 0xc8 { (arg(0, 0x00), arg(1, 0x04)) } else { (Arg::Reg(0x00), arg(0, 0x04)) };
+                let (bits, a) = self.value(lhs)?;
+                let (b_bits, b) = self.value(rhs)?;
+                if bits != b_bits {
+                    return Err(INVALID_OPERATION)
+                }
+                let carry_in = self.flag(CARRY_MASK) as i64;
+                let (wide, signed) = match op {
+                    0xc4 => (a as i64 + b as i64, sext(a, bits) + sext(b, bits)),
+                    0xc5 => (a as i64 + b as i64 + carry_in, sext(a, bits) + sext(b, bits) + carry_in),
+                    0xc7 => (a as i64 - b as i64 - (1 - carry_in), sext(a, bits) - sext(b, bits) - (1 - carry_in)),
+                    _ => (a as i64 - b as i64, sext(a, bits) - sext(b, bits)),
+                };
+                let res = wide as u32 & mask(bits);
+                // carry out of an add, or no borrow out of a sub
+                let carry = if op < 0xc6 { wide > mask(bits) as i64 } else { wide >= 0 };
+                if op != 0xc8 {
+                    self.write(lhs, res);
+                }
+                self.set_flags(carry, !fits(signed as i128, bits), res, bits);
+            }
+            0xd0..=0xd2 => {
+                let (base, other) = (arg(0, 0x00), arg(1, 0x04));
+                let (bits, a) = self.value(base)?;
+                let (b_bits, b) = self.value(other)?;
+                if bits != b_bits || is_const(base) {
+                    return Err(INVALID_OPERATION)
+                }
+                let res = match op { 0xd0 => a & b, 0xd1 => a | b, _ => a ^ b };
+                self.write(base, res);
+                self.set_flags(false, false, res, bits);
+            }
+            0xd3 => {
+                let base = arg(0, 0x00);
+                let (bits, a) = self.value(base)?;
+                if is_const(base) {
+                    return Err(INVALID_OPERATION)
+                }
+                let res = !a & mask(bits);
+                self.write(base, res);
+                self.set_flags(false, false, res, bits);
+            }
+            _ => { // shifts
+                let base = arg(0, 0x00);
+                let (_, count) = self.value(arg(1, 0x0a))?;
+                let (bits, v) = self.value(base)?;
+                if is_const(base) {
+                    return Err(INVALID_OPERATION)
+                }
+                let c = self.flag(CARRY_MASK);
+                let (res, carry) = shift(op, v, bits, count, c);
+                self.write(base, res);
+                self.set_flags(carry, top(v, bits) != top(res, bits), res, bits);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// a shift or rotate worked out in one go, returning the result and carry
+fn shift(op: u8, v: u32, bits: u32, count: u32, carry: bool) -> (u32, bool) {
+    let (v64, m) = (v as u64, mask(bits) as u64);
+    let n = match op {
+        0xd8..=0xda => count.min(bits),
+        0xdc | 0xdd => count % bits,
+        _ => count % (bits + 1),
+    } as u64;
+    if n == 0 {
+        return (v, carry)
+    }
+    let bit = |x: u64, i: u64| (x >> i) & 1 != 0;
+    match op {
+        0xd8 => ((v64 << n & m) as u32, bit(v64, bits as u64 - n)),
+        0xd9 => ((v64 >> n) as u32, bit(v64, n - 1)),
+        0xda => {
+            let s = sext(v, bits) as u64;
+            (((s as i64 >> n) as u64 & m) as u32, bit(s, n - 1))
+        }
+        0xdc => {
+            let res = (v64 << n | v64 >> (bits as u64 - n)) & m;
+            (res as u32, bit(res, 0))
+        }
+        0xdd => {
+            let res = (v64 >> n | v64 << (bits as u64 - n)) & m;
+            (res as u32, bit(res, bits as u64 - 1))
+        }
+        _ => { // through carry, as a rotate of bits + 1
+            let w = bits as u64 + 1;
+            let x = v64 | (carry as u64) << bits;
+            let wm = (1 << w) - 1;
+            let res = if op == 0xde { (x << n | x >> (w - n)) & wm } else { (x >> n | x << (w - n)) & wm };
+            ((res & m) as u32, bit(res, bits as u64))
+        }
+    }
+}
+
+fn random_arg(rng: &mut Rng, mode32: bool) -> Arg {
+    match rng.below(10) {
+        0..=5 => Arg::Reg(rng.below(0x1b) as u8),
+        6 if rng.one_in(4) => Arg::Reg(rng.pick(&[0x1b, 0x1f, 0x24, 0x30, 0x45, 0x6f, 0x73, 0x7f])),
+        _ => {
+            let bits = if mode32 || rng.one_in(20) { rng.pick(&[8, 16, 32]) } else { rng.pick(&[8, 16]) };
+            Arg::Const(bits, rng.value(bits))
+        }
+    }
+}
+
+fn random_insn(rng: &mut Rng, mode32: bool) -> Insn {
+    let opcode = rng.pick(OPCODES);
+    let count = match rng.below(10) { 0 => 0, 1 => 1, 2 => 3, _ => 2 };
+    Insn { opcode, args: (0..count).map(|_| random_arg(rng, mode32)).collect() }
+}
+
+/// runs one random stream, returning what went wrong
+fn run_stream(seed: u64) -> std::result::Result<(), String> {
+    let mut rng = Rng::new(seed);
+    let mut p = Processor::default();
+    let mut mem = MemoryMap::new();
+    mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+    p.set_decode_cache(rng.one_in(2));
+
+    let mode32 = rng.one_in(3);
+    p.xa = rng.value(32);
+    p.xb = rng.value(32);
+    p.xc = rng.value(32);
+    p.xd = rng.value(32);
+    p.xbp = rng.value(32);
+    p.xsi = rng.value(32);
+    p.xdi = rng.value(32);
+    p.xrp = rng.value(32);
+    p.ro = rng.value(16) as u16;
+    p.xsp = STACK;
+    p.xpc = CODE;
+    p.xflags = rng.below(0x10) | if rng.one_in(2) { DSEG_MASK } else { 0 } | if mode32 { MODE32_MASK } else { 0 };
+    p.xidtp = IDT;
+    p.xidtl = 16;
+    for n in 0..16 {
+        mem.write16((HANDLERS as u16 + n as u16).to_le_bytes(), IDT + n * 4);
+    }
+
+    let insns: Vec<Insn> = (0..STREAM_LEN).map(|_| random_insn(&mut rng, mode32)).collect();
+    let mut code = Vec::new();
+    let mut starts = Vec::new();
+    for insn in &insns {
+        starts.push(CODE + code.len() as u32);
+        code.extend(insn.encode());
+    }
+    code.push(0xf4);
+    for (i, b) in code.iter().enumerate() {
+        mem.write(*b, CODE + i as u32);
+    }
+
+    let mut model = Model { regs: Regs::of(&p) };
+    for (i, insn) in insns.iter().enumerate() {
+        let before = model.regs.clone();
+        let len = insn.encode().len() as u32;
+        let expected = match model.step(insn, len) {
+            Ok(()) => model.regs.clone(),
+            Err(vector) => {
+                let frame = if mode32 { 8 } else { 6 };
+                let mut r = before.clone();
+                r.pc = HANDLERS + vector;
+                r.flags &= !(PRIV_MASK | TEST_MASK);
+                let sp_mask = mask(model.pointer_bits());
+                r.ptrs[0] = (before.ptrs[0] & !sp_mask) | (before.ptrs[0].wrapping_sub(frame) & sp_mask);
+                r
+            }
+        };
+        p.clock(&mut mem);
+        let actual = Regs::of(&p);
+        if actual != expected || p.state() != State::Running {
+            return Err(format!(
+                "instruction {} of {:02x?}\n{:02x?} in {} mode\nbefore:   {:x?}\nexpected: {:x?}\nactual:   {:x?}",
+                i, code, insn.encode(), if mode32 { "bc32" } else { "16-bit" }, before, expected, actual,
+            ))
+        }
+        // stop at anything that leaves the stream
+        if starts.get(i + 1) != Some(&actual.pc) {
+            break
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn fuzz_against_model() {
+    let env = |name, default| std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default);
+    let seed = env("BCPU_FUZZ_SEED", DEFAULT_SEED);
+    let streams = env("BCPU_FUZZ_STREAMS", DEFAULT_STREAMS);
+    for s in seed..seed + streams {
+        if let Err(e) = run_stream(s) {
+            panic!("stream with seed {} differs from the model at {}", s, e);
+        }
+    }
+}
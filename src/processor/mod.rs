@@ -1,10 +1,21 @@
-use super::memory::MemoryMap;
+use super::memory::{MemoryMap, DevMsg};
 use crate::utils::*;
 use consts::*;
-use regval::RegVal;
+use regval::{RegVal, FlagUpdate, Shift};
+use timing::*;
+use cache::{DecodeCache, Decoded};
+use translate::{Translator, Block};
+use std::rc::Rc;
 
+mod cache;
+#[cfg(test)]
+mod conformance;
 mod consts;
+#[cfg(test)]
+mod fuzz;
 mod regval;
+mod timing;
+mod translate;
 #[cfg(test)]
 mod tests;
 
@@ -14,59 +25,77 @@ pub struct Processor {
     xsp: u32, xbp: u32, xsi: u32, xdi: u32, xrp: u32, ro: u16,
     co: u16, do_: u16, eo: u16, so: u16,
     xidtp: u32, xidtl: u32, xpc: u32, xflags: u32,
+    state: State,
+    /// a hardware interrupt to deliver before the next instruction
+    pending: Option<DevMsg>,
+    /// set by a block instruction with elements left, so it runs again instead of moving on
+    repeat: bool,
+    cache: DecodeCache,
+    translator: Translator,
+}
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum State {
+    #[default]
+    Running,
+    /// stopped by wfi until the next interrupt
+    Waiting,
+    /// stopped by hlt for good
+    Halted,
 }
 impl Processor {
     fn read(&self, regid: u8) -> Result<RegVal> {
         if self.can_access(regid) {
-            match regid {
-                0..0x10 => {
-                    let v = match regid & GPR_MASK {
-                        0 => self.xa,
-                        4 => self.xb,
-                        8 => self.xc,
-                        0xc => self.xd,
-                        _ => unreachable!(),
-                    };
-                    Ok(RegVal::from_u32(v, regid & GPR_SEL_MASK)) 
-                }
-                0x10..0x1a => {
-                    let v = match regid & PTR_MASK {
-                        0 => self.xsp,
-                        2 => self.xbp,
-                        4 => self.xsi,
-                        6 => self.xdi,
-                        8 => self.xrp,
-                        _ => unreachable!(),
-                    };
-                    Ok(RegVal::from_u32(v, regid & PTR_SEL_MASK))
-                }
-                0x1a => Ok(self.ro.into()),
-                0x20 => Ok(self.co.into()),
-                0x21 => Ok(self.do_.into()),
-                0x22 => Ok(self.eo.into()),
-                0x23 => Ok(self.so.into()),
-                0x28..0x30 => {
-                    let v = match regid & SPEC_MASK {
-                        0 => self.xidtp,
-                        2 => self.xidtl,
-                        4 => self.xpc,
-                        6 => self.xflags,
-                        _ => unreachable!(),
-                    };
-                    Ok(RegVal::from_u32(v, regid & SPEC_SEL_MASK))
-                }
-                
-                _ => Err(Exception::InvalidOperation)
-            }
+            self.read_any(regid)
         }
         else {
             Err(Exception::IllegalOperation)
         }
     }
-    fn read_16(&self, regid: u8) -> Result<u16> {
-        self.read(regid)
-            .map(|v| v.unwrap_u16().ok_or(Exception::InvalidOperation))
-            .flatten()
+    /// reads a register whatever the privilege level, for looking at the processor from outside
+    pub fn inspect(&self, regid: u8) -> Option<u32> {
+        self.read_any(regid).ok().map(RegVal::to_u32)
+    }
+    fn read_any(&self, regid: u8) -> Result<RegVal> {
+        match regid {
+            0..0x10 => {
+                let v = match regid & GPR_MASK {
+                    0 => self.xa,
+                    4 => self.xb,
+                    8 => self.xc,
+                    0xc => self.xd,
+                    _ => unreachable!(),
+                };
+                Ok(RegVal::from_u32(v, regid & GPR_SEL_MASK)) 
+            }
+            0x10..0x1a => {
+                let v = match regid & PTR_MASK {
+                    0 => self.xsp,
+                    2 => self.xbp,
+                    4 => self.xsi,
+                    6 => self.xdi,
+                    8 => self.xrp,
+                    _ => unreachable!(),
+                };
+                Ok(RegVal::from_u32(v, regid & PTR_SEL_MASK))
+            }
+            0x1a => Ok(self.ro.into()),
+            0x20 => Ok(self.co.into()),
+            0x21 => Ok(self.do_.into()),
+            0x22 => Ok(self.eo.into()),
+            0x23 => Ok(self.so.into()),
+            0x28..0x30 => {
+                let v = match regid & SPEC_MASK {
+                    0 => self.xidtp,
+                    2 => self.xidtl,
+                    4 => self.xpc,
+                    6 => self.xflags,
+                    _ => unreachable!(),
+                };
+                Ok(RegVal::from_u32(v, regid & SPEC_SEL_MASK))
+            }
+            
+            _ => Err(Exception::InvalidOperation)
+        }
     }
     fn write(&mut self, regid: u8, val: RegVal) -> Result<()> {
         if self.can_access(regid) {
@@ -143,14 +172,14 @@ impl Processor {
         }
     }
     fn can_access(&self, regid: u8) -> bool {
-        let privilege = (self.xflags & PRIV_MASK) >> 6;
         match regid {
-            0x28..0x30 => {
-                true // provisional
-            }
+            0x20..0x24 | 0x28..0x30 => self.privilege() == 0,
             _ => true
         }
     }
+    fn privilege(&self) -> u32 {
+        (self.xflags & PRIV_MASK) >> 6
+    }
 }
 enum RegSize {
     Byte,
@@ -159,11 +188,97 @@ enum RegSize {
 }
 
 impl Processor {
-    fn clock(&mut self, mem: &mut MemoryMap) {
+    /// runs one instruction, or one translated block, returning the number of cycles it took
+    pub fn clock(&mut self, mem: &mut MemoryMap) -> u32 {
+        if self.state != State::Running {
+            return 0
+        }
+        let start_cycles = mem.cycles();
+        let irqs = self.flag(IRQ_MASK);
+        if let Some(msg) = self.pending.take_if(|msg| irqs || matches!(msg, DevMsg::Nmi)) {
+            let vector = match msg {
+                DevMsg::Vector(v) => {
+                    mem.acknowledge(v);
+                    v
+                }
+                DevMsg::Nmi => Exception::Nmi as u8,
+                _ => Exception::Irq as u8,
+            };
+            self.deliver(mem, vector);
+            return INTERRUPT_CYCLES + (mem.cycles() - start_cycles) as u32
+        }
+        for page in mem.take_written_pages() {
+            self.cache.invalidate(page);
+            self.translator.invalidate(page);
+        }
+
+        let start_pc = self.xpc;
+        let flat_pc = self.get_flat_pc();
+        let mode32 = self.is_mode32();
+        if let Some(block) = self.translator.get(flat_pc, mode32) {
+            return self.run_block(&block, mem)
+        }
+        let (decoded, decode_res) = match self.cache.get(flat_pc, mode32) {
+            Some(d) => {
+                self.set_pc(self.pc().wrapping_add(d.len));
+                (d, Ok(()))
+            }
+            None => {
+                let (d, res) = self.decode(mem);
+                let d = Rc::new(d);
+                // instructions that wrap around the end of the code segment aren't contiguous in memory
+                let contiguous = mode32 || (start_pc & 0xffff) + d.len < 0xffff;
+                if res.is_ok() && contiguous {
+                    self.cache.insert(flat_pc, mode32, d.clone(), mem);
+                }
+                (d, res)
+            }
+        };
+        let instruction = decoded.instruction;
+
+        let exec_cycles = mem.cycles();
+        let res = decode_res.and_then(|_| self.execute(instruction, &decoded.operands, mem));
+
+        let mut cycles = execution_cycles(instruction);
+        if std::mem::take(&mut self.repeat) {
+            self.xpc = start_pc;
+        }
+        if let Err(e) = res { // interrupt processor here
+            self.xpc = start_pc; // so the handler can see the faulting instruction
+            self.fault(mem, e);
+            cycles += INTERRUPT_CYCLES;
+        }
+        self.translator.visit(flat_pc, mode32, &self.cache);
+        cycles + decoded.fetch_cycles + (mem.cycles() - exec_cycles) as u32
+    }
+
+    /// runs a translated block, stopping early if one of its instructions faults.  
+    /// pending interrupts wait for the end of the block
+    fn run_block(&mut self, block: &Block, mem: &mut MemoryMap) -> u32 {
+        let start_cycles = mem.cycles();
+        let mut cycles = 0;
+        for step in &block.steps {
+            let start_pc = self.xpc;
+            self.set_pc(self.pc().wrapping_add(step.len));
+            cycles += step.cycles;
+            if let Err(e) = self.prefixed(step.instruction, |p| (step.op)(p, mem)) {
+                self.xpc = start_pc;
+                self.fault(mem, e);
+                cycles += INTERRUPT_CYCLES;
+                break
+            }
+        }
+        cycles + (mem.cycles() - start_cycles) as u32
+    }
+
+    /// fetches an instruction and its operands, leaving pc at the next instruction
+    fn decode(&mut self, mem: &mut MemoryMap) -> (Decoded, Result<()>) {
+        let start_cycles = mem.cycles();
+        let start_pc = self.pc();
         let instruction = self.get_instruction_byte(mem);
         let mut operands = Vec::new();
 
-        let ops_res = loop {
+        let res = loop {
             if let Some(operand) = self.read_operand(mem) {
                 match operand {
                     Ok(o) => operands.push(o),
@@ -175,16 +290,292 @@ impl Processor {
             }
         };
 
-        match ops_res {
-            Ok(_) => { // proceed to instruction decode
-                
-            }
-            Err(e) => { // interrupt processor here
-                self.xrp = self.xpc;
-                self.ro = self.co;
+        let decoded = Decoded {
+            instruction,
+            operands,
+            len: self.pc().wrapping_sub(start_pc) & self.pointer_size_mask(),
+            fetch_cycles: (mem.cycles() - start_cycles) as u32,
+        };
+        (decoded, res)
+    }
+
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.cache.set_enabled(enabled)
+    }
+    /// translates hot code into blocks, which only works with the decode cache enabled
+    pub fn set_translation(&mut self, enabled: bool) {
+        self.translator.set_enabled(enabled)
+    }
+
+    /// where to start running from, for loaders. in 16-bit mode code is fetched from co:pc
+    pub fn set_entry(&mut self, co: u16, pc: u32) {
+        self.co = co;
+        self.xpc = pc;
+    }
+
+    /// where the next instruction will be fetched from
+    pub fn flat_pc(&self) -> u32 {
+        self.get_flat_pc()
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+    /// raises a hardware interrupt, which is delivered on the next clock, or once the irq flag is set for an irq.  
+    /// this wakes the processor if it's waiting, even for an irq that has to wait.  
+    /// an nmi isn't replaced by anything waiting with it, and an interrupt controller's vector isn't replaced by a plain irq.  
+    /// vectors are acknowledged when they're delivered, so a controller raises one that's replaced or ignored again
+    pub fn signal(&mut self, msg: DevMsg) {
+        if matches!(msg, DevMsg::None) || self.state == State::Halted {
+            return
+        }
+        self.state = State::Running;
+        if msg.urgency() >= self.pending.as_ref().map_or(0, DevMsg::urgency) {
+            self.pending = Some(msg);
+        }
+    }
+
+    fn fault(&mut self, mem: &mut MemoryMap, e: Exception) {
+        self.deliver(mem, e as u8)
+    }
+    /// delivers an interrupt, escalating to a double fault if that fails.  
+    /// if the double fault can't be delivered either, the processor resets
+    fn deliver(&mut self, mem: &mut MemoryMap, vector: u8) {
+        let delivered = self.interrupt(mem, vector)
+            .or_else(|_| self.interrupt(mem, Exception::DoubleFault as u8));
+        if delivered.is_err() {
+            *self = Processor::default();
+        }
+    }
+
+    /// pushes flags, co and pc, enters system mode with irqs off and jumps to the idt entry for vector.  
+    /// each idt entry is a pc followed by a co, and idtl is the number of entries
+    fn interrupt(&mut self, mem: &mut MemoryMap, vector: u8) -> Result<()> {
+        if vector as u32 >= self.xidtl {
+            return Err(Exception::DoubleFault)
+        }
+        let entry = self.xidtp.wrapping_add(vector as u32 * 4);
+        let lo = u16::from_le_bytes(mem.read16(entry));
+        let hi = u16::from_le_bytes(mem.read16(entry.wrapping_add(2)));
+
+        // the frame is written even between a test prefix and the instruction it applies to
+        let flags = self.xflags;
+        self.xflags &= !TEST_MASK;
+        self.push(mem, RegVal::Word(flags.half_split().0))?;
+        self.push(mem, RegVal::Word(self.co))?;
+        let pc = match self.pointer_size() {
+            RegSize::Dword => RegVal::Dword(self.pc()),
+            _ => RegVal::Word(self.pc() as u16),
+        };
+        self.push(mem, pc)?;
+
+        self.xflags &= !(PRIV_MASK | TEST_MASK | IRQ_MASK);
+        if self.is_mode32() { // the whole entry is a flat pc
+            self.set_pc(u32::merge(lo, hi));
+        }
+        else {
+            self.co = hi;
+            self.set_pc(lo as u32);
+        }
+        Ok(())
+    }
+    /// pops pc, co and flags pushed by interrupt
+    fn iret(&mut self, mem: &mut MemoryMap) -> Result<()> {
+        if self.privilege() != 0 {
+            return Err(Exception::IllegalOperation)
+        }
+        let pc = self.pop(mem, self.pointer_size())?.to_u32();
+        let co = self.pop(mem, RegSize::Word)?.to_u32();
+        let flags = self.pop(mem, RegSize::Word)?.to_u32();
 
-                
+        self.set_pc(pc);
+        self.co = co as u16;
+        self.xflags = mix_u32(self.xflags, flags, 0);
+        Ok(())
+    }
+
+    fn execute(&mut self, instruction: u8, operands: &[Operand], mem: &mut MemoryMap) -> Result<()> {
+        self.prefixed(instruction, |p| p.dispatch(instruction, operands, mem))
+    }
+    /// runs an instruction under the test prefix rules, clearing the prefix afterwards
+    fn prefixed(&mut self, instruction: u8, run: impl FnOnce(&mut Self) -> Result<()>) -> Result<()> {
+        if instruction == 0xf0 { // test
+            self.xflags |= TEST_MASK;
+            return Ok(())
+        }
+        let res = if self.is_testing() && is_jump(instruction) {
+            Err(Exception::IllegalOperation)
+        }
+        else {
+            run(self)
+        };
+        // iret brings back the test flag of an instruction that was interrupted after its prefix
+        if instruction != 0xf3 || res.is_err() {
+            self.xflags &= !TEST_MASK;
+        }
+        res
+    }
+
+    fn dispatch(&mut self, instruction: u8, operands: &[Operand], mem: &mut MemoryMap) -> Result<()> {
+        match instruction {
+            0x80 | 0x81 => {
+                let src = operand_or(operands, 0, GPRs::A);
+                let dest = operand_or(operands, 1, GPRs::A);
+                self.mov(src, dest, instruction & 1 != 0)
+            }
+            0x84 => {
+                let src = operand_or(operands, 0, GPRs::A).value(self)?;
+                self.push(mem, src)
+            }
+            0x85 => {
+                let src = operand_or(operands, 0, GPRs::A);
+                let dest = operand_or(operands, 1, GPRs::A);
+                self.swr(src, dest)
+            }
+            0x86 => {
+                let dest = operand_or(operands, 0, GPRs::A);
+                if dest.is_const() {
+                    return Err(Exception::InvalidOperation)
+                }
+                let val = self.pop(mem, dest.size(self)?)?;
+                if !self.is_testing() {
+                    dest.write_back(self, val)?;
+                }
+                Ok(())
+            }
+            0x90 | 0x91 => {
+                let (dest, addr) = self.data_operands(operands)?;
+                if dest.is_const() {
+                    return Err(Exception::InvalidOperation)
+                }
+                let addr = self.data_address(addr, instruction & 1 != 0);
+                let val = self.load(mem, addr, dest.size(self)?);
+                if !self.is_testing() {
+                    dest.write_back(self, val)?;
+                }
+                Ok(())
+            }
+            0xa0 | 0xa1 => {
+                let (src, addr) = self.data_operands(operands)?;
+                let addr = self.data_address(addr, instruction & 1 != 0);
+                let val = src.value(self)?;
+                self.store(mem, addr, val)
+            }
+            0xb0 | 0xb1 => {
+                let (reg, addr) = self.data_operands(operands)?;
+                if reg.is_const() {
+                    return Err(Exception::InvalidOperation)
+                }
+                let addr = self.data_address(addr, instruction & 1 != 0);
+                let reg_val = reg.value(self)?;
+                let mem_val = self.load(mem, addr, reg.size(self)?);
+                self.store(mem, addr, reg_val)?;
+                if !self.is_testing() {
+                    reg.write_back(self, mem_val)?;
+                }
+                Ok(())
+            }
+            0x88..=0x8f | 0x98..=0x9e => {
+                let addr = operand_or(operands, 0, GPRs::A);
+                self.jump_if(instruction, addr)
+            }
+            0xc0 | 0xc1 => {
+                let base = operand_or(operands, 0, GPRs::A);
+                let other = operand_or(operands, 1, GPRs::B);
+                let lhs = base.value(self)?;
+                let rhs = other.value(self)?;
+                let res = if instruction & 1 == 0 {
+                    lhs.mul(rhs)
+                }
+                else {
+                    lhs.imul(rhs)
+                };
+                self.alu_wide(base, other, res.ok_or(Exception::InvalidOperation)?)
             }
+            0xc2 | 0xc3 => {
+                let base = operand_or(operands, 0, GPRs::A);
+                let other = operand_or(operands, 1, GPRs::B);
+                let lhs = base.value(self)?;
+                let rhs = other.value(self)?;
+                let res = if instruction & 1 == 0 {
+                    lhs.div(lhs.with_value(0), rhs)
+                }
+                else {
+                    let hi = if lhs.is_negative() { u32::MAX } else { 0 };
+                    lhs.idiv(lhs.with_value(hi), rhs)
+                };
+                self.alu_wide(base, other, res?)
+            }
+            0xc4..0xc8 => {
+                let rhs = operand_or(operands, 0, GPRs::B);
+                self.add_sub(instruction, rhs)
+            }
+            0xd0..0xd3 => {
+                let base = operand_or(operands, 0, GPRs::A);
+                let lhs = base.value(self)?;
+                let rhs = operand_or(operands, 1, GPRs::B).value(self)?;
+                let res = match instruction {
+                    0xd0 => lhs.and(rhs),
+                    0xd1 => lhs.or(rhs),
+                    0xd2 => lhs.xor(rhs),
+                    _ => unreachable!()
+                };
+                self.alu(base, res)
+            }
+            0xd3 => {
+                let base = operand_or(operands, 0, GPRs::A);
+                let res = base.value(self)?.not();
+                self.alu(base, Some(res))
+            }
+            0xd8..=0xda | 0xdc..=0xdf => {
+                let base = operand_or(operands, 0, GPRs::A);
+                let count = operand_or(operands, 1, GPRs::CL).value(self)?.to_u32();
+                let kind = match instruction {
+                    0xd8 => Shift::Shl,
+                    0xd9 => Shift::Shr,
+                    0xda => Shift::Sar,
+                    0xdc => Shift::Rol,
+                    0xdd => Shift::Ror,
+                    0xde => Shift::Rcl,
+                    0xdf => Shift::Rcr,
+                    _ => unreachable!()
+                };
+                let res = base.value(self)?.shift(kind, count, self.flag(CARRY_MASK));
+                self.alu(base, Some(res))
+            }
+            0xe8..=0xef => {
+                let val = operand_or(operands, 0, GPRs::AL).value(self)?;
+                self.block(mem, instruction, val)
+            }
+            0xf2 => {
+                let vector = operand_or(operands, 0, GPRs::AL).value(self)?.to_u32();
+                if vector > 0xff {
+                    Err(Exception::InvalidOperation)
+                }
+                else if vector < 16 && self.privilege() != 0 { // reserved for the cpu
+                    Err(Exception::IllegalOperation)
+                }
+                else {
+                    self.interrupt(mem, vector as u8)
+                }
+            }
+            0xf3 => self.iret(mem),
+            0xf4 | 0xf5 => { // hlt, wfi
+                if self.privilege() != 0 {
+                    return Err(Exception::IllegalOperation)
+                }
+                if !self.is_testing() {
+                    self.state = if instruction == 0xf4 { State::Halted } else { State::Waiting };
+                }
+                Ok(())
+            }
+            0xc8 => {
+                let lhs = operand_or(operands, 0, GPRs::A);
+                let rhs = operand_or(operands, 1, GPRs::B);
+                self.cmp(lhs, rhs)
+            }
+
+            _ => Err(Exception::InvalidOperation)
         }
     }
 
@@ -210,18 +601,317 @@ impl Processor {
         }
     }
 
+    /// writes back the result of an arithmetic or bitwise operation and updates flags
+    fn alu(&mut self, dest: Operand, res: Option<(RegVal, FlagUpdate)>) -> Result<()> {
+        if dest.is_const() {
+            return Err(Exception::InvalidOperation)
+        }
+        let (val, flags) = res.ok_or(Exception::InvalidOperation)?;
+        if !self.is_testing() {
+            dest.write_back(self, val)?;
+        }
+        self.xflags = flags.update_reg(self.xflags);
+        Ok(())
+    }
+
+    /// like alu, for multiplication and division which produce two values
+    fn alu_wide(&mut self, lo_dest: Operand, hi_dest: Operand, res: ((RegVal, RegVal), FlagUpdate)) -> Result<()> {
+        if lo_dest.is_const() || hi_dest.is_const() {
+            return Err(Exception::InvalidOperation)
+        }
+        let ((lo, hi), flags) = res;
+        if !self.is_testing() {
+            lo_dest.write_back(self, lo)?;
+            hi_dest.write_back(self, hi)?;
+        }
+        self.xflags = flags.update_reg(self.xflags);
+        Ok(())
+    }
+
+    /// add, adc, sub or sbc of an operand into %a
+    fn add_sub(&mut self, instruction: u8, rhs: Operand) -> Result<()> {
+        let base = Operand::Register(GPRs::A as u8);
+        let rhs = rhs.value(self)?;
+        let with_carry = instruction & 1 != 0;
+        let res = if instruction & 2 == 0 {
+            base.value(self)?.add(rhs, with_carry && self.flag(CARRY_MASK))
+        }
+        else {
+            base.value(self)?.sub(rhs, !with_carry || self.flag(CARRY_MASK))
+        };
+        self.alu(base, res)
+    }
+
+    fn cmp(&mut self, lhs: Operand, rhs: Operand) -> Result<()> {
+        let lhs_v = lhs.value(self)?;
+        let rhs_v = rhs.value(self)?;
+        let (_, flags) = lhs_v.sub(rhs_v, true).ok_or(Exception::InvalidOperation)?;
+        self.xflags = flags.update_reg(self.xflags);
+        Ok(())
+    }
+
+    fn jump(&mut self, addr: Operand) -> Result<()> {
+        let target = addr.value(self)?.zero_extend(self.pointer_size())?;
+        self.set_pc(target.to_u32());
+        Ok(())
+    }
+    fn jump_if(&mut self, instruction: u8, addr: Operand) -> Result<()> {
+        if self.condition(instruction) {
+            self.jump(addr)
+        }
+        else {
+            Ok(())
+        }
+    }
+    /// evaluates the condition of a jump instruction against the flags register
+    fn condition(&self, instruction: u8) -> bool {
+        let carry = self.flag(CARRY_MASK);
+        let negative = self.flag(NEGATIVE_MASK);
+        let overflow = self.flag(OVERFLOW_MASK);
+        let zero = self.flag(ZERO_MASK);
+        match instruction {
+            0x88 => true, // jmp
+            0x89 => zero, // jz
+            0x8a => !zero, // jnz
+            0x8b => carry, // jc
+            0x8c => !carry, // jnc
+            0x8d => negative, // jn
+            0x8e => !negative, // jnn
+            0x8f => overflow, // jo
+            0x98 => !overflow, // jno
+            0x99 => carry && !zero, // ja
+            0x9a => !carry || zero, // jbe
+            0x9b => negative != overflow, // jl
+            0x9c => negative == overflow, // jge
+            0x9d => !zero && negative == overflow, // jg
+            0x9e => zero || negative != overflow, // jle
+            _ => unreachable!()
+        }
+    }
+
+    fn swr(&mut self, src: Operand, dest: Operand) -> Result<()> {
+        if src.is_const() || dest.is_const() {
+            return Err(Exception::InvalidOperation)
+        }
+        let src_v = src.value(self)?;
+        let dest_v = dest.value(self)?;
+        if src_v.bits() != dest_v.bits() {
+            return Err(Exception::InvalidOperation)
+        }
+        if !self.is_testing() {
+            dest.write_back(self, src_v)?;
+            src.write_back(self, dest_v)?;
+        }
+        Ok(())
+    }
+
+    /// moves sp down past the value, then stores it at so:sp
+    fn push(&mut self, mem: &mut MemoryMap, val: RegVal) -> Result<()> {
+        let sp = self.sp().wrapping_sub(val.bits() / 8) & self.pointer_size_mask();
+        self.store(mem, self.stack_address(sp), val)?;
+        if !self.is_testing() {
+            self.set_sp(sp);
+        }
+        Ok(())
+    }
+    /// loads from so:sp, then moves sp up past the value
+    fn pop(&mut self, mem: &mut MemoryMap, size: RegSize) -> Result<RegVal> {
+        let width = match size {
+            RegSize::Byte => 1,
+            RegSize::Word => 2,
+            RegSize::Dword => 4,
+        };
+        let sp = self.sp();
+        let val = self.load(mem, self.stack_address(sp), size);
+        if !self.is_testing() {
+            self.set_sp(sp.wrapping_add(width));
+        }
+        Ok(val)
+    }
+    fn sp(&self) -> u32 {
+        self.xsp & self.pointer_size_mask()
+    }
+    fn set_sp(&mut self, sp: u32) {
+        self.xsp = self.mix_pointer(self.xsp, sp)
+    }
+    /// replaces the pointer sized part of a register
+    fn mix_pointer(&self, old: u32, new: u32) -> u32 {
+        if self.is_mode32() { new } else { mix_u32(old, new & 0xffff, 0) }
+    }
+
+    /// the block instructions, which repeat %c times over do:si and eo:di.  
+    /// elements are the size of val, which is also the value to fill with or scan for
+    /// runs up to BLOCK_CHUNK elements of a block instruction, leaving it to run again for the rest,
+    /// so interrupts and devices don't wait for the whole block
+    fn block(&mut self, mem: &mut MemoryMap, instruction: u8, val: RegVal) -> Result<()> {
+        let mask = self.pointer_size_mask();
+        let width = val.bits() / 8;
+        let step = if instruction & 1 == 0 { width } else { width.wrapping_neg() };
+        let uses_si = instruction & 0b010 == 0; // stos and scas only use di
+        let (mut si, mut di, mut c) = (self.xsi & mask, self.xdi & mask, self.xc & mask);
+        let mut flags = None;
+        // a fault stops at the element that caused it, so the handler can see where and carry on from there
+        let mut res = Ok(());
+        let mut stopped = false;
+
+        for _ in 0..BLOCK_CHUNK {
+            if c == 0 {
+                break
+            }
+            let src = self.data_address(si, false);
+            let dest = self.data_address(di, true);
+            match instruction & 0b110 {
+                0b000 => { // movs
+                    let v = self.load(mem, src, val.size());
+                    res = self.store(mem, dest, v);
+                }
+                0b010 => res = self.store(mem, dest, val), // stos
+                0b100 => { // cmps
+                    let lhs = self.load(mem, src, val.size());
+                    let rhs = self.load(mem, dest, val.size());
+                    flags = lhs.sub(rhs, true).map(|(_, f)| f);
+                }
+                _ => { // scas
+                    let rhs = self.load(mem, dest, val.size());
+                    flags = val.sub(rhs, true).map(|(_, f)| f);
+                }
+            }
+            if res.is_err() {
+                break
+            }
+            if uses_si {
+                si = si.wrapping_add(step) & mask;
+            }
+            di = di.wrapping_add(step) & mask;
+            c -= 1;
+
+            let zero = flags.as_ref().map(|f| f.zero);
+            stopped = match instruction & 0b110 {
+                0b100 => zero == Some(false), // stop at the first difference
+                0b110 => zero == Some(true), // stop at the first match
+                _ => false
+            };
+            if stopped {
+                break
+            }
+        }
+
+        if let Some(f) = flags {
+            self.xflags = f.update_reg(self.xflags);
+        }
+        if !self.is_testing() {
+            self.xsi = self.mix_pointer(self.xsi, si);
+            self.xdi = self.mix_pointer(self.xdi, di);
+            self.xc = self.mix_pointer(self.xc, c);
+            self.repeat = c != 0 && !stopped && res.is_ok();
+        }
+        res
+    }
+    fn stack_address(&self, sp: u32) -> u32 {
+        if self.is_mode32() { sp } else { address(sp as u16, self.so) }
+    }
+
+    /// gets the register and address of a memory instruction, adding the offset operand if there is one
+    fn data_operands(&self, operands: &[Operand]) -> Result<(Operand, u32)> {
+        let (reg, addr, offset) = match operands {
+            [reg, addr] => (*reg, self.read_pointer(addr)?, 0),
+            [reg, addr, offset] => (*reg, self.read_pointer(addr)?, self.read_pointer(offset)?),
+            _ => return Err(Exception::InvalidOperation)
+        };
+        Ok((reg, self.pointer_size_mask() & addr.wrapping_add(offset)))
+    }
+    /// reads an operand used as an address, which can be at most pointer sized
+    fn read_pointer(&self, operand: &Operand) -> Result<u32> {
+        Ok(operand.value(self)?.zero_extend(self.pointer_size())?.to_u32())
+    }
+    fn pointer_size_mask(&self) -> u32 {
+        if self.is_mode32() { u32::MAX } else { 0xffff }
+    }
+
+    /// offsets a data address by do (or eo if extra is set).  
+    /// offsets always apply in user mode, and in system mode only when dseg is set.  
+    /// bc32 mode uses flat addresses, so offsets never apply
+    fn data_address(&self, addr: u32, extra: bool) -> u32 {
+        if self.is_mode32() {
+            return addr
+        }
+        let addr = addr as u16;
+        let segmented = self.flag(DSEG_MASK) || (self.xflags & PRIV_MASK) != 0;
+        let offset = match (segmented, extra) {
+            (false, _) => 0,
+            (true, false) => self.do_,
+            (true, true) => self.eo,
+        };
+        address(addr, offset)
+    }
+    fn load(&self, mem: &mut MemoryMap, addr: u32, size: RegSize) -> RegVal {
+        match size {
+            RegSize::Byte => mem.read(addr).into(),
+            RegSize::Word => u16::from_le_bytes(mem.read16(addr)).into(),
+            RegSize::Dword => {
+                let lo = u16::from_le_bytes(mem.read16(addr));
+                let hi = u16::from_le_bytes(mem.read16(addr.wrapping_add(2)));
+                u32::merge(lo, hi).into()
+            }
+        }
+    }
+    /// writes a value to memory, unless the current instruction is being tested
+    fn store(&self, mem: &mut MemoryMap, addr: u32, val: RegVal) -> Result<()> {
+        if self.is_testing() {
+            return Ok(())
+        }
+        let written = match val {
+            RegVal::Byte(v) => mem.write(v, addr),
+            RegVal::Word(v) => mem.write16(v.to_le_bytes(), addr),
+            RegVal::Dword(v) => {
+                let (lo, hi) = v.half_split();
+                let lo = mem.write16(lo.to_le_bytes(), addr);
+                mem.write16(hi.to_le_bytes(), addr.wrapping_add(2)) && lo
+            }
+        };
+        if written { Ok(()) } else { Err(Exception::BusFault) }
+    }
+
     fn get_flat_pc(&self) -> u32 {
-        address(self.xpc.half_split().0, self.co)
+        if self.is_mode32() {
+            self.xpc
+        }
+        else {
+            address(self.xpc.half_split().0, self.co)
+        }
+    }
+    /// pc, or just its low half outside of bc32 mode
+    fn pc(&self) -> u32 {
+        if self.is_mode32() { self.xpc } else { self.xpc & 0xffff }
+    }
+    fn set_pc(&mut self, pc: u32) {
+        if self.is_mode32() {
+            self.xpc = pc
+        }
+        else {
+            self.xpc = mix_u32(self.xpc, pc & 0xffff, 0)
+        }
     }
     fn increment_pc(&mut self) {
-        self.xpc = self.xpc.wrapping_add(1)
+        self.set_pc(self.pc().wrapping_add(1))
     }
     fn decrement_pc(&mut self) {
-        self.xpc = self.xpc.wrapping_sub(1)
+        self.set_pc(self.pc().wrapping_sub(1))
+    }
+
+    fn is_mode32(&self) -> bool {
+        self.flag(MODE32_MASK)
+    }
+    /// the size of pc and of pointers used as addresses
+    fn pointer_size(&self) -> RegSize {
+        if self.is_mode32() { RegSize::Dword } else { RegSize::Word }
     }
 
     fn is_testing(&self) -> bool {
-        (self.xflags & TEST_MASK) != 0
+        self.flag(TEST_MASK)
+    }
+    fn flag(&self, mask: u32) -> bool {
+        (self.xflags & mask) != 0
     }
 
     fn get_instruction_byte(&mut self, mem: &mut MemoryMap) -> u8 {
@@ -247,11 +937,17 @@ impl Processor {
                 let v = u16::from_le_bytes([lo, hi]);
                 Ok(Operand::Const(RegVal::Word(v)))
             }
+            0x72 if self.is_mode32() => {
+                let bytes = [(); 4].map(|_| self.get_instruction_byte(mem));
+                Ok(Operand::Const(RegVal::Dword(u32::from_le_bytes(bytes))))
+            }
+            0x72 => Err(Exception::InvalidOperation),
             _ => Ok(Operand::Register(operand)),
         })
     }
 }
 
+#[derive(Clone, Copy)]
 enum Operand {
     Const(RegVal),
     Register(u8),
@@ -275,18 +971,33 @@ impl Operand {
     fn size(&self, registers: &Processor) -> Result<RegSize> {
         match self {
             Self::Register(r) => registers.size(*r),
-            Self::Const(c) => todo!(),
+            Self::Const(c) => Ok(c.size()),
         }
     }
 }
 
+fn is_jump(instruction: u8) -> bool {
+    matches!(instruction, 0x88..0x90 | 0x98..0x9f | 0xa8..0xac | 0xe2..0xe5 | 0xf2 | 0xf3)
+}
+
+/// gets an explicit operand, or the default register if it was left unspecified
+fn operand_or(operands: &[Operand], idx: usize, default: GPRs) -> Operand {
+    operands.get(idx).copied().unwrap_or(Operand::Register(default as u8))
+}
+
 fn address(addr: u16, offset: u16) -> u32 {
     (addr as u32) + ((offset as u32) << 8)
 }
 
 type Result<T> = std::result::Result<T, Exception>;
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 enum Exception {
     InvalidOperation = 0,
     IllegalOperation = 1,
+    DivideByZero = 2,
+    Irq = 3,
+    Nmi = 4,
+    DoubleFault = 5,
+    DivisionOverflow = 6,
+    BusFault = 7,
 }
@@ -1,6 +1,5 @@
 use crate::utils::*;
 use super::*;
-use std::any::type_name_of_val;
 
 #[derive(Debug, PartialEq)]
 pub struct FlagUpdate {
@@ -34,7 +33,7 @@ macro_rules! add {
             let (new, carry) = $val.carrying_add(rhs, $carry);
             let hi_post = new.top_bit();
 
-            let overflow = hi_pre != hi_post;
+            let overflow = hi_pre == rhs.top_bit() && hi_pre != hi_post;
 
             let val = RegVal::$variant(new);
         
@@ -50,7 +49,7 @@ macro_rules! sub {
             let (new, borrow) = $val.borrowing_sub(rhs, !$carry);
             let hi_post = new.top_bit();
 
-            let overflow = hi_pre != hi_post;
+            let overflow = hi_pre != rhs.top_bit() && hi_pre != hi_post;
 
             let val = RegVal::$variant(new);
         
@@ -83,10 +82,13 @@ macro_rules! mul {
             let lhs = $val as $width_s;
             let rhs = $rhs.$fn_x()? as $width_s;
 
-            let (lo, hi) = lhs.widening_mul(rhs);
+            let (lo, hi) = WideMul::<$width>::widening_mul(lhs, rhs);
             let lo_wrap = RegVal::$variant(lo);
             let hi_wrap = RegVal::$variant(hi);
-            let overflow = hi != 0;
+            // the result fits if the high half only extends the low half
+            let signed = <$width_s>::MIN != 0;
+            let fill = if signed && lo_wrap.is_negative() { <$width>::MAX } else { 0 };
+            let overflow = hi != fill;
             let flags = FlagUpdate {
                 overflow,
                 carry: false,
@@ -98,26 +100,59 @@ macro_rules! mul {
     };
 }
 macro_rules! div {
-    ($width_s:ty, $fn_x:ident, $val:ident, $val_hi:ident, $rhs:ident) => {
+    ($width_s:ty, $narrow_s:ty, $fn_x:ident, $val:ident, $val_hi:ident, $rhs:ident) => {
         {
             let lhs_lo = $val;
-            let lhs_hi = $val_hi.$fn_x()?;
+            let lhs_hi = $val_hi.$fn_x().ok_or(Exception::InvalidOperation)?;
             let lhs = <$width_s>::merge(lhs_lo, lhs_hi);
-            let rhs = $rhs.$fn_x()? as $width_s;
-            let quot: RegVal = (lhs / rhs).half_split().0.into();
+            let rhs = $rhs.$fn_x().ok_or(Exception::InvalidOperation)? as $narrow_s as $width_s;
+            if rhs == 0 {
+                return Err(Exception::DivideByZero)
+            }
+            // MIN / -1, or a quotient too big for the low half
+            let quot = lhs.checked_div(rhs).ok_or(Exception::DivisionOverflow)?;
+            if quot as $narrow_s as $width_s != quot {
+                return Err(Exception::DivisionOverflow)
+            }
+            let quot: RegVal = quot.half_split().0.into();
             let rem: RegVal = (lhs % rhs).half_split().0.into();
-            let zero = rem.is_zero();
+            let zero = quot.is_zero();
             let negative = quot.is_negative();
             let flags = FlagUpdate {
                 zero, negative,
                 overflow: false,
                 carry: false
             };
-            Some(((quot, rem), flags))
+            Ok(((quot, rem), flags))
+        }
+    };
+}
+
+macro_rules! logic {
+    ($name:ident, $op:tt) => {
+        pub fn $name(self, rhs: RegVal) -> Option<(RegVal, FlagUpdate)> {
+            let val = match (self, rhs) {
+                (Self::Byte(l), Self::Byte(r)) => Self::Byte(l $op r),
+                (Self::Word(l), Self::Word(r)) => Self::Word(l $op r),
+                (Self::Dword(l), Self::Dword(r)) => Self::Dword(l $op r),
+                _ => return None
+            };
+            Some((val, FlagUpdate::new(false, false, val)))
         }
     };
 }
 
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Shift {
+    Shl,
+    Shr,
+    Sar,
+    Rol,
+    Ror,
+    Rcl,
+    Rcr,
+}
+
 macro_rules! unwrap_rv {
     ($name:ident, $variant:ident, $ret:ty) => {
         pub fn $name(self) -> Option<$ret> {
@@ -139,11 +174,33 @@ impl RegVal {
     unwrap_rv!(unwrap_u8, Byte, u8);
     unwrap_rv!(unwrap_u16, Word, u16);
     unwrap_rv!(unwrap_u32, Dword, u32);
-    pub fn to_u32(&self) -> u32 {
-        match *self {
+    pub fn to_u32(self) -> u32 {
+        match self {
             Self::Byte(v) => v as u32,
             Self::Word(v) => v as u32,
-            Self::Dword(v) => v as u32,
+            Self::Dword(v) => v,
+        }
+    }
+    /// a value of the same size as self, truncated from val
+    pub fn with_value(self, val: u32) -> RegVal {
+        match self {
+            Self::Byte(_) => Self::Byte(val as u8),
+            Self::Word(_) => Self::Word(val as u16),
+            Self::Dword(_) => Self::Dword(val),
+        }
+    }
+    pub fn size(&self) -> RegSize {
+        match self {
+            Self::Byte(_) => RegSize::Byte,
+            Self::Word(_) => RegSize::Word,
+            Self::Dword(_) => RegSize::Dword,
+        }
+    }
+    pub fn bits(&self) -> u32 {
+        match self {
+            Self::Byte(_) => 8,
+            Self::Word(_) => 16,
+            Self::Dword(_) => 32,
         }
     }
     pub fn from_u32(val: u32, gpr_select: u8) -> Self {
@@ -152,7 +209,7 @@ impl RegVal {
             0 => val.half_split().0.into(),
             1 => val.into(),
             2 => bytes[0].into(),
-            4 => bytes[1].into(),
+            3 => bytes[1].into(),
             _ => unreachable!()
         }
     }
@@ -187,8 +244,8 @@ impl RegVal {
     }
     fn zero_extend_u32(self) -> Option<RegVal> {
         match self {
-            Self::Byte(v) => Some(RegVal::Word(v.zero_extend())),
-            Self::Word(v) => Some(RegVal::Word(v.zero_extend())),
+            Self::Byte(v) => Some(RegVal::Dword(v.zero_extend())),
+            Self::Word(v) => Some(RegVal::Dword(v.zero_extend())),
             Self::Dword(v) => Some(RegVal::Dword(v.zero_extend())),
         }
     }
@@ -231,6 +288,43 @@ impl RegVal {
     one_output!(add);
     one_output!(sub);
 
+    logic!(and, &);
+    logic!(or, |);
+    logic!(xor, ^);
+    pub fn not(self) -> (RegVal, FlagUpdate) {
+        let val = self.with_value(!self.to_u32());
+        (val, FlagUpdate::new(false, false, val))
+    }
+
+    /// shifts or rotates by count bits, one bit at a time.  
+    /// carry is the last bit shifted out, or the old carry if nothing was shifted.  
+    /// overflow is set if the top bit changed
+    pub fn shift(self, kind: Shift, count: u32, carry: bool) -> (RegVal, FlagUpdate) {
+        let bits = self.bits();
+        let top = 1 << (bits - 1);
+        let mut v = self.to_u32();
+        let mut c = carry;
+        let steps = match kind {
+            Shift::Shl | Shift::Shr | Shift::Sar => count.min(bits),
+            Shift::Rol | Shift::Ror => count % bits,
+            Shift::Rcl | Shift::Rcr => count % (bits + 1),
+        };
+        for _ in 0..steps {
+            match kind {
+                Shift::Shl => { c = v & top != 0; v <<= 1 }
+                Shift::Shr => { c = v & 1 != 0; v >>= 1 }
+                Shift::Sar => { c = v & 1 != 0; v = (v >> 1) | (v & top) }
+                Shift::Rol => { c = v & top != 0; v = (v << 1) | c as u32 }
+                Shift::Ror => { c = v & 1 != 0; v = (v >> 1) | if c { top } else { 0 } }
+                Shift::Rcl => { let out = v & top != 0; v = (v << 1) | c as u32; c = out }
+                Shift::Rcr => { let out = v & 1 != 0; v = (v >> 1) | if c { top } else { 0 }; c = out }
+            }
+        }
+        let val = self.with_value(v);
+        let overflow = self.is_negative() != val.is_negative();
+        (val, FlagUpdate::new(c, overflow, val))
+    }
+
     pub fn mul(self, rhs: RegVal) -> Option<((RegVal, RegVal), FlagUpdate)> {
         match self {
             Self::Byte(b) => {
@@ -258,29 +352,29 @@ impl RegVal {
         }
     }
 
-    pub fn div(self, hi: RegVal, rhs: RegVal) -> Option<((RegVal, RegVal), FlagUpdate)> {
+    pub fn div(self, hi: RegVal, rhs: RegVal) -> Result<((RegVal, RegVal), FlagUpdate)> {
         match self {
             Self::Byte(v) => {
-                div!(u16, unwrap_u8, v, hi, rhs)
+                div!(u16, u8, unwrap_u8, v, hi, rhs)
             }
             Self::Word(v) => {
-                div!(u32, unwrap_u16, v, hi, rhs)
+                div!(u32, u16, unwrap_u16, v, hi, rhs)
             }
             Self::Dword(v) => {
-                div!(u64, unwrap_u32, v, hi, rhs)
+                div!(u64, u32, unwrap_u32, v, hi, rhs)
             }
         }
     }
-    pub fn idiv(self, hi: RegVal, rhs: RegVal) -> Option<((RegVal, RegVal), FlagUpdate)> {
+    pub fn idiv(self, hi: RegVal, rhs: RegVal) -> Result<((RegVal, RegVal), FlagUpdate)> {
         match self {
             Self::Byte(v) => {
-                div!(i16, unwrap_u8, v, hi, rhs)
+                div!(i16, i8, unwrap_u8, v, hi, rhs)
             }
             Self::Word(v) => {
-                div!(i32, unwrap_u16, v, hi, rhs)
+                div!(i32, i16, unwrap_u16, v, hi, rhs)
             }
             Self::Dword(v) => {
-                div!(i64, unwrap_u32, v, hi, rhs)
+                div!(i64, i32, unwrap_u32, v, hi, rhs)
             }
         }
     }
@@ -321,12 +415,71 @@ mod tests {
         let lhs = RegVal::Byte(20);
         let rhs = RegVal::Byte(-30i8 as u8);
         let res = RegVal::Byte(-10i8 as u8);
-        assert_eq!(lhs.add(rhs, false), Some((RegVal::Byte(-10i8 as u8), FlagUpdate::new(false, true, res))));
+        assert_eq!(lhs.add(rhs, false), Some((RegVal::Byte(-10i8 as u8), FlagUpdate::new(false, false, res))));
+
+        let lhs = RegVal::Byte(0x7f);
+        let rhs = RegVal::Byte(1);
+        let res = RegVal::Byte(0x80);
+        assert_eq!(lhs.add(rhs, false), Some((res, FlagUpdate::new(false, true, res))));
 
         let lhs = RegVal::Byte(30);
         let rhs = RegVal::Byte(20);
         let res = RegVal::Byte(10);
         assert_eq!(lhs.sub(rhs, true), Some((RegVal::Byte(10), FlagUpdate::new(true, false, res))));
+
+        let lhs = RegVal::Byte(20);
+        let rhs = RegVal::Byte(30);
+        let res = RegVal::Byte(-10i8 as u8);
+        assert_eq!(lhs.sub(rhs, true), Some((res, FlagUpdate::new(false, false, res))));
+
+        let lhs = RegVal::Byte(-0x80i8 as u8);
+        let rhs = RegVal::Byte(1);
+        let res = RegVal::Byte(0x7f);
+        assert_eq!(lhs.sub(rhs, true), Some((res, FlagUpdate::new(true, true, res))));
+    }
+
+    #[test]
+    fn logic() {
+        let lhs = RegVal::Byte(0b1100_1010);
+        let rhs = RegVal::Byte(0b1010_0110);
+        let res = RegVal::Byte(0b1000_0010);
+        assert_eq!(lhs.and(rhs), Some((res, FlagUpdate::new(false, false, res))));
+        let res = RegVal::Byte(0b1110_1110);
+        assert_eq!(lhs.or(rhs), Some((res, FlagUpdate::new(false, false, res))));
+        let res = RegVal::Byte(0b0110_1100);
+        assert_eq!(lhs.xor(rhs), Some((res, FlagUpdate::new(false, false, res))));
+        let res = RegVal::Byte(0b0011_0101);
+        assert_eq!(lhs.not(), (res, FlagUpdate::new(false, false, res)));
+
+        assert_eq!(RegVal::Word(0xff).xor(RegVal::Word(0xff)).map(|(_, f)| f.zero), Some(true));
+        assert_eq!(RegVal::Word(0xff).and(RegVal::Byte(0xff)), None);
+    }
+
+    #[test]
+    fn shift() {
+        let v = RegVal::Byte(0b1001_0110);
+        let res = RegVal::Byte(0b0101_1000);
+        assert_eq!(v.shift(Shift::Shl, 2, false), (res, FlagUpdate::new(false, true, res)));
+        let res = RegVal::Byte(0b0010_0101);
+        assert_eq!(v.shift(Shift::Shr, 2, false), (res, FlagUpdate::new(true, true, res)));
+        let res = RegVal::Byte(0b1110_0101);
+        assert_eq!(v.shift(Shift::Sar, 2, false), (res, FlagUpdate::new(true, false, res)));
+        let res = RegVal::Byte(0b0101_1010);
+        assert_eq!(v.shift(Shift::Rol, 2, false), (res, FlagUpdate::new(false, true, res)));
+        let res = RegVal::Byte(0b1010_0101);
+        assert_eq!(v.shift(Shift::Ror, 2, false), (res, FlagUpdate::new(true, false, res)));
+        let res = RegVal::Byte(0b0101_1011);
+        assert_eq!(v.shift(Shift::Rcl, 2, true), (res, FlagUpdate::new(false, true, res)));
+        let res = RegVal::Byte(0b0010_0101);
+        assert_eq!(v.shift(Shift::Rcr, 2, false), (res, FlagUpdate::new(true, true, res)));
+
+        // nine bit rotate through carry comes back round
+        assert_eq!(v.shift(Shift::Rcl, 9, true).0, v);
+        // shifting everything out
+        assert_eq!(RegVal::Word(0xffff).shift(Shift::Shr, 40, false).0, RegVal::Word(0));
+        assert_eq!(RegVal::Dword(0x8000_0000).shift(Shift::Sar, 40, false).0, RegVal::Dword(0xffff_ffff));
+        // nothing shifted keeps the carry
+        assert_eq!(v.shift(Shift::Shl, 0, true), (v, FlagUpdate::new(true, false, v)));
     }
 
     #[test]
@@ -347,7 +500,7 @@ mod tests {
         let lhs_hi = RegVal::Byte(0x12);
         let rhs = RegVal::Byte(0x56);
         let div_res = lhs_lo.div(lhs_hi, rhs);
-        let correct = Some((
+        let correct = Ok((
             (RegVal::Byte(0x36), RegVal::Byte(0x10)),
             FlagUpdate {
                 carry: false, overflow: false, zero: false, negative: false
@@ -360,18 +513,142 @@ mod tests {
         let lhs_hi = hi.into();
         let rhs = RegVal::Byte(0x56);
         let div_res = lhs_lo.idiv(lhs_hi, rhs);
-        let correct = Some((
+        let correct = Ok((
             (RegVal::Byte(0xca), RegVal::Byte(0xf0)),
             FlagUpdate {
                 carry: false, overflow: false, zero: false, negative: true
             }
         ));
-        assert_eq!(div_res, correct)
+        assert_eq!(div_res, correct);
+
+        let div_res = RegVal::Byte(100).idiv(RegVal::Byte(0), RegVal::Byte(-7i8 as u8));
+        assert_eq!(div_res.map(|(res, _)| res), Ok((RegVal::Byte(-14i8 as u8), RegVal::Byte(2))));
+    }
+
+    #[test]
+    fn div_exceptions() {
+        let zero = RegVal::Word(0);
+        assert_eq!(RegVal::Word(10).div(zero, zero), Err(Exception::DivideByZero));
+        assert_eq!(RegVal::Word(10).idiv(zero, zero), Err(Exception::DivideByZero));
+
+        // i8::MIN / -1
+        let min = RegVal::Byte(0x80);
+        let minus_one = RegVal::Byte(0xff);
+        assert_eq!(min.idiv(minus_one, minus_one), Err(Exception::DivisionOverflow));
+        // i16::MIN / -1 in the wide dividend doesn't panic either
+        assert_eq!(RegVal::Byte(0).idiv(min, minus_one), Err(Exception::DivisionOverflow));
+        // quotient too big for the low half
+        assert_eq!(RegVal::Byte(0).div(RegVal::Byte(1), RegVal::Byte(1)), Err(Exception::DivisionOverflow));
+
+        assert_eq!(RegVal::Byte(0).div(RegVal::Word(1), RegVal::Byte(1)), Err(Exception::InvalidOperation));
     }
 
     #[test]
     fn extend() {
         let v = RegVal::Word(-10i16 as u16);
         assert_eq!(v.sign_extend(RegSize::Dword), Ok(RegVal::Dword(-10i32 as u32)));
+        assert_eq!(RegVal::Byte(0xff).zero_extend(RegSize::Dword), Ok(RegVal::Dword(0xff)));
+        assert_eq!(RegVal::Word(0xffff).zero_extend(RegSize::Dword), Ok(RegVal::Dword(0xffff)));
+    }
+
+    fn random(rng: &mut Rng, bits: u32) -> RegVal {
+        let v = rng.value(bits);
+        match bits {
+            8 => RegVal::Byte(v as u8),
+            16 => RegVal::Word(v as u16),
+            _ => RegVal::Dword(v),
+        }
+    }
+    fn signed(v: RegVal) -> i64 {
+        let shift = 64 - v.bits();
+        ((v.to_u32() as i64) << shift) >> shift
+    }
+    fn fits_signed(v: i128, bits: u32) -> bool {
+        v >= -(1 << (bits - 1)) && v < 1 << (bits - 1)
+    }
+
+    /// checks the arithmetic against plain integer maths over lots of random and edge case values
+    #[test]
+    fn properties() {
+        let mut rng = Rng::new(0x7e57);
+        for _ in 0..20_000 {
+            let bits = rng.pick(&[8, 16, 32]);
+            let (a, b) = (random(&mut rng, bits), random(&mut rng, bits));
+            // wide enough for any product
+            let (ua, ub) = (a.to_u32() as i128, b.to_u32() as i128);
+            let (sa, sb) = (signed(a) as i128, signed(b) as i128);
+            let cin = rng.one_in(2);
+            let mask = (1i128 << bits) - 1;
+
+            let (sum, f) = a.add(b, cin).unwrap();
+            assert_eq!(sum.to_u32() as i128, (ua + ub + cin as i128) & mask);
+            assert_eq!(f.carry, ua + ub + cin as i128 > mask, "{:?} + {:?}", a, b);
+            assert_eq!(f.overflow, !fits_signed(sa + sb + cin as i128, bits), "{:?} + {:?}", a, b);
+
+            // the carry going into sub is the inverse of a borrow
+            let (diff, f) = a.sub(b, cin).unwrap();
+            let borrow = !cin as i128;
+            assert_eq!(diff.to_u32() as i128, (ua - ub - borrow) & mask);
+            assert_eq!(f.carry, ua - ub - borrow >= 0, "{:?} - {:?}", a, b);
+            assert_eq!(f.overflow, !fits_signed(sa - sb - borrow, bits), "{:?} - {:?}", a, b);
+            assert_eq!(f.zero, diff.is_zero());
+            assert_eq!(f.negative, diff.is_negative());
+
+            let ((lo, hi), f) = a.mul(b).unwrap();
+            let product = ua * ub;
+            assert_eq!((lo.to_u32() as i128, hi.to_u32() as i128), (product & mask, product >> bits));
+            assert_eq!(f.overflow, product > mask);
+            assert_eq!(f.zero, product == 0);
+
+            let ((lo, hi), f) = a.imul(b).unwrap();
+            let product = sa * sb;
+            assert_eq!((lo.to_u32() as i128, hi.to_u32() as i128), (product & mask, (product >> bits) & mask));
+            assert_eq!(f.overflow, !fits_signed(product, bits), "{:?} * {:?}", a, b);
+
+            match a.div(a.with_value(0), b) {
+                Ok(((q, r), _)) => assert_eq!((q.to_u32() as i128, r.to_u32() as i128), (ua / ub, ua % ub)),
+                Err(e) => assert_eq!((e, ub), (Exception::DivideByZero, 0)),
+            }
+            let hi = a.with_value(if a.is_negative() { u32::MAX } else { 0 });
+            match a.idiv(hi, b) {
+                Ok(((q, r), f)) => {
+                    assert_eq!((signed(q) as i128, signed(r) as i128), (sa / sb, sa % sb), "{:?} / {:?}", a, b);
+                    assert_eq!(f.negative, sa / sb < 0);
+                }
+                Err(Exception::DivideByZero) => assert_eq!(sb, 0),
+                Err(e) => assert_eq!((e, sa, sb), (Exception::DivisionOverflow, -(1 << (bits - 1)), -1)),
+            }
+
+            let size = |width| match width { 8 => RegSize::Byte, 16 => RegSize::Word, _ => RegSize::Dword };
+            for width in [8, 16, 32] {
+                match a.zero_extend(size(width)) {
+                    Ok(v) => assert_eq!((v.bits(), v.to_u32()), (width, a.to_u32())),
+                    Err(_) => assert!(width < bits),
+                }
+                match a.sign_extend(size(width)) {
+                    Ok(v) => assert_eq!((v.bits(), signed(v) as i128), (width, sa)),
+                    Err(_) => assert!(width < bits),
+                }
+            }
+
+            // rotating one way then back again changes nothing
+            let n = rng.below(70);
+            let (rotated, f) = a.shift(Shift::Rol, n, cin);
+            assert_eq!(rotated.shift(Shift::Ror, n, f.carry).0, a);
+            let (rotated, f) = a.shift(Shift::Rcl, n, cin);
+            assert_eq!(rotated.shift(Shift::Rcr, n, f.carry), (a, FlagUpdate::new(cin, f.overflow, a)));
+            let (shifted, f) = a.shift(Shift::Shl, n, cin);
+            assert_eq!(shifted.to_u32() as i128, (ua << n.min(bits)) & mask);
+            if n != 0 && n <= bits {
+                assert_eq!(f.carry, (ua >> (bits - n)) & 1 != 0);
+            }
+            assert_eq!(a.shift(Shift::Sar, n, cin).0.to_u32() as i128, (sa >> n.min(bits)) & mask);
+
+            // writing part of a register and reading the same part back gives the same value
+            let sel = rng.below(4) as u8;
+            let old = rng.next_u32();
+            let part = RegVal::from_u32(rng.next_u32(), sel);
+            assert_eq!(RegVal::from_u32(mix_u32(old, part.to_u32(), sel), sel), part);
+        }
     }
 }
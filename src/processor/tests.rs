@@ -1,4 +1,13 @@
+#![allow(clippy::field_reassign_with_default)]
+
 use super::*;
+use crate::memory::RustMemory;
+
+fn memory() -> MemoryMap {
+    let mut mem = MemoryMap::new();
+    mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+    mem
+}
 
 #[test]
 fn gpr_mov() {
@@ -27,3 +36,657 @@ fn ptr_mov() {
     assert!(mov_res.is_ok());
     assert_eq!(p.xbp, 0x0000_5678)
 }
+
+#[test]
+fn cmp_flags() {
+    let mut p = Processor::default();
+
+    p.xa = 20;
+    p.xb = 30;
+    let cmp_res = p.cmp(Operand::Register(GPRs::A as u8), Operand::Register(GPRs::B as u8));
+    assert!(cmp_res.is_ok());
+    assert_eq!(p.xa, 20); // cmp does not write back
+    assert_eq!(p.xflags & 0xf, NEGATIVE_MASK);
+
+    let cmp_res = p.cmp(Operand::Register(GPRs::A as u8), Operand::Const(20u16.into()));
+    assert!(cmp_res.is_ok());
+    assert_eq!(p.xflags & 0xf, CARRY_MASK | ZERO_MASK);
+
+    let cmp_res = p.cmp(Operand::Register(GPRs::AL as u8), Operand::Const(20u16.into()));
+    assert_eq!(cmp_res, Err(Exception::InvalidOperation));
+}
+
+#[test]
+fn conditional_jumps() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+    let target = [Operand::Const(0x1234u16.into())];
+
+    // signed: -5 < 3, unsigned: 0xfffb > 3
+    p.xa = -5i16 as u16 as u32;
+    p.xb = 3;
+    assert!(p.execute(0xc8, &[], &mut mem).is_ok());
+    for (instruction, taken) in [
+        (0x89, false), (0x8a, true), // jz, jnz
+        (0x8b, true), (0x8c, false), // jc, jnc
+        (0x99, true), (0x9a, false), // ja, jbe
+        (0x9b, true), (0x9c, false), // jl, jge
+        (0x9d, false), (0x9e, true), // jg, jle
+    ] {
+        p.xpc = 0;
+        assert!(p.execute(instruction, &target, &mut mem).is_ok());
+        assert_eq!(p.xpc == 0x1234, taken, "instruction {:#x}", instruction);
+    }
+
+    // signed overflow: -128 - 1
+    p.xa = 0x80;
+    p.xb = 1;
+    assert!(p.execute(0xc8, &[Operand::Register(GPRs::AL as u8), Operand::Register(GPRs::BL as u8)], &mut mem).is_ok());
+    for (instruction, taken) in [
+        (0x8d, false), (0x8e, true), // jn, jnn
+        (0x8f, true), (0x98, false), // jo, jno
+        (0x9b, true), (0x9d, false), // jl, jg
+    ] {
+        p.xpc = 0;
+        assert!(p.execute(instruction, &target, &mut mem).is_ok());
+        assert_eq!(p.xpc == 0x1234, taken, "instruction {:#x}", instruction);
+    }
+}
+
+#[test]
+fn alu_ops() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+
+    p.xa = 0x1234;
+    p.xb = 0x00ff;
+    assert!(p.execute(0xd0, &[], &mut mem).is_ok()); // and %a, %b
+    assert_eq!(p.xa, 0x0034);
+
+    assert!(p.execute(0xd3, &[Operand::Register(GPRs::BL as u8)], &mut mem).is_ok()); // not %bl
+    assert_eq!(p.xb, 0x0000);
+    assert_eq!(p.xflags & ZERO_MASK, ZERO_MASK);
+
+    p.xc = 4;
+    assert!(p.execute(0xd8, &[], &mut mem).is_ok()); // shl %a, %cl
+    assert_eq!(p.xa, 0x0340);
+
+    p.xa = 0xffff;
+    p.xb = 1;
+    assert!(p.execute(0xc4, &[], &mut mem).is_ok()); // add %b
+    assert_eq!(p.xa, 0);
+    assert_eq!(p.xflags & 0xf, CARRY_MASK | ZERO_MASK);
+    assert!(p.execute(0xc5, &[], &mut mem).is_ok()); // adc %b
+    assert_eq!(p.xa, 2);
+    assert!(p.execute(0xc6, &[Operand::Const(3u16.into())], &mut mem).is_ok()); // sub word 3
+    assert_eq!(p.xa, 0xffff);
+    assert_eq!(p.xflags & CARRY_MASK, 0);
+
+    assert_eq!(p.execute(0xd1, &[Operand::Const(1u16.into())], &mut mem), Err(Exception::InvalidOperation));
+}
+
+#[test]
+fn memory_ops() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+
+    p.xa = 0x1234;
+    assert!(p.execute(0xa0, &[Operand::Register(GPRs::A as u8), Operand::Const(0x100u16.into())], &mut mem).is_ok()); // st %a, word 0x100
+    assert_eq!(mem.read16(0x100), [0x34, 0x12]);
+    p.xb = 1;
+    assert!(p.execute(0x90, &[Operand::Register(GPRs::CL as u8), Operand::Const(0x100u16.into()), Operand::Register(GPRs::B as u8)], &mut mem).is_ok()); // ld %cl, word 0x100, %b
+    assert_eq!(p.xc, 0x12);
+
+    p.xsp = 0x200;
+    assert!(p.execute(0x84, &[Operand::Register(GPRs::XA as u8)], &mut mem).is_ok()); // push %xa
+    assert_eq!(p.xsp, 0x1fc);
+    assert!(p.execute(0x86, &[Operand::Register(GPRs::XD as u8)], &mut mem).is_ok()); // pop %xd
+    assert_eq!(p.xsp, 0x200);
+    assert_eq!(p.xd, 0x1234);
+}
+
+#[test]
+fn test_prefix() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+
+    p.xa = 5;
+    p.xb = 5;
+    assert!(p.execute(0xf0, &[], &mut mem).is_ok()); // test
+    assert!(p.is_testing());
+    assert!(p.execute(0xc6, &[], &mut mem).is_ok()); // sub %b
+    assert_eq!(p.xa, 5);
+    assert_eq!(p.xflags & ZERO_MASK, ZERO_MASK);
+    assert!(!p.is_testing());
+    assert!(p.execute(0xc6, &[], &mut mem).is_ok()); // sub %b
+    assert_eq!(p.xa, 0);
+
+    p.xsp = 0x200;
+    assert!(p.execute(0xf0, &[], &mut mem).is_ok());
+    assert!(p.execute(0x84, &[Operand::Register(GPRs::B as u8)], &mut mem).is_ok()); // push %b
+    assert_eq!(p.xsp, 0x200);
+    assert_eq!(mem.read16(0x200), [0, 0]);
+
+    assert!(p.execute(0xf0, &[], &mut mem).is_ok());
+    assert_eq!(p.execute(0x88, &[Operand::Const(0x1234u16.into())], &mut mem), Err(Exception::IllegalOperation)); // jmp
+    assert_eq!(p.xpc, 0);
+    assert!(!p.is_testing());
+}
+
+#[test]
+fn interrupted_test_prefix() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+    idt(&mut p, &mut mem, 16);
+    p.xsp = 0x400;
+    p.xpc = 0x100;
+    p.xflags = IRQ_MASK;
+    p.xa = 5;
+    let program = [
+        0xf0, // test
+        0xc4, 0x71, 0x01, 0x00, // add word 1
+        0x85, // nop
+    ];
+    for (i, b) in program.iter().enumerate() {
+        mem.write(*b, 0x100 + i as u32);
+    }
+    mem.write16([0xf3, 0x85], 0x2000 + Exception::Irq as u32); // iret
+    p.clock(&mut mem);
+    assert!(p.is_testing());
+
+    // an irq between the prefix and the add returns to an add that's still tested
+    p.signal(DevMsg::Irq);
+    p.clock(&mut mem);
+    assert!(!p.is_testing());
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x101);
+    assert!(p.is_testing());
+    p.clock(&mut mem);
+    assert_eq!((p.xpc, p.xa), (0x105, 5));
+    assert!(!p.is_testing());
+}
+
+/// an idt at 0x1000 where vector n jumps to 0x2000 + n in segment 0
+fn idt(p: &mut Processor, mem: &mut MemoryMap, entries: u32) {
+    p.xidtp = 0x1000;
+    p.xidtl = entries;
+    for i in 0..entries {
+        mem.write16((0x2000 + i as u16).to_le_bytes(), 0x1000 + i * 4);
+        mem.write16([0, 0], 0x1000 + i * 4 + 2);
+    }
+}
+
+#[test]
+fn software_interrupts() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+    idt(&mut p, &mut mem, 32);
+
+    p.xsp = 0x400;
+    p.xpc = 0x123;
+    p.xflags = 0x80 | CARRY_MASK; // user mode
+    assert!(p.execute(0xf2, &[Operand::Const(0x10u8.into())], &mut mem).is_ok()); // int byte 0x10
+    assert_eq!(p.xpc, 0x2010);
+    assert_eq!(p.privilege(), 0);
+    assert_eq!(p.xsp, 0x3fa);
+    assert_eq!(mem.read16(0x3fa), [0x23, 0x01]);
+
+    assert!(p.execute(0xf3, &[], &mut mem).is_ok()); // iret
+    assert_eq!(p.xpc, 0x123);
+    assert_eq!(p.xsp, 0x400);
+    assert_eq!(p.xflags, 0x80 | CARRY_MASK);
+
+    // user mode can't use reserved vectors or iret
+    assert_eq!(p.execute(0xf2, &[Operand::Const(0x03u8.into())], &mut mem), Err(Exception::IllegalOperation));
+    assert_eq!(p.execute(0xf3, &[], &mut mem), Err(Exception::IllegalOperation));
+    // vectors past the end of the idt double fault
+    assert_eq!(p.execute(0xf2, &[Operand::Const(0x20u8.into())], &mut mem), Err(Exception::DoubleFault));
+}
+
+#[test]
+fn exceptions() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+    idt(&mut p, &mut mem, 16);
+
+    p.xsp = 0x400;
+    p.xpc = 0x100;
+    p.xflags = 0x80;
+    mem.write(0x80, 0x100); // mov
+    mem.write(GPRs::A as u8, 0x101);
+    mem.write(Spec::FLAGS as u8, 0x102);
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x2000 + Exception::IllegalOperation as u32);
+    assert_eq!(mem.read16(0x3fa), [0x00, 0x01]); // return to the faulting instruction
+
+    // an empty idt double faults, then resets
+    p.xidtl = 0;
+    mem.write(0xff, 0x2001);
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0);
+    assert_eq!(p.xsp, 0);
+}
+
+#[test]
+fn division_exceptions() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+    idt(&mut p, &mut mem, 16);
+
+    p.xa = 100;
+    p.xb = 7;
+    assert!(p.execute(0xc2, &[], &mut mem).is_ok()); // div %a, %b
+    assert_eq!((p.xa, p.xb), (14, 2));
+
+    p.xsp = 0x400;
+    p.xpc = 0x100;
+    p.xb = 0;
+    mem.write(0xc2, 0x100); // div
+    mem.write(0x85, 0x101); // nop, ends the operand list
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x2000 + Exception::DivideByZero as u32);
+    assert_eq!(p.xa, 14);
+
+    p.xpc = 0x100;
+    p.xa = 0x8000;
+    p.xb = 0xffff;
+    mem.write(0xc3, 0x100); // idiv
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x2000 + Exception::DivisionOverflow as u32);
+}
+
+#[test]
+fn bus_faults() {
+    let mut p = Processor::default();
+    let mut mem = MemoryMap::new();
+    mem.add_device_at(Box::new(crate::memory::Rom::new(vec![0; 0x10], true)), 0x3000..0x3010);
+    mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+    idt(&mut p, &mut mem, 16);
+
+    p.xsp = 0x400;
+    p.xpc = 0x100;
+    p.xa = 0x1234;
+    for (i, b) in [0xa0, GPRs::A as u8, 0x71, 0x00, 0x30, 0x85].iter().enumerate() {
+        mem.write(*b, 0x100 + i as u32); // st %a, word 0x3000
+    }
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x2000 + Exception::BusFault as u32);
+    assert_eq!(mem.read16(0x3fa), [0x00, 0x01]);
+    assert_eq!(mem.read16(0x3000), [0, 0]);
+
+    // block instructions stop at the element that faulted
+    p.xdi = 0x2ffe;
+    p.xc = 4;
+    assert_eq!(p.execute(0xea, &[Operand::Const(0xaau8.into())], &mut mem), Err(Exception::BusFault)); // stos byte 0xaa
+    assert_eq!((p.xdi, p.xc), (0x3000, 2));
+    assert_eq!(mem.read16(0x2ffe), [0xaa, 0xaa]);
+}
+
+#[test]
+fn mode32() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+    mem.add_device_at(Box::new(RustMemory::new()), 0x1_0000..0x2_0000);
+
+    p.xflags = MODE32_MASK;
+    p.xpc = 0x1_0000;
+    p.co = 0x1234; // ignored in bc32 mode
+    p.do_ = 0x1234;
+    let program = [
+        0x80, 0x72, 0x78, 0x56, 0x34, 0x12, GPRs::XA as u8, // mov dword 0x12345678, %xa
+        0xa0, GPRs::XA as u8, 0x72, 0x00, 0x80, 0x01, 0x00, // st %xa, dword 0x18000
+        0x88, 0x72, 0x00, 0x00, 0x01, 0x00, // jmp dword 0x10000
+        0x85, // nop
+    ];
+    for (i, b) in program.iter().enumerate() {
+        mem.write(*b, 0x1_0000 + i as u32);
+    }
+    p.clock(&mut mem);
+    assert_eq!(p.xa, 0x1234_5678);
+    p.clock(&mut mem);
+    assert_eq!(mem.read16(0x1_8000), [0x78, 0x56]);
+    assert_eq!(mem.read16(0x1_8002), [0x34, 0x12]);
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x1_0000);
+
+    p.xsp = 0x1_0000;
+    assert!(p.execute(0x84, &[Operand::Register(GPRs::A as u8)], &mut mem).is_ok()); // push %a
+    assert_eq!(p.xsp, 0xfffe);
+    assert_eq!(mem.read16(0xfffe), [0x78, 0x56]);
+}
+
+#[test]
+fn mode32_switching() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+
+    // dword constants only exist in bc32 mode
+    mem.write(0x80, 0);
+    mem.write(0x72, 1);
+    mem.write(0x85, 6);
+    p.clock(&mut mem); // faults with no idt, and resets
+    assert_eq!(p.xpc, 0);
+    assert_eq!(p.xa, 0);
+
+    // switching modes is privileged
+    let flags = Operand::Register(Spec::FLAGS as u8);
+    assert!(p.execute(0x80, &[Operand::Const(MODE32_MASK.half_split().0.into()), flags], &mut mem).is_ok());
+    assert!(p.is_mode32());
+    p.xflags = 0x80;
+    let res = p.execute(0x80, &[Operand::Const(MODE32_MASK.half_split().0.into()), flags], &mut mem);
+    assert_eq!(res, Err(Exception::IllegalOperation));
+    assert!(!p.is_mode32());
+}
+
+#[test]
+fn halt_and_wait() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+    idt(&mut p, &mut mem, 16);
+
+    p.xsp = 0x400;
+    assert!(p.execute(0xf5, &[], &mut mem).is_ok()); // wfi
+    assert_eq!(p.state(), State::Waiting);
+    p.xpc = 0x100;
+    p.clock(&mut mem); // does nothing while waiting
+    assert_eq!(p.xpc, 0x100);
+    p.signal(DevMsg::Irq);
+    assert_eq!(p.state(), State::Running);
+    // but the irq waits for the irq flag
+    mem.write16([0x85, 0x85], 0x100); // nops
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x101);
+    p.xflags = IRQ_MASK;
+    p.clock(&mut mem); // delivers the irq instead of running an instruction
+    assert_eq!(p.xpc, 0x2000 + Exception::Irq as u32);
+    // which is off in the handler, until iret
+    assert_eq!(p.xflags, 0);
+    assert!(p.execute(0xf3, &[], &mut mem).is_ok());
+    assert_eq!((p.xpc, p.xflags), (0x101, IRQ_MASK));
+
+    assert!(p.execute(0xf4, &[], &mut mem).is_ok()); // hlt
+    assert_eq!(p.state(), State::Halted);
+    p.signal(DevMsg::Nmi);
+    assert_eq!(p.state(), State::Halted);
+
+    // an interrupt controller's vector beats a plain irq, but not an nmi
+    let mut p = Processor::default();
+    idt(&mut p, &mut mem, 32);
+    p.xsp = 0x400;
+    p.xflags = IRQ_MASK;
+    p.signal(DevMsg::Vector(0x12));
+    p.signal(DevMsg::Irq);
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x2012);
+    // nmis are taken with irqs off
+    p.signal(DevMsg::Vector(0x13));
+    p.signal(DevMsg::Nmi);
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x2000 + Exception::Nmi as u32);
+
+    let mut p = Processor::default();
+    p.xflags = 0x80;
+    assert_eq!(p.execute(0xf4, &[], &mut mem), Err(Exception::IllegalOperation));
+    assert_eq!(p.execute(0xf5, &[], &mut mem), Err(Exception::IllegalOperation));
+}
+
+/// raises an interrupt on its first clock
+struct Raise(Option<DevMsg>);
+impl crate::memory::Device for Raise {
+    fn write(&mut self, _val: u8, _offset: u32, _range: u32) {}
+    fn write16(&mut self, _val: [u8; 2], _offset: u32, _range: u32) {}
+    fn clock(&mut self, _cycles: u32) -> DevMsg {
+        self.0.take().unwrap_or(DevMsg::None)
+    }
+}
+
+#[test]
+fn controller_vectors() {
+    use crate::memory::{pic, Pic};
+    let mut p = Processor::default();
+    let mut mem = MemoryMap::new();
+    let pic = Pic::new();
+    let line = pic.connect(2, Box::new(Raise(Some(DevMsg::Irq))));
+    mem.add_device(line, vec![]);
+    mem.add_device(Box::new(Raise(Some(DevMsg::Nmi))), vec![]);
+    mem.add_device_at(Box::new(pic), 0xff00..0xff00 + pic::SIZE);
+    mem.add_device_at(Box::new(RustMemory::new()), 0..0x1_0000);
+    idt(&mut p, &mut mem, 32);
+    p.xsp = 0x400;
+    p.xflags = IRQ_MASK;
+
+    // the nmi raised in the same clock wins, and the line stays pending
+    p.signal(mem.clock(1));
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x2000 + Exception::Nmi as u32);
+    assert_eq!((mem.read(0xff00 + pic::PENDING), mem.read(0xff00 + pic::IN_SERVICE)), (0x04, 0));
+
+    // so it's raised again once the nmi handler lets irqs in, and only goes into service once it's taken
+    p.xflags = IRQ_MASK;
+    p.signal(mem.clock(1));
+    assert_eq!(mem.read(0xff00 + pic::IN_SERVICE), 0);
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x2012);
+    assert_eq!((mem.read(0xff00 + pic::PENDING), mem.read(0xff00 + pic::IN_SERVICE)), (0, 0x04));
+    assert!(matches!(mem.clock(1), DevMsg::None));
+}
+
+#[test]
+fn block_ops() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+
+    p.xflags = DSEG_MASK;
+    p.do_ = 0x10;
+    p.eo = 0x20;
+    for i in 0..8 {
+        mem.write(i as u8 + 1, 0x1000 + i);
+    }
+
+    // movs %a, copying 3 words forwards from do:0 to eo:0
+    p.xc = 3;
+    assert!(p.execute(0xe8, &[Operand::Register(GPRs::A as u8)], &mut mem).is_ok());
+    assert_eq!((p.xsi, p.xdi, p.xc), (6, 6, 0));
+    assert_eq!([mem.read16(0x2000), mem.read16(0x2004)], [[1, 2], [5, 6]]);
+    assert_eq!(mem.read(0x2006), 0);
+
+    // stos byte 0xaa, filling backwards from eo:5
+    p.xdi = 5;
+    p.xc = 2;
+    p.xsi = 0x1234;
+    assert!(p.execute(0xeb, &[Operand::Const(0xaau8.into())], &mut mem).is_ok());
+    assert_eq!((p.xsi, p.xdi, p.xc), (0x1234, 3, 0));
+    assert_eq!([mem.read(0x2003), mem.read(0x2004), mem.read(0x2005)], [4, 0xaa, 0xaa]);
+
+    // cmps, stopping at the first difference
+    p.xsi = 0;
+    p.xdi = 0;
+    p.xc = 8;
+    assert!(p.execute(0xec, &[], &mut mem).is_ok());
+    assert_eq!((p.xsi, p.xdi, p.xc), (5, 5, 3));
+    assert_eq!(p.xflags & ZERO_MASK, 0);
+
+    // scas, stopping at the first match
+    p.xdi = 0;
+    p.xc = 8;
+    p.xa = 0xaa;
+    assert!(p.execute(0xee, &[], &mut mem).is_ok());
+    assert_eq!((p.xdi, p.xc), (5, 3));
+    assert_eq!(p.xflags & ZERO_MASK, ZERO_MASK);
+
+    // nothing happens when c is zero
+    p.xflags = DSEG_MASK;
+    p.xc = 0;
+    assert!(p.execute(0xe8, &[], &mut mem).is_ok());
+    assert_eq!((p.xsi, p.xdi, p.xflags), (5, 5, DSEG_MASK));
+
+    // pointers wrap at 16 bits outside of bc32 mode
+    p.xdi = 0xffff;
+    p.xc = 2;
+    assert!(p.execute(0xea, &[], &mut mem).is_ok());
+    assert_eq!(p.xdi, 1);
+}
+
+#[test]
+fn long_blocks() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+    idt(&mut p, &mut mem, 16);
+    p.xsp = 0x400;
+    p.xpc = 0x100;
+    mem.write16([0xea, 0x85], 0x100); // stos, then a nop
+    p.xa = 0x11;
+    p.xdi = 0x800;
+    p.xc = 40;
+    p.xflags = IRQ_MASK;
+
+    // it runs in chunks, staying on the instruction until c runs out
+    p.clock(&mut mem);
+    assert_eq!((p.xpc, p.xc, p.xdi), (0x100, 40 - BLOCK_CHUNK, 0x800 + BLOCK_CHUNK));
+    // so an interrupt is taken part way through, and returns to the instruction
+    p.signal(DevMsg::Irq);
+    p.clock(&mut mem);
+    assert_eq!(p.xpc, 0x2000 + Exception::Irq as u32);
+    assert_eq!(mem.read16(0x3fa), [0x00, 0x01]);
+    p.xpc = 0x100;
+    p.clock(&mut mem);
+    p.clock(&mut mem);
+    assert_eq!((p.xpc, p.xc, p.xdi), (0x101, 0, 0x800 + 40));
+    assert_eq!((mem.read(0x800 + 39), mem.read(0x800 + 40)), (0x11, 0));
+
+    // under test it runs one chunk without changing anything, and moves on
+    p.xpc = 0x100;
+    p.xc = 40;
+    p.xflags = TEST_MASK;
+    p.clock(&mut mem);
+    assert_eq!((p.xpc, p.xc), (0x101, 40));
+}
+
+#[test]
+fn cycles() {
+    let mut p = Processor::default();
+    let mut mem = memory();
+    idt(&mut p, &mut mem, 16);
+
+    let program = [
+        0x80, 0x71, 0x34, 0x12, GPRs::A as u8, // mov word 0x1234, %a
+        0xa0, GPRs::A as u8, 0x71, 0x01, 0x01, // st %a, word 0x101
+        0xc3, // idiv
+        0x85, // nop
+    ];
+    for (i, b) in program.iter().enumerate() {
+        mem.write(*b, 0x100 + i as u32);
+    }
+    p.xpc = 0x100;
+    p.xsp = 0x400;
+    // 5 instruction bytes, 1 peek at the next instruction, 1 to execute
+    assert_eq!(p.clock(&mut mem), 7);
+    // 5 instruction bytes, 1 peek, 2 writes for the unaligned word, 1 to execute
+    assert_eq!(p.clock(&mut mem), 9);
+    // 1 instruction byte, 1 peek, 12 to divide, 4 to enter the divide by zero handler,
+    // 3 pushes and 2 reads of the idt entry
+    assert_eq!(p.clock(&mut mem), 23);
+}
+
+#[test]
+fn decode_cache() {
+    let program = [
+        0x80, 0x71, 0x11, 0x11, GPRs::A as u8, // mov word 0x1111, %a
+        0xa0, GPRs::B as u8, 0x71, 0x02, 0x01, // st %b, word 0x102
+        0x88, 0x71, 0x00, 0x01, // jmp word 0x100
+        0x85, // nop
+    ];
+    let mut cycles = Vec::new();
+    for cached in [true, false] {
+        let mut p = Processor::default();
+        let mut mem = memory();
+        p.set_decode_cache(cached);
+        for (i, b) in program.iter().enumerate() {
+            mem.write(*b, 0x100 + i as u32);
+        }
+        p.xpc = 0x100;
+        p.xb = 0x2222;
+
+        let mut run = Vec::new();
+        run.push(p.clock(&mut mem));
+        assert_eq!(p.xa, 0x1111);
+        run.push(p.clock(&mut mem)); // overwrites the constant in the mov
+        run.push(p.clock(&mut mem));
+        run.push(p.clock(&mut mem));
+        assert_eq!(p.xa, 0x2222);
+        run.push(p.clock(&mut mem));
+        run.push(p.clock(&mut mem));
+        run.push(p.clock(&mut mem));
+        assert_eq!(p.xpc, 0x105);
+        cycles.push(run);
+    }
+    // the cache doesn't change timing
+    assert_eq!(cycles[0], cycles[1]);
+}
+
+/// runs a program at 0x100 until it halts, returning its registers, the low 4k of memory and the cycles it took
+fn run_to_halt(program: &[u8], translated: bool) -> (Vec<u32>, Vec<u8>, u64) {
+    let mut p = Processor::default();
+    let mut mem = memory();
+    idt(&mut p, &mut mem, 16);
+    // divide by zero handler: mov word 1, %d; iret; nop
+    for (i, b) in [0x80, 0x71, 0x01, 0x00, GPRs::D as u8, 0xf3, 0x85].iter().enumerate() {
+        mem.write(*b, 0x2002 + i as u32);
+    }
+    for (i, b) in program.iter().enumerate() {
+        mem.write(*b, 0x100 + i as u32);
+    }
+    p.set_translation(translated);
+    p.xsp = 0x400;
+    p.xpc = 0x100;
+
+    let mut cycles = 0;
+    while p.state() == State::Running {
+        cycles += p.clock(&mut mem) as u64;
+    }
+    let registers = vec![
+        p.xa, p.xb, p.xc, p.xd, p.xsp, p.xbp, p.xsi, p.xdi, p.xrp, p.ro as u32,
+        p.co as u32, p.do_ as u32, p.eo as u32, p.so as u32, p.xidtp, p.xidtl, p.xpc, p.xflags,
+    ];
+    let memory = (0..0x1000).map(|a| mem.read(a)).collect();
+    (registers, memory, cycles)
+}
+
+#[test]
+fn translation() {
+    let arithmetic = [
+        0x80, 0x71, 0xc8, 0x00, GPRs::C as u8, // mov word 200, %c
+        0x80, 0x71, 0x00, 0x00, GPRs::D as u8, // mov word 0, %d
+        0xc4, GPRs::C as u8, // 0x10a: add %c
+        0xf0, 0xc6, 0x71, 0x00, 0x01, // test sub word 0x100
+        0xdd, GPRs::B as u8, 0x70, 0x03, // ror %b, byte 3
+        0xd2, GPRs::B as u8, GPRs::A as u8, // xor %b, %a
+        0xc2, GPRs::A as u8, GPRs::D as u8, // div %a, %d, which faults until the handler sets %d
+        0xa0, GPRs::B as u8, 0x71, 0x00, 0x08, // st %b, word 0x800
+        0x85, GPRs::A as u8, GPRs::C as u8, // swr %a, %c
+        0xc6, 0x71, 0x01, 0x00, // sub word 1
+        0x85, GPRs::A as u8, GPRs::C as u8, // swr %a, %c
+        0x8a, 0x71, 0x0a, 0x01, // jnz word 0x10a
+        0xf4, // hlt
+    ];
+    let self_modifying = [
+        0x80, 0x71, 0x40, 0x00, GPRs::C as u8, // mov word 64, %c
+        0x80, 0x71, 0x00, 0x00, GPRs::A as u8, // mov word 0, %a
+        0xc4, 0x71, 0x01, 0x00, // 0x10a: add word 1
+        0x85, GPRs::A as u8, GPRs::C as u8, // swr %a, %c
+        0xc6, 0x71, 0x01, 0x00, // sub word 1
+        0x85, GPRs::A as u8, GPRs::C as u8, // swr %a, %c
+        0x89, 0x71, 0x32, 0x01, // jz word 0x132
+        0xc8, GPRs::C as u8, 0x71, 0x20, 0x00, // cmp %c, word 32
+        0x8a, 0x71, 0x0a, 0x01, // jnz word 0x10a
+        0x80, 0x70, 0x05, GPRs::BL as u8, // mov byte 5, %bl
+        0xa0, GPRs::BL as u8, 0x71, 0x0c, 0x01, // st %bl, word 0x10c, the constant in the add
+        0x88, 0x71, 0x0a, 0x01, // jmp word 0x10a
+        0xf4, // 0x132: hlt
+    ];
+    for program in [&arithmetic[..], &self_modifying[..]] {
+        assert_eq!(run_to_halt(program, true), run_to_halt(program, false));
+    }
+    // the add picks up its new constant, even though it had been translated with the old one
+    assert_eq!(run_to_halt(&self_modifying, true).0[0], 32 + 32 * 5);
+}
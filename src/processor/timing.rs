@@ -0,0 +1,15 @@
+/// cycles taken to enter an interrupt handler, on top of the stack pushes
+pub const INTERRUPT_CYCLES: u32 = 4;
+/// the most elements a block instruction moves each time it runs, which bounds how long interrupts wait for it
+pub const BLOCK_CHUNK: u32 = 16;
+
+/// cycles an instruction takes to execute, on top of its bus accesses
+pub fn execution_cycles(instruction: u8) -> u32 {
+    match instruction {
+        0xc0 | 0xc1 => 4, // mul, imul
+        0xc2 | 0xc3 => 12, // div, idiv
+        0xd8..=0xdf => 2, // shifts and rotates
+        0xf2 => 1 + INTERRUPT_CYCLES, // int
+        _ => 1
+    }
+}
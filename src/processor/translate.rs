@@ -0,0 +1,150 @@
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+use crate::memory::{MemoryMap, PAGE_BITS};
+use super::*;
+
+/// how many times the interpreter has to run an instruction before a block is translated from it
+const HOT_THRESHOLD: u32 = 16;
+const MAX_BLOCK_LEN: usize = 32;
+/// number of blocks and heat counters kept. must be a power of 2
+const SLOTS: usize = 4096;
+
+type Op = Box<dyn Fn(&mut Processor, &mut MemoryMap) -> Result<()>>;
+
+/// one instruction of a block, with its operands already bound
+pub struct Step {
+    pub instruction: u8,
+    /// bytes pc moves past before running it
+    pub len: u32,
+    /// fetch and execution cycles, not counting the bus accesses it makes while running
+    pub cycles: u32,
+    pub op: Op,
+}
+
+/// a straight run of translated instructions, ending at the first jump or untranslatable instruction
+pub struct Block {
+    pub steps: Vec<Step>,
+    pages: RangeInclusive<u32>,
+}
+
+/// translates hot code into blocks of threaded code, built from what the decode cache already holds.
+/// anything touching memory, system registers or control flow beyond plain jumps is left to the interpreter,
+/// so devices still see every bus access between instructions as they would without translation
+pub struct Translator {
+    enabled: bool,
+    /// direct mapped counts of how often the interpreter has run each flat pc
+    heat: Vec<(u32, bool, u32)>,
+    blocks: Vec<Option<(u32, bool, Rc<Block>)>>,
+}
+impl Default for Translator {
+    fn default() -> Translator {
+        Translator {
+            enabled: false,
+            heat: vec![(0, false, 0); SLOTS],
+            blocks: vec![None; SLOTS],
+        }
+    }
+}
+impl Translator {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.heat.fill((0, false, 0));
+        self.blocks.fill(None);
+    }
+    pub fn get(&self, flat_pc: u32, mode32: bool) -> Option<Rc<Block>> {
+        match &self.blocks[flat_pc as usize & (SLOTS - 1)] {
+            Some((pc, m, b)) if *pc == flat_pc && *m == mode32 => Some(b.clone()),
+            _ => None
+        }
+    }
+    /// notes that the interpreter ran the instruction at flat_pc, translating a block from it once it gets hot
+    pub fn visit(&mut self, flat_pc: u32, mode32: bool, cache: &DecodeCache) {
+        if !self.enabled {
+            return
+        }
+        let slot = flat_pc as usize & (SLOTS - 1);
+        let heat = &mut self.heat[slot];
+        if heat.0 != flat_pc || heat.1 != mode32 {
+            *heat = (flat_pc, mode32, 0);
+        }
+        heat.2 += 1;
+        if heat.2 >= HOT_THRESHOLD {
+            heat.2 = 0;
+            if let Some(block) = translate(flat_pc, mode32, cache) {
+                self.blocks[slot] = Some((flat_pc, mode32, Rc::new(block)));
+            }
+        }
+    }
+    /// drops every block translated from a page
+    pub fn invalidate(&mut self, page: u32) {
+        for b in self.blocks.iter_mut() {
+            if matches!(b, Some((_, _, block)) if block.pages.contains(&page)) {
+                *b = None
+            }
+        }
+    }
+}
+
+fn translate(flat_pc: u32, mode32: bool, cache: &DecodeCache) -> Option<Block> {
+    let mut steps = Vec::new();
+    let mut pc = flat_pc;
+    while steps.len() < MAX_BLOCK_LEN {
+        let decoded = match cache.get(pc, mode32) {
+            Some(d) if translatable(&d) => d,
+            _ => break
+        };
+        steps.push(compile(&decoded));
+        pc = pc.wrapping_add(decoded.len);
+        if is_jump(decoded.instruction) {
+            break
+        }
+    }
+    if steps.is_empty() {
+        return None
+    }
+    // the byte after the last instruction was read too, to end its operands
+    Some(Block { steps, pages: (flat_pc >> PAGE_BITS)..=(pc >> PAGE_BITS) })
+}
+
+fn translatable(decoded: &Decoded) -> bool {
+    let system = decoded.operands.iter().any(|o| matches!(o, Operand::Register(0x20..0x30)));
+    !system && matches!(decoded.instruction,
+        0x80 | 0x81 | 0x85 | // mov, swr
+        0x88..=0x8f | 0x98..=0x9e | // jumps
+        0xc0..=0xc8 | 0xd0..=0xd3 | 0xd8..=0xda | 0xdc..=0xdf | // arithmetic
+        0xf0 // test
+    )
+}
+
+/// binds an instruction's operands into a closure, calling straight into the instruction's handler where it has one
+fn compile(decoded: &Decoded) -> Step {
+    let instruction = decoded.instruction;
+    let operands = decoded.operands.clone();
+    let op: Op = match instruction {
+        0x80 | 0x81 => {
+            let src = operand_or(&operands, 0, GPRs::A);
+            let dest = operand_or(&operands, 1, GPRs::A);
+            Box::new(move |p, _| p.mov(src, dest, instruction & 1 != 0))
+        }
+        0x88..=0x8f | 0x98..=0x9e => {
+            let addr = operand_or(&operands, 0, GPRs::A);
+            Box::new(move |p, _| p.jump_if(instruction, addr))
+        }
+        0xc4..=0xc7 => {
+            let rhs = operand_or(&operands, 0, GPRs::B);
+            Box::new(move |p, _| p.add_sub(instruction, rhs))
+        }
+        0xc8 => {
+            let lhs = operand_or(&operands, 0, GPRs::A);
+            let rhs = operand_or(&operands, 1, GPRs::B);
+            Box::new(move |p, _| p.cmp(lhs, rhs))
+        }
+        _ => Box::new(move |p, mem| p.dispatch(instruction, &operands, mem))
+    };
+    Step {
+        instruction,
+        len: decoded.len,
+        cycles: decoded.fetch_cycles + execution_cycles(instruction),
+        op,
+    }
+}
@@ -45,6 +45,7 @@ num_merge!(u8, i16);
 num_merge!(u16, i32);
 num_merge!(u32, i64);
 
+#[allow(dead_code)]
 pub trait MergeUp<T> {
     fn merge_up(lo: Self, hi: Self) -> T;
 }
@@ -103,12 +104,12 @@ pub fn mix_u32(old_val: u32, in_val: u32, sel: u8) -> u32 {
     }
 }
 
-pub trait SignedWideMul<T> {
+pub trait WideMul<T> {
     fn widening_mul(self, rhs: Self) -> (T, T);
 }
 macro_rules! wide_mul {
     ($from:ty, $signed_wide:ty, $out:ty) => {
-        impl SignedWideMul<$out> for $from {
+        impl WideMul<$out> for $from {
             fn widening_mul(self, rhs: Self) -> ($out, $out) {
                 let lhs = self as $signed_wide;
                 let rhs = rhs as $signed_wide;
@@ -117,6 +118,9 @@ macro_rules! wide_mul {
         }
     };
 }
+wide_mul!(u8, u16, u8);
+wide_mul!(u16, u32, u16);
+wide_mul!(u32, u64, u32);
 wide_mul!(i8, i16, u8);
 wide_mul!(i16, i32, u16);
 wide_mul!(i32, i64, u32);
@@ -137,6 +141,129 @@ top_bit!(u8, 0x80);
 top_bit!(u16, 0x80_00);
 top_bit!(u32, 0x8000_0000);
 
+/// reads little endian values from a byte slice, failing with err once it runs out
+pub struct ByteReader<'a, E> {
+    data: &'a [u8],
+    pos: usize,
+    err: E,
+}
+impl<'a, E: Clone> ByteReader<'a, E> {
+    pub fn new(data: &'a [u8], pos: usize, err: E) -> Self {
+        ByteReader { data, pos, err }
+    }
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], E> {
+        let b = self.data.get(self.pos..self.pos.saturating_add(len)).ok_or(self.err.clone())?;
+        self.pos += len;
+        Ok(b)
+    }
+    pub fn u8(&mut self) -> Result<u8, E> {
+        Ok(self.bytes(1)?[0])
+    }
+    pub fn u16(&mut self) -> Result<u16, E> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+    pub fn u32(&mut self) -> Result<u32, E> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+    /// a string prefixed with its length in a byte
+    pub fn name(&mut self) -> Result<Option<&'a str>, E> {
+        let len = self.u8()? as usize;
+        Ok(std::str::from_utf8(self.bytes(len)?).ok())
+    }
+}
+
+/// an address in decimal or 0x hex
+pub fn parse_addr(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// cuts off a ; comment, unless it's inside quotes
+pub fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (';', None) => return &line[..i],
+            _ => ()
+        }
+    }
+    line
+}
+
+/// the bytes of a quoted string, with \n, \t, \0, \\ and quote escapes
+pub fn string(s: &str) -> Result<Vec<u8>, String> {
+    let quote = s.chars().next().filter(|c| *c == '"' || *c == '\'');
+    let inner = match quote {
+        Some(q) if s.len() >= 2 && s.ends_with(q) => &s[1..s.len() - 1],
+        _ => return Err(format!("bad string {}", s)),
+    };
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' {
+            match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some(c @ ('\\' | '"' | '\'')) => c,
+                _ => return Err(format!("bad escape in {}", s)),
+            }
+        }
+        else { c };
+        let mut buf = [0; 4];
+        bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+    }
+    Ok(bytes)
+}
+
+/// a small xorshift generator, for tests that want lots of varied input
+#[cfg(test)]
+pub struct Rng(u64);
+#[cfg(test)]
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // xorshift gets stuck at 0
+        Rng(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+    pub fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+    /// a number in 0..n
+    pub fn below(&mut self, n: u32) -> u32 {
+        (self.next_u64() % n as u64) as u32
+    }
+    /// true one time in n
+    pub fn one_in(&mut self, n: u32) -> bool {
+        self.below(n) == 0
+    }
+    pub fn pick<T: Copy>(&mut self, items: &[T]) -> T {
+        items[self.below(items.len() as u32) as usize]
+    }
+    /// a value of some number of bits, often one of the edge cases arithmetic goes wrong on
+    pub fn value(&mut self, bits: u32) -> u32 {
+        let mask = if bits == 32 { u32::MAX } else { (1 << bits) - 1 };
+        let top = 1 << (bits - 1);
+        let v = match self.below(4) {
+            0 => self.pick(&[0, 1, 2, mask, mask - 1, top, top - 1, top + 1]),
+            1 => self.below(8),
+            _ => self.next_u32(),
+        };
+        v & mask
+    }
+}
+
 #[test]
 fn num_splits() {
     assert_eq!(0x12_34u16.half_split(), (0x34, 0x12));